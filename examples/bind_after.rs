@@ -11,7 +11,7 @@ async fn main() -> Result<()> {
     // Create necessary directories
     let mount_point = Path::new("/tmp/mnt/ninep");
 
-    common::setup_directories(&mount_point)?;
+    common::setup_directories(mount_point)?;
 
     // Create NineP filesystem with /tmp/target as root
     let hello_fs = NineP::new(PathBuf::from("/tmp/target"))?;
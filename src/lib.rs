@@ -44,6 +44,7 @@
 
 pub mod modules;
 
+pub use modules::error::{Error, Result};
 pub use modules::mount::FilesystemManager;
 pub use modules::proto::NineP;
 
@@ -1,12 +1,15 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use froggr::modules::namespace::BindMode;
+use froggr::modules::auth::{AuthScheme, HmacSha256, P9any, DEFAULT_AUTH_DOMAIN};
+use froggr::modules::namespace::{BindMode, SymlinkPolicy};
+use froggr::modules::proto::Export;
+use froggr::modules::server::Server;
 use froggr::modules::session::SessionManager;
 use log::{debug, error, info};
 use std::path::PathBuf;
-use froggr::session::Session;
+use froggr::{FilesystemManager, NineP};
 use std::path::Path;
-use env_logger;
+use std::sync::Arc;
 use nix::unistd::mkfifo;
 use nix::sys::stat::Mode;
 
@@ -37,6 +40,9 @@ enum Commands {
         /// Create new binding
         #[arg(short = 'c', long = "create", group = "bind_mode")]
         create: bool,
+        /// Treatment of symlinks leading outside the source: follow, keep or refuse
+        #[arg(short = 's', long = "symlinks", default_value = "keep")]
+        symlinks: SymlinkPolicy,
         /// Source directory path
         source: PathBuf,
         /// Target directory path
@@ -52,6 +58,23 @@ enum Commands {
         #[arg(default_value = "localhost")]
         node_id: String,
     },
+    /// Serve a directory over the 9P protocol
+    Serve {
+        /// Address to listen on
+        #[arg(short = 'l', long = "listen", default_value = "127.0.0.1:564")]
+        listen: String,
+        /// Largest message size offered to clients, in bytes
+        #[arg(short = 'm', long = "msize", default_value_t = froggr::modules::constants::MAX_MSIZE)]
+        msize: u32,
+        /// File of user secrets; clients must authenticate when given
+        #[arg(short = 'k', long = "auth-keys")]
+        auth_keys: Option<PathBuf>,
+        /// Additional tree clients can attach to, as NAME=PATH[:ro][:users=USER,...]
+        #[arg(short = 'e', long = "export")]
+        exports: Vec<String>,
+        /// Root directory to export
+        root: PathBuf,
+    },
     /// Manage filesystem sessions
     Session {
         /// List all active sessions
@@ -90,7 +113,7 @@ async fn main() -> Result<()> {
     let session_manager = SessionManager::new()?;
 
     match &cli.command {
-        Commands::Bind { before, after, replace, create, source, target } => {
+        Commands::Bind { before, after, replace, create, symlinks, source, target } => {
             info!("Starting bind operation in process {}", std::process::id());
             let mode = match (before, after, replace, create) {
                 (_, _, true, _) => BindMode::Replace,
@@ -105,7 +128,13 @@ async fn main() -> Result<()> {
 
             if let Some(session) = session_manager.get_session(&session_id)? {
                 info!("Found session with PID {}", session.pid);
-                session_manager.send_bind_command(&session_id, source.clone(), target.clone(), mode)?;
+                session_manager.send_bind_command(
+                    &session_id,
+                    source.clone(),
+                    target.clone(),
+                    mode,
+                    symlinks.clone(),
+                )?;
                 info!("Sent bind command to session");
             } else {
                 error!("No session found for bind operation");
@@ -140,6 +169,32 @@ async fn main() -> Result<()> {
             std::thread::sleep(std::time::Duration::from_secs(1));
             info!("Mount operation completed");
         }
+        Commands::Serve {
+            listen,
+            msize,
+            auth_keys,
+            exports,
+            root,
+        } => {
+            info!("Starting 9P server for {} on {}", root.display(), listen);
+            let mut fs = NineP::new(root.clone())?;
+            fs.set_max_msize(*msize)?;
+            if let Some(path) = auth_keys {
+                let hmac: Arc<dyn AuthScheme> = Arc::new(HmacSha256::from_file(path)?);
+                fs.set_auth(Arc::new(P9any::new(DEFAULT_AUTH_DOMAIN, vec![hmac])));
+                info!("Authenticating clients with {}", path.display());
+            }
+            for spec in exports {
+                let (aname, export) = Export::parse(spec)?;
+                info!("Exporting {} as tree '{}'", export.path.display(), aname);
+                fs.export(&aname, export);
+            }
+            let fs_manager = FilesystemManager::new(fs);
+            fs_manager.bind(root, root, BindMode::Replace)?;
+
+            println!("Serving {} on {}", root.display(), listen);
+            Server::new(fs_manager.fs.clone()).serve_tcp(listen).await?;
+        }
         Commands::Session { list, kill, purge, session_id } => {
             if *list {
                 let sessions = session_manager.list_sessions()?;
@@ -166,6 +221,10 @@ async fn main() -> Result<()> {
                     println!("ID: {}", session.id);
                     println!("PID: {}", session.pid);
                     println!("Root: {}", session.root.display());
+                    let socket_path = session_manager.socket_path(&session.id);
+                    if socket_path.exists() {
+                        println!("9P socket: {}", socket_path.display());
+                    }
                     println!("\nMounts:");
                     for (source, target) in &session.mounts {
                         println!("  {} -> {}", source.display(), target.display());
@@ -179,7 +238,7 @@ async fn main() -> Result<()> {
                 }
             }
         }
-        Commands::InternalSession { session_id, root } => {
+        Commands::InternalSession { session_id, root: _ } => {
             info!("Starting session process for ID: {}", session_id);
             let pipe_path = format!("/tmp/froggr/sessions/{}.pipe", session_id);
             let pipe_path = Path::new(&pipe_path);
@@ -0,0 +1,439 @@
+//! Authentication of 9P clients.
+//!
+//! A client that wants to attach to a server with authentication enabled
+//! first sends a Tauth, which opens an auth fid (afid). It then runs the
+//! conversation of an [`AuthScheme`] by reading from and writing to the afid,
+//! and finally names the afid in its Tattach. The attach only succeeds if the
+//! conversation proved the client to be the user it attaches as.
+//!
+//! Two schemes are provided:
+//!
+//! - [`HmacSha256`]: the server sends a random challenge and the client
+//!   answers with an HMAC-SHA256 of the challenge and its user name, keyed
+//!   with a secret shared between the user and the server.
+//! - [`P9any`]: a negotiation in the style of Plan 9's p9any, in which the
+//!   server offers a list of schemes and the client picks the one to run.
+
+use super::error::{Error, Result};
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Authentication domain offered by `frg serve` in the p9any negotiation.
+pub const DEFAULT_AUTH_DOMAIN: &str = "froggr";
+
+/// Size of the random challenge sent by [`HmacSha256`], in bytes.
+const CHALLENGE_SIZE: usize = 32;
+
+/// An authentication protocol that clients run over an auth fid.
+pub trait AuthScheme: Debug + Send + Sync {
+    /// Returns the name of the protocol, as used in the p9any negotiation.
+    fn name(&self) -> &str;
+
+    /// Starts a conversation with a client claiming to be `uname`.
+    ///
+    /// # Arguments
+    /// * `uname` - The user name sent in the Tauth.
+    ///
+    /// # Returns
+    /// The server side of the conversation.
+    fn start(&self, uname: &str) -> Box<dyn Conversation>;
+}
+
+/// The server side of one run of an authentication protocol.
+pub trait Conversation: Debug + Send {
+    /// Returns the next bytes the server sends to the client.
+    ///
+    /// # Arguments
+    /// * `count` - The maximum number of bytes to return.
+    ///
+    /// # Returns
+    /// Up to `count` bytes, or nothing if the server is waiting for the client.
+    fn read(&mut self, count: u32) -> Result<Vec<u8>>;
+
+    /// Consumes bytes sent by the client.
+    ///
+    /// # Arguments
+    /// * `data` - The bytes written to the auth fid.
+    ///
+    /// # Returns
+    /// The number of bytes consumed, or an error if the client failed to
+    /// authenticate.
+    fn write(&mut self, data: &[u8]) -> Result<u32>;
+
+    /// Returns whether the client has proven to be the user it claimed.
+    fn authenticated(&self) -> bool;
+}
+
+/// State of an auth fid opened by Tauth.
+#[derive(Debug)]
+pub struct AuthFid {
+    /// User name sent in the Tauth
+    pub uname: String,
+    /// Tree the client wants to attach to
+    pub aname: String,
+    /// Server side of the authentication conversation
+    pub conversation: Box<dyn Conversation>,
+}
+
+/// Challenge-response authentication with secrets shared per user.
+///
+/// The client reads a challenge, a line holding 32 random bytes in hex, and
+/// writes back the hex encoded HMAC-SHA256 of the challenge bytes followed by
+/// its user name, keyed with its secret.
+#[derive(Debug, Clone, Default)]
+pub struct HmacSha256 {
+    /// Secret of each user allowed to attach.
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl HmacSha256 {
+    /// Creates the scheme for the given users.
+    ///
+    /// # Arguments
+    /// * `keys` - The secret of each user, by user name.
+    pub fn new(keys: HashMap<String, Vec<u8>>) -> Self {
+        Self { keys }
+    }
+
+    /// Loads user secrets from a file.
+    ///
+    /// Each non-empty line holds a user name and its secret, separated by
+    /// whitespace. Lines starting with `#` are ignored.
+    ///
+    /// # Arguments
+    /// * `path` - The file to read.
+    ///
+    /// # Returns
+    /// The scheme for the users listed in the file.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let mut keys = HashMap::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [user, secret] => {
+                    keys.insert(user.to_string(), secret.as_bytes().to_vec());
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "{}:{}: expected a user name and a secret",
+                        path.display(),
+                        number + 1
+                    ))
+                }
+            }
+        }
+        Ok(Self::new(keys))
+    }
+
+    /// Computes the response a client must send for a challenge.
+    ///
+    /// # Arguments
+    /// * `secret` - The secret of the user.
+    /// * `challenge` - The challenge bytes, not hex encoded.
+    /// * `uname` - The user name sent in the Tauth.
+    ///
+    /// # Returns
+    /// The hex encoded response.
+    pub fn response(secret: &[u8], challenge: &[u8], uname: &str) -> String {
+        to_hex(&Self::mac(secret, challenge, uname).finalize().into_bytes())
+    }
+
+    fn mac(secret: &[u8], challenge: &[u8], uname: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+        mac.update(challenge);
+        mac.update(uname.as_bytes());
+        mac
+    }
+}
+
+impl AuthScheme for HmacSha256 {
+    fn name(&self) -> &str {
+        "hmac-sha256"
+    }
+
+    fn start(&self, uname: &str) -> Box<dyn Conversation> {
+        let mut challenge = vec![0u8; CHALLENGE_SIZE];
+        getrandom::getrandom(&mut challenge).expect("system random number generator failed");
+        let outbox = format!("{}\n", to_hex(&challenge)).into_bytes();
+        Box::new(HmacConversation {
+            uname: uname.to_string(),
+            key: self.keys.get(uname).cloned(),
+            challenge,
+            outbox,
+            state: HmacState::Challenged,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HmacState {
+    Challenged,
+    Authenticated,
+    Failed,
+}
+
+#[derive(Debug)]
+struct HmacConversation {
+    uname: String,
+    key: Option<Vec<u8>>,
+    challenge: Vec<u8>,
+    outbox: Vec<u8>,
+    state: HmacState,
+}
+
+impl Conversation for HmacConversation {
+    fn read(&mut self, count: u32) -> Result<Vec<u8>> {
+        Ok(drain(&mut self.outbox, count))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<u32> {
+        if self.state != HmacState::Challenged {
+            return Err(Error::BadUse);
+        }
+
+        // A client may terminate the response with a newline or a NUL
+        let response = std::str::from_utf8(data)
+            .ok()
+            .map(|s| s.trim_end_matches(['\n', '\0']))
+            .and_then(from_hex);
+        let verified = match (&self.key, response) {
+            (Some(key), Some(response)) => HmacSha256::mac(key, &self.challenge, &self.uname)
+                .verify_slice(&response)
+                .is_ok(),
+            _ => false,
+        };
+
+        if !verified {
+            // A challenge may only be answered once
+            self.state = HmacState::Failed;
+            return Err(Error::AuthFailed);
+        }
+        self.state = HmacState::Authenticated;
+        Ok(data.len() as u32)
+    }
+
+    fn authenticated(&self) -> bool {
+        self.state == HmacState::Authenticated
+    }
+}
+
+/// Negotiation of the scheme to run, in the style of Plan 9's p9any.
+///
+/// The client reads the offer, `v.2 ` followed by the space separated
+/// `proto@domain` pairs of the available schemes and a NUL. It then writes
+/// `proto domain` followed by a NUL, reads `OK` followed by a NUL and
+/// continues with the chosen scheme on the same fid.
+#[derive(Debug, Clone)]
+pub struct P9any {
+    /// Authentication domain of the server.
+    domain: String,
+    /// Schemes offered to the client, in order of preference.
+    schemes: Vec<Arc<dyn AuthScheme>>,
+}
+
+impl P9any {
+    /// Creates a negotiation offering the given schemes.
+    ///
+    /// # Arguments
+    /// * `domain` - The authentication domain of the server.
+    /// * `schemes` - The schemes to offer, in order of preference.
+    pub fn new(domain: &str, schemes: Vec<Arc<dyn AuthScheme>>) -> Self {
+        Self {
+            domain: domain.to_string(),
+            schemes,
+        }
+    }
+}
+
+impl AuthScheme for P9any {
+    fn name(&self) -> &str {
+        "p9any"
+    }
+
+    fn start(&self, uname: &str) -> Box<dyn Conversation> {
+        let offer: Vec<String> = self
+            .schemes
+            .iter()
+            .map(|scheme| format!("{}@{}", scheme.name(), self.domain))
+            .collect();
+        Box::new(P9anyConversation {
+            uname: uname.to_string(),
+            negotiation: self.clone(),
+            outbox: format!("v.2 {}\0", offer.join(" ")).into_bytes(),
+            chosen: None,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct P9anyConversation {
+    uname: String,
+    negotiation: P9any,
+    outbox: Vec<u8>,
+    chosen: Option<Box<dyn Conversation>>,
+}
+
+impl Conversation for P9anyConversation {
+    fn read(&mut self, count: u32) -> Result<Vec<u8>> {
+        // The offer, or the OK after the choice, comes before anything the
+        // chosen scheme sends
+        if !self.outbox.is_empty() {
+            return Ok(drain(&mut self.outbox, count));
+        }
+        match &mut self.chosen {
+            Some(conversation) => conversation.read(count),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<u32> {
+        if let Some(conversation) = &mut self.chosen {
+            return conversation.write(data);
+        }
+
+        let choice = String::from_utf8_lossy(data);
+        let choice = choice.trim_end_matches(['\n', '\0']);
+        let scheme = match choice.split(' ').collect::<Vec<_>>()[..] {
+            [proto, domain] if domain == self.negotiation.domain => self
+                .negotiation
+                .schemes
+                .iter()
+                .find(|scheme| scheme.name() == proto),
+            _ => None,
+        }
+        .ok_or(Error::AuthFailed)?;
+
+        self.chosen = Some(scheme.start(&self.uname));
+        self.outbox = b"OK\0".to_vec();
+        Ok(data.len() as u32)
+    }
+
+    fn authenticated(&self) -> bool {
+        self.chosen
+            .as_ref()
+            .is_some_and(|conversation| conversation.authenticated())
+    }
+}
+
+// Removes and returns up to `count` bytes from the front of `outbox`
+fn drain(outbox: &mut Vec<u8>, count: u32) -> Vec<u8> {
+    let end = std::cmp::min(count as usize, outbox.len());
+    outbox.drain(..end).collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    // An odd length leaves a single digit, which `get` refuses
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> HashMap<String, Vec<u8>> {
+        HashMap::from([("glenda".to_string(), b"secret".to_vec())])
+    }
+
+    // Reads the hex challenge sent by the server
+    fn read_challenge(conversation: &mut Box<dyn Conversation>) -> Vec<u8> {
+        let line = conversation.read(1024).unwrap();
+        from_hex(std::str::from_utf8(&line).unwrap().trim_end()).unwrap()
+    }
+
+    #[test]
+    fn test_hmac_accepts_correct_response() -> Result<()> {
+        let scheme = HmacSha256::new(keys());
+        let mut conversation = scheme.start("glenda");
+        let challenge = read_challenge(&mut conversation);
+        assert_eq!(challenge.len(), CHALLENGE_SIZE);
+        assert!(!conversation.authenticated());
+
+        let response = HmacSha256::response(b"secret", &challenge, "glenda");
+        conversation.write(format!("{}\n", response).as_bytes())?;
+        assert!(conversation.authenticated());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hmac_rejects_wrong_response() {
+        let scheme = HmacSha256::new(keys());
+
+        // Wrong secret, and the challenge cannot be retried
+        let mut conversation = scheme.start("glenda");
+        let challenge = read_challenge(&mut conversation);
+        let wrong = HmacSha256::response(b"guess", &challenge, "glenda");
+        assert!(conversation.write(wrong.as_bytes()).is_err());
+        let right = HmacSha256::response(b"secret", &challenge, "glenda");
+        assert!(conversation.write(right.as_bytes()).is_err());
+        assert!(!conversation.authenticated());
+
+        // A response computed for another user
+        let mut conversation = scheme.start("glenda");
+        let challenge = read_challenge(&mut conversation);
+        let other = HmacSha256::response(b"secret", &challenge, "bootes");
+        assert!(conversation.write(other.as_bytes()).is_err());
+
+        // Users without a secret never authenticate
+        let mut conversation = scheme.start("bootes");
+        let challenge = read_challenge(&mut conversation);
+        let response = HmacSha256::response(b"", &challenge, "bootes");
+        assert!(conversation.write(response.as_bytes()).is_err());
+        assert!(conversation.write(b"not hex").is_err());
+    }
+
+    #[test]
+    fn test_p9any_negotiation() -> Result<()> {
+        let hmac: Arc<dyn AuthScheme> = Arc::new(HmacSha256::new(keys()));
+        let scheme = P9any::new("example", vec![hmac]);
+
+        let mut conversation = scheme.start("glenda");
+        // Reads may be shorter than a message
+        let mut offer = conversation.read(4)?;
+        offer.extend(conversation.read(1024)?);
+        assert_eq!(offer, b"v.2 hmac-sha256@example\0");
+
+        let mut unknown = scheme.start("glenda");
+        assert!(unknown.write(b"p9sk1 example\0").is_err());
+        assert!(unknown.write(b"hmac-sha256 other\0").is_err());
+
+        conversation.write(b"hmac-sha256 example\0")?;
+        assert_eq!(conversation.read(1024)?, b"OK\0");
+        let challenge = read_challenge(&mut conversation);
+        let response = HmacSha256::response(b"secret", &challenge, "glenda");
+        assert!(!conversation.authenticated());
+        conversation.write(response.as_bytes())?;
+        assert!(conversation.authenticated());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_from_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("keys");
+        fs::write(&path, "# user secret\nglenda secret\n\nbootes  other\n")?;
+        let scheme = HmacSha256::from_file(&path)?;
+        assert_eq!(scheme.keys["glenda"], b"secret");
+        assert_eq!(scheme.keys["bootes"], b"other");
+
+        fs::write(&path, "glenda\n")?;
+        assert!(HmacSha256::from_file(&path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,2319 @@
+//! 9P2000 wire format encoding and decoding.
+//!
+//! Every 9P message travels as `size[4] type[1] tag[2]` followed by a
+//! type-specific body. Integers are little-endian, strings carry a 2-byte
+//! length prefix, qids are 13 bytes and stat structures are prefixed with
+//! their own 2-byte size. This module converts between that byte format and
+//! the `Tmessage`/`Rmessage` types that the rest of froggr works with.
+//!
+//! The 9P2000.u extension adds numeric ids and an extension string to stat
+//! structures, Tauth, Tattach and Tcreate, and an errno to Rerror. 9P2000.L
+//! keeps the numeric ids in Tauth and Tattach, replaces Rerror with Rlerror
+//! and adds the Linux-specific requests such as Tlopen, Tgetattr and
+//! Treaddir. Which layout is used is selected with a `Dialect`.
+
+use super::proto::{Flock, Getattr, Qid, Setattr, Stat, Statfs};
+use anyhow::{anyhow, Result};
+
+/// Tag used by Tversion, which is sent before any other request.
+pub const NOTAG: u16 = 0xFFFF;
+
+/// Fid value meaning "no fid", e.g. the afid of an unauthenticated Tattach.
+pub const NOFID: u32 = 0xFFFF_FFFF;
+
+/// Mode bit of directories.
+pub const DMDIR: u32 = 0x8000_0000;
+/// Mode bit of append-only files.
+pub const DMAPPEND: u32 = 0x4000_0000;
+/// Mode bit of files that only one client may open at a time.
+pub const DMEXCL: u32 = 0x2000_0000;
+/// Mode bit of authentication files.
+pub const DMAUTH: u32 = 0x0800_0000;
+/// Mode bit of symbolic links (9P2000.u).
+pub const DMSYMLINK: u32 = 0x0200_0000;
+/// Mode bit of hard links in a 9P2000.u Tcreate; the extension holds the
+/// fid of the file to link to.
+pub const DMLINK: u32 = 0x0100_0000;
+/// Mode bit of device files (9P2000.u).
+pub const DMDEVICE: u32 = 0x0080_0000;
+/// Mode bit of named pipes (9P2000.u).
+pub const DMNAMEDPIPE: u32 = 0x0020_0000;
+/// Mode bit of sockets (9P2000.u).
+pub const DMSOCKET: u32 = 0x0010_0000;
+/// Mode bit of setuid files (9P2000.u).
+pub const DMSETUID: u32 = 0x0008_0000;
+/// Mode bit of setgid files (9P2000.u).
+pub const DMSETGID: u32 = 0x0004_0000;
+
+/// Tgetattr mask of the fields returned by a basic stat.
+pub const GETATTR_BASIC: u64 = 0x0000_07ff;
+
+/// Tsetattr bit: change the permission bits.
+pub const SETATTR_MODE: u32 = 0x0000_0001;
+/// Tsetattr bit: change the owner.
+pub const SETATTR_UID: u32 = 0x0000_0002;
+/// Tsetattr bit: change the group.
+pub const SETATTR_GID: u32 = 0x0000_0004;
+/// Tsetattr bit: change the length.
+pub const SETATTR_SIZE: u32 = 0x0000_0008;
+/// Tsetattr bit: set the access time, to now unless `SETATTR_ATIME_SET`.
+pub const SETATTR_ATIME: u32 = 0x0000_0010;
+/// Tsetattr bit: set the modification time, to now unless `SETATTR_MTIME_SET`.
+pub const SETATTR_MTIME: u32 = 0x0000_0020;
+/// Tsetattr bit: update the status change time.
+pub const SETATTR_CTIME: u32 = 0x0000_0040;
+/// Tsetattr bit: use the given access time.
+pub const SETATTR_ATIME_SET: u32 = 0x0000_0080;
+/// Tsetattr bit: use the given modification time.
+pub const SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+/// Lock type: shared read lock.
+pub const LOCK_TYPE_RDLCK: u8 = 0;
+/// Lock type: exclusive write lock.
+pub const LOCK_TYPE_WRLCK: u8 = 1;
+/// Lock type: release a lock.
+pub const LOCK_TYPE_UNLCK: u8 = 2;
+
+/// Rlock status: the lock was acquired.
+pub const LOCK_SUCCESS: u8 = 0;
+/// Rlock status: the lock is held by another owner.
+pub const LOCK_BLOCKED: u8 = 1;
+
+/// Value of `n_uname` when the client did not send a numeric user id.
+pub const NONUNAME: u32 = 0xFFFF_FFFF;
+
+/// Maximum number of path elements in a single Twalk.
+pub const MAXWELEM: usize = 16;
+
+/// Size of the `size[4] type[1] tag[2]` header shared by all messages.
+pub const HEADER_SIZE: usize = 7;
+
+/// Header overhead of Twrite/Rread, subtracted from msize to obtain the iounit.
+pub const IOHDRSZ: u32 = 24;
+
+/// Linux error reply (9P2000.L)
+pub const RLERROR: u8 = 7;
+/// Filesystem statistics request (9P2000.L)
+pub const TSTATFS: u8 = 8;
+/// Filesystem statistics reply (9P2000.L)
+pub const RSTATFS: u8 = 9;
+/// Linux open request (9P2000.L)
+pub const TLOPEN: u8 = 12;
+/// Linux open reply (9P2000.L)
+pub const RLOPEN: u8 = 13;
+/// Linux create request (9P2000.L)
+pub const TLCREATE: u8 = 14;
+/// Linux create reply (9P2000.L)
+pub const RLCREATE: u8 = 15;
+/// Symlink request (9P2000.L)
+pub const TSYMLINK: u8 = 16;
+/// Symlink reply (9P2000.L)
+pub const RSYMLINK: u8 = 17;
+/// Device node request (9P2000.L)
+pub const TMKNOD: u8 = 18;
+/// Device node reply (9P2000.L)
+pub const RMKNOD: u8 = 19;
+/// Readlink request (9P2000.L)
+pub const TREADLINK: u8 = 22;
+/// Readlink reply (9P2000.L)
+pub const RREADLINK: u8 = 23;
+/// Getattr request (9P2000.L)
+pub const TGETATTR: u8 = 24;
+/// Getattr reply (9P2000.L)
+pub const RGETATTR: u8 = 25;
+/// Setattr request (9P2000.L)
+pub const TSETATTR: u8 = 26;
+/// Setattr reply (9P2000.L)
+pub const RSETATTR: u8 = 27;
+/// Extended attribute walk request (9P2000.L)
+pub const TXATTRWALK: u8 = 30;
+/// Extended attribute walk reply (9P2000.L)
+pub const RXATTRWALK: u8 = 31;
+/// Extended attribute create request (9P2000.L)
+pub const TXATTRCREATE: u8 = 32;
+/// Extended attribute create reply (9P2000.L)
+pub const RXATTRCREATE: u8 = 33;
+/// Readdir request (9P2000.L)
+pub const TREADDIR: u8 = 40;
+/// Readdir reply (9P2000.L)
+pub const RREADDIR: u8 = 41;
+/// Fsync request (9P2000.L)
+pub const TFSYNC: u8 = 50;
+/// Fsync reply (9P2000.L)
+pub const RFSYNC: u8 = 51;
+/// Lock request (9P2000.L)
+pub const TLOCK: u8 = 52;
+/// Lock reply (9P2000.L)
+pub const RLOCK: u8 = 53;
+/// Getlock request (9P2000.L)
+pub const TGETLOCK: u8 = 54;
+/// Getlock reply (9P2000.L)
+pub const RGETLOCK: u8 = 55;
+/// Hard link request (9P2000.L)
+pub const TLINK: u8 = 70;
+/// Hard link reply (9P2000.L)
+pub const RLINK: u8 = 71;
+/// Mkdir request (9P2000.L)
+pub const TMKDIR: u8 = 72;
+/// Mkdir reply (9P2000.L)
+pub const RMKDIR: u8 = 73;
+/// Renameat request (9P2000.L)
+pub const TRENAMEAT: u8 = 74;
+/// Renameat reply (9P2000.L)
+pub const RRENAMEAT: u8 = 75;
+/// Unlinkat request (9P2000.L)
+pub const TUNLINKAT: u8 = 76;
+/// Unlinkat reply (9P2000.L)
+pub const RUNLINKAT: u8 = 77;
+
+/// Version request
+pub const TVERSION: u8 = 100;
+/// Version reply
+pub const RVERSION: u8 = 101;
+/// Authentication request
+pub const TAUTH: u8 = 102;
+/// Authentication reply
+pub const RAUTH: u8 = 103;
+/// Attach request
+pub const TATTACH: u8 = 104;
+/// Attach reply
+pub const RATTACH: u8 = 105;
+/// Error request (illegal on the wire, reserved by the protocol)
+pub const TERROR: u8 = 106;
+/// Error reply
+pub const RERROR: u8 = 107;
+/// Flush request
+pub const TFLUSH: u8 = 108;
+/// Flush reply
+pub const RFLUSH: u8 = 109;
+/// Walk request
+pub const TWALK: u8 = 110;
+/// Walk reply
+pub const RWALK: u8 = 111;
+/// Open request
+pub const TOPEN: u8 = 112;
+/// Open reply
+pub const ROPEN: u8 = 113;
+/// Create request
+pub const TCREATE: u8 = 114;
+/// Create reply
+pub const RCREATE: u8 = 115;
+/// Read request
+pub const TREAD: u8 = 116;
+/// Read reply
+pub const RREAD: u8 = 117;
+/// Write request
+pub const TWRITE: u8 = 118;
+/// Write reply
+pub const RWRITE: u8 = 119;
+/// Clunk request
+pub const TCLUNK: u8 = 120;
+/// Clunk reply
+pub const RCLUNK: u8 = 121;
+/// Remove request
+pub const TREMOVE: u8 = 122;
+/// Remove reply
+pub const RREMOVE: u8 = 123;
+/// Stat request
+pub const TSTAT: u8 = 124;
+/// Stat reply
+pub const RSTAT: u8 = 125;
+/// Wstat request
+pub const TWSTAT: u8 = 126;
+/// Wstat reply
+pub const RWSTAT: u8 = 127;
+
+/// Protocol dialect negotiated by Tversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Plain 9P2000
+    Plan9,
+    /// 9P2000.u, with Unix ownership, special files and errno
+    Unix,
+    /// 9P2000.L, with the requests used by the Linux v9fs client
+    Linux,
+}
+
+impl Dialect {
+    /// Returns the dialect for a version string, if it is supported.
+    ///
+    /// # Arguments
+    /// * `version` - The version string sent in Tversion.
+    pub fn from_version(version: &str) -> Option<Self> {
+        match version {
+            "9P2000" => Some(Dialect::Plan9),
+            "9P2000.u" => Some(Dialect::Unix),
+            "9P2000.L" => Some(Dialect::Linux),
+            _ => None,
+        }
+    }
+
+    /// Returns the version string of the dialect.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Dialect::Plan9 => "9P2000",
+            Dialect::Unix => "9P2000.u",
+            Dialect::Linux => "9P2000.L",
+        }
+    }
+}
+
+/// A request sent from a 9P client to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tmessage {
+    /// Negotiates the protocol version and maximum message size
+    Version {
+        /// Maximum message size the client is willing to handle
+        msize: u32,
+        /// Requested protocol version
+        version: String,
+    },
+    /// Opens an authentication fid
+    Auth {
+        /// Fid to use for the authentication exchange
+        afid: u32,
+        /// User name
+        uname: String,
+        /// Tree the user wants to attach to
+        aname: String,
+        /// Numeric user id (9P2000.u), or `NONUNAME`
+        n_uname: u32,
+    },
+    /// Attaches a fid to the root of a file tree
+    Attach {
+        /// Fid that will represent the root
+        fid: u32,
+        /// Authentication fid, or `NOFID`
+        afid: u32,
+        /// User name
+        uname: String,
+        /// Tree to attach to
+        aname: String,
+        /// Numeric user id (9P2000.u), or `NONUNAME`
+        n_uname: u32,
+    },
+    /// Aborts a pending request
+    Flush {
+        /// Tag of the request to abort
+        oldtag: u16,
+    },
+    /// Walks a fid through a sequence of path elements
+    Walk {
+        /// Fid to start from
+        fid: u32,
+        /// Fid that will represent the walk result
+        newfid: u32,
+        /// Path elements to walk
+        wnames: Vec<String>,
+    },
+    /// Opens a fid for I/O
+    Open {
+        /// Fid to open
+        fid: u32,
+        /// Open mode (OREAD, OWRITE, ... combined with OTRUNC/ORCLOSE)
+        mode: u8,
+    },
+    /// Creates a file in the directory represented by a fid
+    Create {
+        /// Fid of the parent directory, which then represents the new file
+        fid: u32,
+        /// Name of the new file
+        name: String,
+        /// Permissions and mode bits of the new file
+        perm: u32,
+        /// Open mode for the new file
+        mode: u8,
+        /// Symlink target or device description of special files (9P2000.u)
+        extension: String,
+    },
+    /// Reads from an open fid
+    Read {
+        /// Fid to read from
+        fid: u32,
+        /// Offset to start reading at
+        offset: u64,
+        /// Maximum number of bytes to return
+        count: u32,
+    },
+    /// Writes to an open fid
+    Write {
+        /// Fid to write to
+        fid: u32,
+        /// Offset to start writing at
+        offset: u64,
+        /// Bytes to write
+        data: Vec<u8>,
+    },
+    /// Releases a fid
+    Clunk {
+        /// Fid to release
+        fid: u32,
+    },
+    /// Removes the file represented by a fid and releases the fid
+    Remove {
+        /// Fid of the file to remove
+        fid: u32,
+    },
+    /// Requests the stat of a fid
+    Stat {
+        /// Fid to stat
+        fid: u32,
+    },
+    /// Changes the stat of a fid
+    Wstat {
+        /// Fid to modify
+        fid: u32,
+        /// New stat values
+        stat: Stat,
+    },
+    /// Requests filesystem statistics (9P2000.L)
+    Statfs {
+        /// Any fid on the filesystem
+        fid: u32,
+    },
+    /// Opens a fid with Linux open flags (9P2000.L)
+    Lopen {
+        /// Fid to open
+        fid: u32,
+        /// Linux open flags (`O_RDONLY`, `O_TRUNC`, ...)
+        flags: u32,
+    },
+    /// Creates and opens a regular file (9P2000.L)
+    Lcreate {
+        /// Fid of the parent directory, which then represents the new file
+        fid: u32,
+        /// Name of the new file
+        name: String,
+        /// Linux open flags
+        flags: u32,
+        /// Permission bits of the new file
+        mode: u32,
+        /// Group id of the new file
+        gid: u32,
+    },
+    /// Creates a symbolic link (9P2000.L)
+    Symlink {
+        /// Fid of the parent directory
+        fid: u32,
+        /// Name of the link
+        name: String,
+        /// Target of the link
+        symtgt: String,
+        /// Group id of the link
+        gid: u32,
+    },
+    /// Creates a device node, named pipe or socket (9P2000.L)
+    Mknod {
+        /// Fid of the parent directory
+        dfid: u32,
+        /// Name of the node
+        name: String,
+        /// File type and permission bits, as in `st_mode`
+        mode: u32,
+        /// Major device number
+        major: u32,
+        /// Minor device number
+        minor: u32,
+        /// Group id of the node
+        gid: u32,
+    },
+    /// Reads the target of a symbolic link (9P2000.L)
+    Readlink {
+        /// Fid of the link
+        fid: u32,
+    },
+    /// Requests the attributes of a fid (9P2000.L)
+    Getattr {
+        /// Fid to query
+        fid: u32,
+        /// Mask of the requested fields
+        request_mask: u64,
+    },
+    /// Changes the attributes of a fid (9P2000.L)
+    Setattr {
+        /// Fid to modify
+        fid: u32,
+        /// Attributes to change
+        attr: Setattr,
+    },
+    /// Walks a fid to one or all of its extended attributes (9P2000.L)
+    Xattrwalk {
+        /// Fid of the file
+        fid: u32,
+        /// Fid that will represent the attribute
+        newfid: u32,
+        /// Name of the attribute, or empty to list all attributes
+        name: String,
+    },
+    /// Prepares a fid for setting an extended attribute (9P2000.L)
+    Xattrcreate {
+        /// Fid of the file, which then represents the attribute
+        fid: u32,
+        /// Name of the attribute
+        name: String,
+        /// Length of the value that will be written
+        attr_size: u64,
+        /// Linux setxattr flags
+        flags: u32,
+    },
+    /// Reads directory entries (9P2000.L)
+    Readdir {
+        /// Fid of the open directory
+        fid: u32,
+        /// Offset returned with the last entry of the previous read, or 0
+        offset: u64,
+        /// Maximum number of bytes to return
+        count: u32,
+    },
+    /// Flushes a file to stable storage (9P2000.L)
+    Fsync {
+        /// Fid of the open file
+        fid: u32,
+        /// Non-zero to only flush the file data
+        datasync: u32,
+    },
+    /// Acquires or releases a byte-range lock (9P2000.L)
+    Lock {
+        /// Fid of the open file
+        fid: u32,
+        /// The lock to acquire or release
+        lock: Flock,
+    },
+    /// Tests whether a byte-range lock could be acquired (9P2000.L)
+    Getlock {
+        /// Fid of the open file
+        fid: u32,
+        /// The lock to test
+        lock: Flock,
+    },
+    /// Creates a hard link (9P2000.L)
+    Link {
+        /// Fid of the directory to create the link in
+        dfid: u32,
+        /// Fid of the file to link to
+        fid: u32,
+        /// Name of the link
+        name: String,
+    },
+    /// Creates a directory (9P2000.L)
+    Mkdir {
+        /// Fid of the parent directory
+        dfid: u32,
+        /// Name of the new directory
+        name: String,
+        /// Permission bits of the new directory
+        mode: u32,
+        /// Group id of the new directory
+        gid: u32,
+    },
+    /// Renames a directory entry (9P2000.L)
+    Renameat {
+        /// Fid of the directory containing the entry
+        olddirfid: u32,
+        /// Current name of the entry
+        oldname: String,
+        /// Fid of the directory to move the entry to
+        newdirfid: u32,
+        /// New name of the entry
+        newname: String,
+    },
+    /// Removes a directory entry (9P2000.L)
+    Unlinkat {
+        /// Fid of the directory containing the entry
+        dirfd: u32,
+        /// Name of the entry
+        name: String,
+        /// `AT_REMOVEDIR` to remove a directory
+        flags: u32,
+    },
+}
+
+/// A reply sent from the 9P server to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rmessage {
+    /// Negotiated protocol version and maximum message size
+    Version {
+        /// Maximum message size the server will use
+        msize: u32,
+        /// Negotiated protocol version
+        version: String,
+    },
+    /// Qid of a newly opened authentication fid
+    Auth {
+        /// Qid of the authentication file
+        aqid: Qid,
+    },
+    /// Qid of the attached root
+    Attach {
+        /// Qid of the root directory
+        qid: Qid,
+    },
+    /// Failure of the corresponding request
+    Error {
+        /// Error description
+        ename: String,
+        /// Unix error number (9P2000.u)
+        errno: u32,
+    },
+    /// Acknowledges a flush
+    Flush,
+    /// Qids of the path elements that were walked
+    Walk {
+        /// One qid per successfully walked element
+        wqids: Vec<Qid>,
+    },
+    /// Result of an open
+    Open {
+        /// Qid of the opened file
+        qid: Qid,
+        /// Maximum number of bytes guaranteed to be transferred atomically
+        iounit: u32,
+    },
+    /// Result of a create
+    Create {
+        /// Qid of the created file
+        qid: Qid,
+        /// Maximum number of bytes guaranteed to be transferred atomically
+        iounit: u32,
+    },
+    /// Data returned by a read
+    Read {
+        /// Bytes read
+        data: Vec<u8>,
+    },
+    /// Result of a write
+    Write {
+        /// Number of bytes written
+        count: u32,
+    },
+    /// Acknowledges a clunk
+    Clunk,
+    /// Acknowledges a remove
+    Remove,
+    /// Stat of a fid
+    Stat {
+        /// The requested stat
+        stat: Stat,
+    },
+    /// Acknowledges a wstat
+    Wstat,
+    /// Failure of the corresponding request (9P2000.L)
+    Lerror {
+        /// Linux error number
+        ecode: u32,
+    },
+    /// Filesystem statistics (9P2000.L)
+    Statfs {
+        /// The requested statistics
+        statfs: Statfs,
+    },
+    /// Result of a Linux open (9P2000.L)
+    Lopen {
+        /// Qid of the opened file
+        qid: Qid,
+        /// Maximum number of bytes guaranteed to be transferred atomically
+        iounit: u32,
+    },
+    /// Result of a Linux create (9P2000.L)
+    Lcreate {
+        /// Qid of the created file
+        qid: Qid,
+        /// Maximum number of bytes guaranteed to be transferred atomically
+        iounit: u32,
+    },
+    /// Qid of a new symbolic link (9P2000.L)
+    Symlink {
+        /// Qid of the link
+        qid: Qid,
+    },
+    /// Qid of a new device node (9P2000.L)
+    Mknod {
+        /// Qid of the node
+        qid: Qid,
+    },
+    /// Target of a symbolic link (9P2000.L)
+    Readlink {
+        /// Target of the link
+        target: String,
+    },
+    /// Attributes of a fid (9P2000.L)
+    Getattr {
+        /// The requested attributes
+        attr: Getattr,
+    },
+    /// Acknowledges a setattr (9P2000.L)
+    Setattr,
+    /// Length of the walked extended attribute (9P2000.L)
+    Xattrwalk {
+        /// Length of the attribute value or name list
+        size: u64,
+    },
+    /// Acknowledges an xattrcreate (9P2000.L)
+    Xattrcreate,
+    /// Directory entries (9P2000.L)
+    Readdir {
+        /// Packed entries, see `encode_dirent`
+        data: Vec<u8>,
+    },
+    /// Acknowledges an fsync (9P2000.L)
+    Fsync,
+    /// Result of a lock request (9P2000.L)
+    Lock {
+        /// `LOCK_SUCCESS`, `LOCK_BLOCKED` or another Rlock status
+        status: u8,
+    },
+    /// Conflicting lock, or the tested lock with type unlock (9P2000.L)
+    Getlock {
+        /// The conflicting lock
+        lock: Flock,
+    },
+    /// Acknowledges a link (9P2000.L)
+    Link,
+    /// Qid of a new directory (9P2000.L)
+    Mkdir {
+        /// Qid of the directory
+        qid: Qid,
+    },
+    /// Acknowledges a renameat (9P2000.L)
+    Renameat,
+    /// Acknowledges an unlinkat (9P2000.L)
+    Unlinkat,
+}
+
+impl Tmessage {
+    /// Returns the 9P message type number of this request.
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Tmessage::Version { .. } => TVERSION,
+            Tmessage::Auth { .. } => TAUTH,
+            Tmessage::Attach { .. } => TATTACH,
+            Tmessage::Flush { .. } => TFLUSH,
+            Tmessage::Walk { .. } => TWALK,
+            Tmessage::Open { .. } => TOPEN,
+            Tmessage::Create { .. } => TCREATE,
+            Tmessage::Read { .. } => TREAD,
+            Tmessage::Write { .. } => TWRITE,
+            Tmessage::Clunk { .. } => TCLUNK,
+            Tmessage::Remove { .. } => TREMOVE,
+            Tmessage::Stat { .. } => TSTAT,
+            Tmessage::Wstat { .. } => TWSTAT,
+            Tmessage::Statfs { .. } => TSTATFS,
+            Tmessage::Lopen { .. } => TLOPEN,
+            Tmessage::Lcreate { .. } => TLCREATE,
+            Tmessage::Symlink { .. } => TSYMLINK,
+            Tmessage::Mknod { .. } => TMKNOD,
+            Tmessage::Readlink { .. } => TREADLINK,
+            Tmessage::Getattr { .. } => TGETATTR,
+            Tmessage::Setattr { .. } => TSETATTR,
+            Tmessage::Xattrwalk { .. } => TXATTRWALK,
+            Tmessage::Xattrcreate { .. } => TXATTRCREATE,
+            Tmessage::Readdir { .. } => TREADDIR,
+            Tmessage::Fsync { .. } => TFSYNC,
+            Tmessage::Lock { .. } => TLOCK,
+            Tmessage::Getlock { .. } => TGETLOCK,
+            Tmessage::Link { .. } => TLINK,
+            Tmessage::Mkdir { .. } => TMKDIR,
+            Tmessage::Renameat { .. } => TRENAMEAT,
+            Tmessage::Unlinkat { .. } => TUNLINKAT,
+        }
+    }
+
+    /// Encodes the request into a complete 9P message.
+    ///
+    /// # Arguments
+    /// * `tag` - The tag identifying this request.
+    /// * `dialect` - The negotiated protocol dialect.
+    ///
+    /// # Returns
+    /// The message bytes, including the size header, or an error if a field
+    /// is too long for its length prefix.
+    pub fn encode(&self, tag: u16, dialect: Dialect) -> Result<Vec<u8>> {
+        let mut enc = Encoder::new(self.message_type(), tag, dialect);
+        match self {
+            Tmessage::Version { msize, version } => {
+                enc.put_u32(*msize);
+                enc.put_str(version);
+            }
+            Tmessage::Auth {
+                afid,
+                uname,
+                aname,
+                n_uname,
+            } => {
+                enc.put_u32(*afid);
+                enc.put_str(uname);
+                enc.put_str(aname);
+                enc.put_n_uname(*n_uname);
+            }
+            Tmessage::Attach {
+                fid,
+                afid,
+                uname,
+                aname,
+                n_uname,
+            } => {
+                enc.put_u32(*fid);
+                enc.put_u32(*afid);
+                enc.put_str(uname);
+                enc.put_str(aname);
+                enc.put_n_uname(*n_uname);
+            }
+            Tmessage::Flush { oldtag } => enc.put_u16(*oldtag),
+            Tmessage::Walk {
+                fid,
+                newfid,
+                wnames,
+            } => {
+                enc.put_u32(*fid);
+                enc.put_u32(*newfid);
+                enc.put_u16(wnames.len() as u16);
+                for name in wnames {
+                    enc.put_str(name);
+                }
+            }
+            Tmessage::Open { fid, mode } => {
+                enc.put_u32(*fid);
+                enc.put_u8(*mode);
+            }
+            Tmessage::Create {
+                fid,
+                name,
+                perm,
+                mode,
+                extension,
+            } => {
+                enc.put_u32(*fid);
+                enc.put_str(name);
+                enc.put_u32(*perm);
+                enc.put_u8(*mode);
+                if enc.dialect == Dialect::Unix {
+                    enc.put_str(extension);
+                }
+            }
+            Tmessage::Read { fid, offset, count } => {
+                enc.put_u32(*fid);
+                enc.put_u64(*offset);
+                enc.put_u32(*count);
+            }
+            Tmessage::Write { fid, offset, data } => {
+                enc.put_u32(*fid);
+                enc.put_u64(*offset);
+                enc.put_data(data);
+            }
+            Tmessage::Clunk { fid } | Tmessage::Remove { fid } | Tmessage::Stat { fid } => {
+                enc.put_u32(*fid)
+            }
+            Tmessage::Wstat { fid, stat } => {
+                enc.put_u32(*fid);
+                enc.put_stat_n(stat);
+            }
+            Tmessage::Statfs { fid } | Tmessage::Readlink { fid } => enc.put_u32(*fid),
+            Tmessage::Lopen { fid, flags } => {
+                enc.put_u32(*fid);
+                enc.put_u32(*flags);
+            }
+            Tmessage::Lcreate {
+                fid,
+                name,
+                flags,
+                mode,
+                gid,
+            } => {
+                enc.put_u32(*fid);
+                enc.put_str(name);
+                enc.put_u32(*flags);
+                enc.put_u32(*mode);
+                enc.put_u32(*gid);
+            }
+            Tmessage::Symlink {
+                fid,
+                name,
+                symtgt,
+                gid,
+            } => {
+                enc.put_u32(*fid);
+                enc.put_str(name);
+                enc.put_str(symtgt);
+                enc.put_u32(*gid);
+            }
+            Tmessage::Mknod {
+                dfid,
+                name,
+                mode,
+                major,
+                minor,
+                gid,
+            } => {
+                enc.put_u32(*dfid);
+                enc.put_str(name);
+                enc.put_u32(*mode);
+                enc.put_u32(*major);
+                enc.put_u32(*minor);
+                enc.put_u32(*gid);
+            }
+            Tmessage::Getattr { fid, request_mask } => {
+                enc.put_u32(*fid);
+                enc.put_u64(*request_mask);
+            }
+            Tmessage::Setattr { fid, attr } => {
+                enc.put_u32(*fid);
+                enc.put_u32(attr.valid);
+                enc.put_u32(attr.mode);
+                enc.put_u32(attr.uid);
+                enc.put_u32(attr.gid);
+                enc.put_u64(attr.size);
+                enc.put_u64(attr.atime_sec);
+                enc.put_u64(attr.atime_nsec);
+                enc.put_u64(attr.mtime_sec);
+                enc.put_u64(attr.mtime_nsec);
+            }
+            Tmessage::Xattrwalk { fid, newfid, name } => {
+                enc.put_u32(*fid);
+                enc.put_u32(*newfid);
+                enc.put_str(name);
+            }
+            Tmessage::Xattrcreate {
+                fid,
+                name,
+                attr_size,
+                flags,
+            } => {
+                enc.put_u32(*fid);
+                enc.put_str(name);
+                enc.put_u64(*attr_size);
+                enc.put_u32(*flags);
+            }
+            Tmessage::Readdir { fid, offset, count } => {
+                enc.put_u32(*fid);
+                enc.put_u64(*offset);
+                enc.put_u32(*count);
+            }
+            Tmessage::Fsync { fid, datasync } => {
+                enc.put_u32(*fid);
+                enc.put_u32(*datasync);
+            }
+            Tmessage::Lock { fid, lock } => {
+                enc.put_u32(*fid);
+                enc.put_u8(lock.typ);
+                enc.put_u32(lock.flags);
+                enc.put_flock_range(lock);
+            }
+            Tmessage::Getlock { fid, lock } => {
+                enc.put_u32(*fid);
+                enc.put_u8(lock.typ);
+                enc.put_flock_range(lock);
+            }
+            Tmessage::Link { dfid, fid, name } => {
+                enc.put_u32(*dfid);
+                enc.put_u32(*fid);
+                enc.put_str(name);
+            }
+            Tmessage::Mkdir {
+                dfid,
+                name,
+                mode,
+                gid,
+            } => {
+                enc.put_u32(*dfid);
+                enc.put_str(name);
+                enc.put_u32(*mode);
+                enc.put_u32(*gid);
+            }
+            Tmessage::Renameat {
+                olddirfid,
+                oldname,
+                newdirfid,
+                newname,
+            } => {
+                enc.put_u32(*olddirfid);
+                enc.put_str(oldname);
+                enc.put_u32(*newdirfid);
+                enc.put_str(newname);
+            }
+            Tmessage::Unlinkat { dirfd, name, flags } => {
+                enc.put_u32(*dirfd);
+                enc.put_str(name);
+                enc.put_u32(*flags);
+            }
+        }
+        enc.finish()
+    }
+
+    /// Decodes a complete 9P request.
+    ///
+    /// # Arguments
+    /// * `buf` - The message bytes, including the size header.
+    /// * `dialect` - The negotiated protocol dialect.
+    ///
+    /// # Returns
+    /// A tuple containing the tag and the decoded request.
+    pub fn decode(buf: &[u8], dialect: Dialect) -> Result<(u16, Self)> {
+        let (typ, tag, mut dec) = Decoder::message(buf, dialect)?;
+        let msg = match typ {
+            TVERSION => Tmessage::Version {
+                msize: dec.u32()?,
+                version: dec.string()?,
+            },
+            TAUTH => Tmessage::Auth {
+                afid: dec.u32()?,
+                uname: dec.string()?,
+                aname: dec.string()?,
+                n_uname: dec.n_uname()?,
+            },
+            TATTACH => Tmessage::Attach {
+                fid: dec.u32()?,
+                afid: dec.u32()?,
+                uname: dec.string()?,
+                aname: dec.string()?,
+                n_uname: dec.n_uname()?,
+            },
+            TFLUSH => Tmessage::Flush { oldtag: dec.u16()? },
+            TWALK => {
+                let fid = dec.u32()?;
+                let newfid = dec.u32()?;
+                let nwname = dec.u16()? as usize;
+                let mut wnames = Vec::with_capacity(nwname);
+                for _ in 0..nwname {
+                    wnames.push(dec.string()?);
+                }
+                Tmessage::Walk {
+                    fid,
+                    newfid,
+                    wnames,
+                }
+            }
+            TOPEN => Tmessage::Open {
+                fid: dec.u32()?,
+                mode: dec.u8()?,
+            },
+            TCREATE => Tmessage::Create {
+                fid: dec.u32()?,
+                name: dec.string()?,
+                perm: dec.u32()?,
+                mode: dec.u8()?,
+                extension: if dec.dialect == Dialect::Unix {
+                    dec.string()?
+                } else {
+                    String::new()
+                },
+            },
+            TREAD => Tmessage::Read {
+                fid: dec.u32()?,
+                offset: dec.u64()?,
+                count: dec.u32()?,
+            },
+            TWRITE => Tmessage::Write {
+                fid: dec.u32()?,
+                offset: dec.u64()?,
+                data: dec.data()?,
+            },
+            TCLUNK => Tmessage::Clunk { fid: dec.u32()? },
+            TREMOVE => Tmessage::Remove { fid: dec.u32()? },
+            TSTAT => Tmessage::Stat { fid: dec.u32()? },
+            TWSTAT => Tmessage::Wstat {
+                fid: dec.u32()?,
+                stat: dec.stat_n()?,
+            },
+            TSTATFS => Tmessage::Statfs { fid: dec.u32()? },
+            TLOPEN => Tmessage::Lopen {
+                fid: dec.u32()?,
+                flags: dec.u32()?,
+            },
+            TLCREATE => Tmessage::Lcreate {
+                fid: dec.u32()?,
+                name: dec.string()?,
+                flags: dec.u32()?,
+                mode: dec.u32()?,
+                gid: dec.u32()?,
+            },
+            TSYMLINK => Tmessage::Symlink {
+                fid: dec.u32()?,
+                name: dec.string()?,
+                symtgt: dec.string()?,
+                gid: dec.u32()?,
+            },
+            TMKNOD => Tmessage::Mknod {
+                dfid: dec.u32()?,
+                name: dec.string()?,
+                mode: dec.u32()?,
+                major: dec.u32()?,
+                minor: dec.u32()?,
+                gid: dec.u32()?,
+            },
+            TREADLINK => Tmessage::Readlink { fid: dec.u32()? },
+            TGETATTR => Tmessage::Getattr {
+                fid: dec.u32()?,
+                request_mask: dec.u64()?,
+            },
+            TSETATTR => Tmessage::Setattr {
+                fid: dec.u32()?,
+                attr: Setattr {
+                    valid: dec.u32()?,
+                    mode: dec.u32()?,
+                    uid: dec.u32()?,
+                    gid: dec.u32()?,
+                    size: dec.u64()?,
+                    atime_sec: dec.u64()?,
+                    atime_nsec: dec.u64()?,
+                    mtime_sec: dec.u64()?,
+                    mtime_nsec: dec.u64()?,
+                },
+            },
+            TXATTRWALK => Tmessage::Xattrwalk {
+                fid: dec.u32()?,
+                newfid: dec.u32()?,
+                name: dec.string()?,
+            },
+            TXATTRCREATE => Tmessage::Xattrcreate {
+                fid: dec.u32()?,
+                name: dec.string()?,
+                attr_size: dec.u64()?,
+                flags: dec.u32()?,
+            },
+            TREADDIR => Tmessage::Readdir {
+                fid: dec.u32()?,
+                offset: dec.u64()?,
+                count: dec.u32()?,
+            },
+            TFSYNC => Tmessage::Fsync {
+                fid: dec.u32()?,
+                datasync: dec.u32()?,
+            },
+            TLOCK => {
+                let fid = dec.u32()?;
+                let typ = dec.u8()?;
+                let flags = dec.u32()?;
+                let lock = dec.flock_range(typ, flags)?;
+                Tmessage::Lock { fid, lock }
+            }
+            TGETLOCK => {
+                let fid = dec.u32()?;
+                let typ = dec.u8()?;
+                let lock = dec.flock_range(typ, 0)?;
+                Tmessage::Getlock { fid, lock }
+            }
+            TLINK => Tmessage::Link {
+                dfid: dec.u32()?,
+                fid: dec.u32()?,
+                name: dec.string()?,
+            },
+            TMKDIR => Tmessage::Mkdir {
+                dfid: dec.u32()?,
+                name: dec.string()?,
+                mode: dec.u32()?,
+                gid: dec.u32()?,
+            },
+            TRENAMEAT => Tmessage::Renameat {
+                olddirfid: dec.u32()?,
+                oldname: dec.string()?,
+                newdirfid: dec.u32()?,
+                newname: dec.string()?,
+            },
+            TUNLINKAT => Tmessage::Unlinkat {
+                dirfd: dec.u32()?,
+                name: dec.string()?,
+                flags: dec.u32()?,
+            },
+            _ => return Err(anyhow!("Unknown T-message type: {}", typ)),
+        };
+        dec.finish()?;
+        Ok((tag, msg))
+    }
+}
+
+impl Rmessage {
+    /// Returns the 9P message type number of this reply.
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Rmessage::Version { .. } => RVERSION,
+            Rmessage::Auth { .. } => RAUTH,
+            Rmessage::Attach { .. } => RATTACH,
+            Rmessage::Error { .. } => RERROR,
+            Rmessage::Flush => RFLUSH,
+            Rmessage::Walk { .. } => RWALK,
+            Rmessage::Open { .. } => ROPEN,
+            Rmessage::Create { .. } => RCREATE,
+            Rmessage::Read { .. } => RREAD,
+            Rmessage::Write { .. } => RWRITE,
+            Rmessage::Clunk => RCLUNK,
+            Rmessage::Remove => RREMOVE,
+            Rmessage::Stat { .. } => RSTAT,
+            Rmessage::Wstat => RWSTAT,
+            Rmessage::Lerror { .. } => RLERROR,
+            Rmessage::Statfs { .. } => RSTATFS,
+            Rmessage::Lopen { .. } => RLOPEN,
+            Rmessage::Lcreate { .. } => RLCREATE,
+            Rmessage::Symlink { .. } => RSYMLINK,
+            Rmessage::Mknod { .. } => RMKNOD,
+            Rmessage::Readlink { .. } => RREADLINK,
+            Rmessage::Getattr { .. } => RGETATTR,
+            Rmessage::Setattr => RSETATTR,
+            Rmessage::Xattrwalk { .. } => RXATTRWALK,
+            Rmessage::Xattrcreate => RXATTRCREATE,
+            Rmessage::Readdir { .. } => RREADDIR,
+            Rmessage::Fsync => RFSYNC,
+            Rmessage::Lock { .. } => RLOCK,
+            Rmessage::Getlock { .. } => RGETLOCK,
+            Rmessage::Link => RLINK,
+            Rmessage::Mkdir { .. } => RMKDIR,
+            Rmessage::Renameat => RRENAMEAT,
+            Rmessage::Unlinkat => RUNLINKAT,
+        }
+    }
+
+    /// Encodes the reply into a complete 9P message.
+    ///
+    /// # Arguments
+    /// * `tag` - The tag of the request being answered.
+    /// * `dialect` - The negotiated protocol dialect.
+    ///
+    /// # Returns
+    /// The message bytes, including the size header, or an error if a field
+    /// is too long for its length prefix.
+    pub fn encode(&self, tag: u16, dialect: Dialect) -> Result<Vec<u8>> {
+        let mut enc = Encoder::new(self.message_type(), tag, dialect);
+        match self {
+            Rmessage::Version { msize, version } => {
+                enc.put_u32(*msize);
+                enc.put_str(version);
+            }
+            Rmessage::Auth { aqid } => enc.put_qid(aqid),
+            Rmessage::Attach { qid } => enc.put_qid(qid),
+            Rmessage::Error { ename, errno } => {
+                enc.put_str(ename);
+                if enc.dialect == Dialect::Unix {
+                    enc.put_u32(*errno);
+                }
+            }
+            Rmessage::Walk { wqids } => {
+                enc.put_u16(wqids.len() as u16);
+                for qid in wqids {
+                    enc.put_qid(qid);
+                }
+            }
+            Rmessage::Open { qid, iounit }
+            | Rmessage::Create { qid, iounit }
+            | Rmessage::Lopen { qid, iounit }
+            | Rmessage::Lcreate { qid, iounit } => {
+                enc.put_qid(qid);
+                enc.put_u32(*iounit);
+            }
+            Rmessage::Read { data } => enc.put_data(data),
+            Rmessage::Write { count } => enc.put_u32(*count),
+            Rmessage::Stat { stat } => enc.put_stat_n(stat),
+            Rmessage::Lerror { ecode } => enc.put_u32(*ecode),
+            Rmessage::Statfs { statfs } => {
+                enc.put_u32(statfs.typ);
+                enc.put_u32(statfs.bsize);
+                enc.put_u64(statfs.blocks);
+                enc.put_u64(statfs.bfree);
+                enc.put_u64(statfs.bavail);
+                enc.put_u64(statfs.files);
+                enc.put_u64(statfs.ffree);
+                enc.put_u64(statfs.fsid);
+                enc.put_u32(statfs.namelen);
+            }
+            Rmessage::Symlink { qid } | Rmessage::Mknod { qid } | Rmessage::Mkdir { qid } => {
+                enc.put_qid(qid)
+            }
+            Rmessage::Readlink { target } => enc.put_str(target),
+            Rmessage::Getattr { attr } => {
+                enc.put_u64(attr.valid);
+                enc.put_qid(&attr.qid);
+                enc.put_u32(attr.mode);
+                enc.put_u32(attr.uid);
+                enc.put_u32(attr.gid);
+                for value in [
+                    attr.nlink,
+                    attr.rdev,
+                    attr.size,
+                    attr.blksize,
+                    attr.blocks,
+                    attr.atime_sec,
+                    attr.atime_nsec,
+                    attr.mtime_sec,
+                    attr.mtime_nsec,
+                    attr.ctime_sec,
+                    attr.ctime_nsec,
+                    attr.btime_sec,
+                    attr.btime_nsec,
+                    attr.gen,
+                    attr.data_version,
+                ] {
+                    enc.put_u64(value);
+                }
+            }
+            Rmessage::Xattrwalk { size } => enc.put_u64(*size),
+            Rmessage::Readdir { data } => enc.put_data(data),
+            Rmessage::Lock { status } => enc.put_u8(*status),
+            Rmessage::Getlock { lock } => {
+                enc.put_u8(lock.typ);
+                enc.put_flock_range(lock);
+            }
+            Rmessage::Flush
+            | Rmessage::Clunk
+            | Rmessage::Remove
+            | Rmessage::Wstat
+            | Rmessage::Setattr
+            | Rmessage::Xattrcreate
+            | Rmessage::Fsync
+            | Rmessage::Link
+            | Rmessage::Renameat
+            | Rmessage::Unlinkat => {}
+        }
+        enc.finish()
+    }
+
+    /// Decodes a complete 9P reply.
+    ///
+    /// # Arguments
+    /// * `buf` - The message bytes, including the size header.
+    /// * `dialect` - The negotiated protocol dialect.
+    ///
+    /// # Returns
+    /// A tuple containing the tag and the decoded reply.
+    pub fn decode(buf: &[u8], dialect: Dialect) -> Result<(u16, Self)> {
+        let (typ, tag, mut dec) = Decoder::message(buf, dialect)?;
+        let msg = match typ {
+            RVERSION => Rmessage::Version {
+                msize: dec.u32()?,
+                version: dec.string()?,
+            },
+            RAUTH => Rmessage::Auth { aqid: dec.qid()? },
+            RATTACH => Rmessage::Attach { qid: dec.qid()? },
+            RERROR => Rmessage::Error {
+                ename: dec.string()?,
+                errno: if dec.dialect == Dialect::Unix {
+                    dec.u32()?
+                } else {
+                    0
+                },
+            },
+            RFLUSH => Rmessage::Flush,
+            RWALK => {
+                let nwqid = dec.u16()? as usize;
+                let mut wqids = Vec::with_capacity(nwqid);
+                for _ in 0..nwqid {
+                    wqids.push(dec.qid()?);
+                }
+                Rmessage::Walk { wqids }
+            }
+            ROPEN => Rmessage::Open {
+                qid: dec.qid()?,
+                iounit: dec.u32()?,
+            },
+            RCREATE => Rmessage::Create {
+                qid: dec.qid()?,
+                iounit: dec.u32()?,
+            },
+            RREAD => Rmessage::Read { data: dec.data()? },
+            RWRITE => Rmessage::Write { count: dec.u32()? },
+            RCLUNK => Rmessage::Clunk,
+            RREMOVE => Rmessage::Remove,
+            RSTAT => Rmessage::Stat {
+                stat: dec.stat_n()?,
+            },
+            RWSTAT => Rmessage::Wstat,
+            RLERROR => Rmessage::Lerror { ecode: dec.u32()? },
+            RSTATFS => Rmessage::Statfs {
+                statfs: Statfs {
+                    typ: dec.u32()?,
+                    bsize: dec.u32()?,
+                    blocks: dec.u64()?,
+                    bfree: dec.u64()?,
+                    bavail: dec.u64()?,
+                    files: dec.u64()?,
+                    ffree: dec.u64()?,
+                    fsid: dec.u64()?,
+                    namelen: dec.u32()?,
+                },
+            },
+            RLOPEN => Rmessage::Lopen {
+                qid: dec.qid()?,
+                iounit: dec.u32()?,
+            },
+            RLCREATE => Rmessage::Lcreate {
+                qid: dec.qid()?,
+                iounit: dec.u32()?,
+            },
+            RSYMLINK => Rmessage::Symlink { qid: dec.qid()? },
+            RMKNOD => Rmessage::Mknod { qid: dec.qid()? },
+            RREADLINK => Rmessage::Readlink {
+                target: dec.string()?,
+            },
+            RGETATTR => Rmessage::Getattr {
+                attr: Getattr {
+                    valid: dec.u64()?,
+                    qid: dec.qid()?,
+                    mode: dec.u32()?,
+                    uid: dec.u32()?,
+                    gid: dec.u32()?,
+                    nlink: dec.u64()?,
+                    rdev: dec.u64()?,
+                    size: dec.u64()?,
+                    blksize: dec.u64()?,
+                    blocks: dec.u64()?,
+                    atime_sec: dec.u64()?,
+                    atime_nsec: dec.u64()?,
+                    mtime_sec: dec.u64()?,
+                    mtime_nsec: dec.u64()?,
+                    ctime_sec: dec.u64()?,
+                    ctime_nsec: dec.u64()?,
+                    btime_sec: dec.u64()?,
+                    btime_nsec: dec.u64()?,
+                    gen: dec.u64()?,
+                    data_version: dec.u64()?,
+                },
+            },
+            RSETATTR => Rmessage::Setattr,
+            RXATTRWALK => Rmessage::Xattrwalk { size: dec.u64()? },
+            RXATTRCREATE => Rmessage::Xattrcreate,
+            RREADDIR => Rmessage::Readdir { data: dec.data()? },
+            RFSYNC => Rmessage::Fsync,
+            RLOCK => Rmessage::Lock { status: dec.u8()? },
+            RGETLOCK => {
+                let typ = dec.u8()?;
+                Rmessage::Getlock {
+                    lock: dec.flock_range(typ, 0)?,
+                }
+            }
+            RLINK => Rmessage::Link,
+            RMKDIR => Rmessage::Mkdir { qid: dec.qid()? },
+            RRENAMEAT => Rmessage::Renameat,
+            RUNLINKAT => Rmessage::Unlinkat,
+            _ => return Err(anyhow!("Unknown R-message type: {}", typ)),
+        };
+        dec.finish()?;
+        Ok((tag, msg))
+    }
+}
+
+/// Encodes a stat structure, including its leading 2-byte size.
+///
+/// This is the format used for directory entries returned by a read on a
+/// directory fid.
+///
+/// # Arguments
+/// * `stat` - The stat to encode.
+/// * `dialect` - The negotiated protocol dialect.
+///
+/// # Returns
+/// The stat bytes, or an error if a field is too long for its length prefix.
+pub fn encode_stat(stat: &Stat, dialect: Dialect) -> Result<Vec<u8>> {
+    let mut enc = Encoder {
+        buf: Vec::new(),
+        dialect,
+        error: None,
+    };
+    enc.put_stat(stat);
+    enc.into_bytes()
+}
+
+/// Decodes a single stat structure, including its leading 2-byte size.
+///
+/// # Arguments
+/// * `buf` - The bytes to decode.
+/// * `dialect` - The negotiated protocol dialect.
+///
+/// # Returns
+/// A tuple containing the stat and the number of bytes consumed.
+pub fn decode_stat(buf: &[u8], dialect: Dialect) -> Result<(Stat, usize)> {
+    let mut dec = Decoder {
+        buf,
+        pos: 0,
+        dialect,
+    };
+    let stat = dec.stat()?;
+    Ok((stat, dec.pos))
+}
+
+/// Encodes a 9P2000.L directory entry as returned by Treaddir.
+///
+/// # Arguments
+/// * `qid` - Qid of the entry.
+/// * `offset` - Offset to pass to Treaddir to continue after this entry.
+/// * `typ` - Directory entry type, as in `d_type` (`DT_DIR`, `DT_REG`, ...).
+/// * `name` - Name of the entry.
+///
+/// # Returns
+/// The entry bytes, or an error if the name is too long for 9P.
+pub fn encode_dirent(qid: &Qid, offset: u64, typ: u8, name: &str) -> Result<Vec<u8>> {
+    let mut enc = Encoder {
+        buf: Vec::new(),
+        dialect: Dialect::Linux,
+        error: None,
+    };
+    enc.put_qid(qid);
+    enc.put_u64(offset);
+    enc.put_u8(typ);
+    enc.put_str(name);
+    enc.into_bytes()
+}
+
+/// Builds a 9P message by appending fields in wire order.
+///
+/// A field too long for its length prefix is recorded in `error` rather
+/// than failing every `put_*` call, and reported when the bytes are taken.
+struct Encoder {
+    buf: Vec<u8>,
+    dialect: Dialect,
+    error: Option<anyhow::Error>,
+}
+
+impl Encoder {
+    fn new(typ: u8, tag: u16, dialect: Dialect) -> Self {
+        let mut enc = Encoder {
+            buf: Vec::with_capacity(HEADER_SIZE),
+            dialect,
+            error: None,
+        };
+        // The size is patched in by `finish`
+        enc.put_u32(0);
+        enc.put_u8(typ);
+        enc.put_u16(tag);
+        enc
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        let size = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&size.to_le_bytes());
+        self.into_bytes()
+    }
+
+    fn into_bytes(self) -> Result<Vec<u8>> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.buf),
+        }
+    }
+
+    // Converts the length of a field to its 2-byte prefix, recording an
+    // error if it does not fit
+    fn len_u16(&mut self, len: usize, what: &str) -> u16 {
+        u16::try_from(len).unwrap_or_else(|_| {
+            self.error
+                .get_or_insert_with(|| anyhow!("{} of {} bytes is too long for 9P", what, len));
+            0
+        })
+    }
+
+    fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn put_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_str(&mut self, s: &str) {
+        let len = self.len_u16(s.len(), "String");
+        self.put_u16(len);
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn put_data(&mut self, data: &[u8]) {
+        self.put_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    fn put_qid(&mut self, qid: &Qid) {
+        self.put_u8(qid.file_type);
+        self.put_u32(qid.version);
+        self.put_u64(qid.path);
+    }
+
+    fn put_stat(&mut self, stat: &Stat) {
+        let start = self.buf.len();
+        // The stat size is patched in once the body length is known
+        self.put_u16(0);
+        self.put_u16(stat.typ);
+        self.put_u32(stat.dev);
+        self.put_qid(&stat.qid);
+        self.put_u32(stat.mode);
+        self.put_u32(stat.atime);
+        self.put_u32(stat.mtime);
+        self.put_u64(stat.length);
+        self.put_str(&stat.name);
+        self.put_str(&stat.uid);
+        self.put_str(&stat.gid);
+        self.put_str(&stat.muid);
+        if self.dialect == Dialect::Unix {
+            self.put_str(&stat.extension);
+            self.put_u32(stat.n_uid);
+            self.put_u32(stat.n_gid);
+            self.put_u32(stat.n_muid);
+        }
+        let size = self.len_u16(self.buf.len() - start - 2, "Stat");
+        self.buf[start..start + 2].copy_from_slice(&size.to_le_bytes());
+    }
+
+    // Tauth and Tattach only carry a numeric user id in 9P2000.u and .L
+    fn put_n_uname(&mut self, n_uname: u32) {
+        if self.dialect != Dialect::Plan9 {
+            self.put_u32(n_uname);
+        }
+    }
+
+    // Fields shared by Tlock, Tgetlock and Rgetlock after the type and flags
+    fn put_flock_range(&mut self, lock: &Flock) {
+        self.put_u64(lock.start);
+        self.put_u64(lock.length);
+        self.put_u32(lock.proc_id);
+        self.put_str(&lock.client_id);
+    }
+
+    // Rstat and Twstat wrap the stat in an extra 2-byte count
+    fn put_stat_n(&mut self, stat: &Stat) {
+        let start = self.buf.len();
+        self.put_u16(0);
+        self.put_stat(stat);
+        let size = self.len_u16(self.buf.len() - start - 2, "Stat");
+        self.buf[start..start + 2].copy_from_slice(&size.to_le_bytes());
+    }
+}
+
+/// Reads fields in wire order from a 9P message.
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    dialect: Dialect,
+}
+
+impl<'a> Decoder<'a> {
+    fn message(buf: &'a [u8], dialect: Dialect) -> Result<(u8, u16, Self)> {
+        let mut dec = Decoder {
+            buf,
+            pos: 0,
+            dialect,
+        };
+        let size = dec.u32()? as usize;
+        if size != buf.len() {
+            return Err(anyhow!(
+                "Message size mismatch: header says {}, got {}",
+                size,
+                buf.len()
+            ));
+        }
+        let typ = dec.u8()?;
+        let tag = dec.u16()?;
+        Ok((typ, tag, dec))
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.pos != self.buf.len() {
+            return Err(anyhow!(
+                "Trailing bytes in message: {}",
+                self.buf.len() - self.pos
+            ));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(anyhow!("Message too short"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| anyhow!("Invalid UTF-8 in string"))
+    }
+
+    fn data(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn qid(&mut self) -> Result<Qid> {
+        Ok(Qid {
+            file_type: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+
+    fn stat(&mut self) -> Result<Stat> {
+        let size = self.u16()?;
+        let start = self.pos;
+        let mut stat = Stat {
+            size,
+            typ: self.u16()?,
+            dev: self.u32()?,
+            qid: self.qid()?,
+            mode: self.u32()?,
+            atime: self.u32()?,
+            mtime: self.u32()?,
+            length: self.u64()?,
+            name: self.string()?,
+            uid: self.string()?,
+            gid: self.string()?,
+            muid: self.string()?,
+            extension: String::new(),
+            n_uid: NONUNAME,
+            n_gid: NONUNAME,
+            n_muid: NONUNAME,
+        };
+        if self.dialect == Dialect::Unix {
+            stat.extension = self.string()?;
+            stat.n_uid = self.u32()?;
+            stat.n_gid = self.u32()?;
+            stat.n_muid = self.u32()?;
+        }
+        if self.pos - start != size as usize {
+            return Err(anyhow!("Stat size mismatch"));
+        }
+        Ok(stat)
+    }
+
+    fn n_uname(&mut self) -> Result<u32> {
+        if self.dialect != Dialect::Plan9 {
+            self.u32()
+        } else {
+            Ok(NONUNAME)
+        }
+    }
+
+    fn flock_range(&mut self, typ: u8, flags: u32) -> Result<Flock> {
+        Ok(Flock {
+            typ,
+            flags,
+            start: self.u64()?,
+            length: self.u64()?,
+            proc_id: self.u32()?,
+            client_id: self.string()?,
+        })
+    }
+
+    fn stat_n(&mut self) -> Result<Stat> {
+        let n = self.u16()? as usize;
+        let start = self.pos;
+        let stat = self.stat()?;
+        if self.pos - start != n {
+            return Err(anyhow!("Stat size mismatch"));
+        }
+        Ok(stat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_stat() -> Stat {
+        Stat {
+            size: 51,
+            typ: 0,
+            dev: 0,
+            qid: Qid {
+                version: 3,
+                path: 0x42,
+                file_type: 0,
+            },
+            mode: 0o644,
+            atime: 1,
+            mtime: 2,
+            length: 5,
+            name: "x".to_string(),
+            uid: "u".to_string(),
+            gid: "g".to_string(),
+            muid: "m".to_string(),
+            extension: String::new(),
+            n_uid: NONUNAME,
+            n_gid: NONUNAME,
+            n_muid: NONUNAME,
+        }
+    }
+
+    #[rustfmt::skip]
+    fn sample_stat_bytes() -> Vec<u8> {
+        vec![
+            51, 0,                          // size
+            0, 0,                           // type
+            0, 0, 0, 0,                     // dev
+            0x00, 3, 0, 0, 0,               // qid.type, qid.vers
+            0x42, 0, 0, 0, 0, 0, 0, 0,      // qid.path
+            0xa4, 0x01, 0, 0,               // mode
+            1, 0, 0, 0,                     // atime
+            2, 0, 0, 0,                     // mtime
+            5, 0, 0, 0, 0, 0, 0, 0,         // length
+            1, 0, b'x',                     // name
+            1, 0, b'u',                     // uid
+            1, 0, b'g',                     // gid
+            1, 0, b'm',                     // muid
+        ]
+    }
+
+    fn assert_tmessage(tag: u16, msg: Tmessage, bytes: &[u8]) {
+        assert_tmessage_as(Dialect::Plan9, tag, msg, bytes);
+    }
+
+    fn assert_rmessage(tag: u16, msg: Rmessage, bytes: &[u8]) {
+        assert_rmessage_as(Dialect::Plan9, tag, msg, bytes);
+    }
+
+    fn assert_tmessage_as(dialect: Dialect, tag: u16, msg: Tmessage, bytes: &[u8]) {
+        assert_eq!(msg.encode(tag, dialect).unwrap(), bytes);
+        assert_eq!(Tmessage::decode(bytes, dialect).unwrap(), (tag, msg));
+    }
+
+    fn assert_rmessage_as(dialect: Dialect, tag: u16, msg: Rmessage, bytes: &[u8]) {
+        assert_eq!(msg.encode(tag, dialect).unwrap(), bytes);
+        assert_eq!(Rmessage::decode(bytes, dialect).unwrap(), (tag, msg));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_version() {
+        let bytes = [
+            19, 0, 0, 0, TVERSION, 0xff, 0xff,
+            0x00, 0x20, 0, 0,
+            6, 0, b'9', b'P', b'2', b'0', b'0', b'0',
+        ];
+        assert_tmessage(NOTAG, Tmessage::Version { msize: 8192, version: "9P2000".into() }, &bytes);
+
+        let mut reply = bytes;
+        reply[4] = RVERSION;
+        assert_rmessage(NOTAG, Rmessage::Version { msize: 8192, version: "9P2000".into() }, &reply);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_auth() {
+        let bytes = [
+            21, 0, 0, 0, TAUTH, 1, 0,
+            5, 0, 0, 0,
+            6, 0, b'g', b'l', b'e', b'n', b'd', b'a',
+            0, 0,
+        ];
+        assert_tmessage(1, Tmessage::Auth { afid: 5, uname: "glenda".into(), aname: "".into(), n_uname: NONUNAME }, &bytes);
+
+        let bytes = [
+            20, 0, 0, 0, RAUTH, 1, 0,
+            0x08, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let aqid = Qid { version: 0, path: 9, file_type: 0x08 };
+        assert_rmessage(1, Rmessage::Auth { aqid }, &bytes);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_attach() {
+        let bytes = [
+            25, 0, 0, 0, TATTACH, 1, 0,
+            0, 0, 0, 0,
+            0xff, 0xff, 0xff, 0xff,
+            6, 0, b'g', b'l', b'e', b'n', b'd', b'a',
+            0, 0,
+        ];
+        assert_tmessage(
+            1,
+            Tmessage::Attach { fid: 0, afid: NOFID, uname: "glenda".into(), aname: "".into(), n_uname: NONUNAME },
+            &bytes,
+        );
+
+        let bytes = [
+            20, 0, 0, 0, RATTACH, 1, 0,
+            0x80, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let qid = Qid { version: 0, path: 1, file_type: 0x80 };
+        assert_rmessage(1, Rmessage::Attach { qid }, &bytes);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_error() {
+        let bytes = [
+            28, 0, 0, 0, RERROR, 2, 0,
+            19, 0,
+            b'f', b'i', b'l', b'e', b' ', b'd', b'o', b'e', b's', b' ',
+            b'n', b'o', b't', b' ', b'e', b'x', b'i', b's', b't',
+        ];
+        assert_rmessage(2, Rmessage::Error { ename: "file does not exist".into(), errno: 0 }, &bytes);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_flush() {
+        assert_tmessage(3, Tmessage::Flush { oldtag: 2 }, &[9, 0, 0, 0, TFLUSH, 3, 0, 2, 0]);
+        assert_rmessage(3, Rmessage::Flush, &[7, 0, 0, 0, RFLUSH, 3, 0]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_walk() {
+        let bytes = [
+            30, 0, 0, 0, TWALK, 4, 0,
+            0, 0, 0, 0,
+            1, 0, 0, 0,
+            2, 0,
+            3, 0, b'u', b's', b'r',
+            6, 0, b'g', b'l', b'e', b'n', b'd', b'a',
+        ];
+        assert_tmessage(
+            4,
+            Tmessage::Walk { fid: 0, newfid: 1, wnames: vec!["usr".into(), "glenda".into()] },
+            &bytes,
+        );
+
+        let bytes = [
+            35, 0, 0, 0, RWALK, 4, 0,
+            2, 0,
+            0x80, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+            0x80, 1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let wqids = vec![
+            Qid { version: 0, path: 2, file_type: 0x80 },
+            Qid { version: 1, path: 3, file_type: 0x80 },
+        ];
+        assert_rmessage(4, Rmessage::Walk { wqids }, &bytes);
+
+        // A zero-element walk clones the fid
+        assert_tmessage(
+            5,
+            Tmessage::Walk { fid: 0, newfid: 1, wnames: vec![] },
+            &[17, 0, 0, 0, TWALK, 5, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+        );
+        assert_rmessage(5, Rmessage::Walk { wqids: vec![] }, &[9, 0, 0, 0, RWALK, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_open_and_create() {
+        assert_tmessage(6, Tmessage::Open { fid: 1, mode: 0x10 }, &[12, 0, 0, 0, TOPEN, 6, 0, 1, 0, 0, 0, 0x10]);
+
+        let bytes = [
+            24, 0, 0, 0, ROPEN, 6, 0,
+            0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0,
+            0, 0x20, 0, 0,
+        ];
+        let qid = Qid { version: 0, path: 7, file_type: 0 };
+        assert_rmessage(6, Rmessage::Open { qid: qid.clone(), iounit: 8192 }, &bytes);
+
+        let bytes = [
+            21, 0, 0, 0, TCREATE, 7, 0,
+            1, 0, 0, 0,
+            3, 0, b'n', b'e', b'w',
+            0xa4, 0x01, 0, 0,
+            2,
+        ];
+        assert_tmessage(7, Tmessage::Create { fid: 1, name: "new".into(), perm: 0o644, mode: 2, extension: "".into() }, &bytes);
+
+        let mut reply = [
+            24, 0, 0, 0, RCREATE, 7, 0,
+            0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0,
+            0, 0x20, 0, 0,
+        ];
+        assert_rmessage(7, Rmessage::Create { qid, iounit: 8192 }, &reply);
+        reply[4] = ROPEN;
+        assert!(matches!(Rmessage::decode(&reply, Dialect::Plan9), Ok((7, Rmessage::Open { .. }))));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_read_and_write() {
+        let bytes = [
+            23, 0, 0, 0, TREAD, 8, 0,
+            1, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0x20, 0, 0,
+        ];
+        assert_tmessage(8, Tmessage::Read { fid: 1, offset: 0, count: 8192 }, &bytes);
+
+        let bytes = [16, 0, 0, 0, RREAD, 8, 0, 5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o'];
+        assert_rmessage(8, Rmessage::Read { data: b"hello".to_vec() }, &bytes);
+
+        let bytes = [
+            25, 0, 0, 0, TWRITE, 9, 0,
+            1, 0, 0, 0,
+            0x10, 0, 0, 0, 0, 0, 0, 0,
+            2, 0, 0, 0, b'h', b'i',
+        ];
+        assert_tmessage(9, Tmessage::Write { fid: 1, offset: 16, data: b"hi".to_vec() }, &bytes);
+        assert_rmessage(9, Rmessage::Write { count: 2 }, &[11, 0, 0, 0, RWRITE, 9, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_clunk_remove_stat() {
+        assert_tmessage(10, Tmessage::Clunk { fid: 1 }, &[11, 0, 0, 0, TCLUNK, 10, 0, 1, 0, 0, 0]);
+        assert_rmessage(10, Rmessage::Clunk, &[7, 0, 0, 0, RCLUNK, 10, 0]);
+        assert_tmessage(11, Tmessage::Remove { fid: 1 }, &[11, 0, 0, 0, TREMOVE, 11, 0, 1, 0, 0, 0]);
+        assert_rmessage(11, Rmessage::Remove, &[7, 0, 0, 0, RREMOVE, 11, 0]);
+        assert_tmessage(12, Tmessage::Stat { fid: 1 }, &[11, 0, 0, 0, TSTAT, 12, 0, 1, 0, 0, 0]);
+        assert_rmessage(13, Rmessage::Wstat, &[7, 0, 0, 0, RWSTAT, 13, 0]);
+    }
+
+    #[test]
+    fn test_stat_messages() {
+        let stat_bytes = sample_stat_bytes();
+        assert_eq!(encode_stat(&sample_stat(), Dialect::Plan9).unwrap(), stat_bytes);
+        assert_eq!(
+            decode_stat(&stat_bytes, Dialect::Plan9).unwrap(),
+            (sample_stat(), stat_bytes.len())
+        );
+
+        // Rstat: size[4] type[1] tag[2] n[2] stat[n]
+        let mut bytes = vec![62, 0, 0, 0, RSTAT, 12, 0, 53, 0];
+        bytes.extend_from_slice(&stat_bytes);
+        assert_rmessage(12, Rmessage::Stat { stat: sample_stat() }, &bytes);
+
+        // Twstat: size[4] type[1] tag[2] fid[4] n[2] stat[n]
+        let mut bytes = vec![66, 0, 0, 0, TWSTAT, 13, 0, 1, 0, 0, 0, 53, 0];
+        bytes.extend_from_slice(&stat_bytes);
+        assert_tmessage(
+            13,
+            Tmessage::Wstat {
+                fid: 1,
+                stat: sample_stat(),
+            },
+            &bytes,
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_unix_extensions() {
+        let bytes = [
+            29, 0, 0, 0, TATTACH, 1, 0,
+            0, 0, 0, 0,
+            0xff, 0xff, 0xff, 0xff,
+            6, 0, b'g', b'l', b'e', b'n', b'd', b'a',
+            0, 0,
+            0xe8, 0x03, 0, 0,
+        ];
+        assert_tmessage_as(
+            Dialect::Unix,
+            1,
+            Tmessage::Attach { fid: 0, afid: NOFID, uname: "glenda".into(), aname: "".into(), n_uname: 1000 },
+            &bytes,
+        );
+
+        let bytes = [
+            25, 0, 0, 0, TCREATE, 7, 0,
+            1, 0, 0, 0,
+            1, 0, b'l',
+            0xff, 0x01, 0, 0x02,
+            0,
+            4, 0, b'/', b't', b'm', b'p',
+        ];
+        let create = Tmessage::Create {
+            fid: 1,
+            name: "l".into(),
+            perm: DMSYMLINK | 0o777,
+            mode: 0,
+            extension: "/tmp".into(),
+        };
+        assert_tmessage_as(Dialect::Unix, 7, create, &bytes);
+
+        let bytes = [
+            18, 0, 0, 0, RERROR, 2, 0,
+            5, 0, b'e', b'r', b'r', b'o', b'r',
+            2, 0, 0, 0,
+        ];
+        assert_rmessage_as(Dialect::Unix, 2, Rmessage::Error { ename: "error".into(), errno: 2 }, &bytes);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_unix_stat() {
+        let stat = Stat {
+            size: 68,
+            extension: "ext".into(),
+            n_uid: 1000,
+            n_gid: 100,
+            n_muid: 1000,
+            ..sample_stat()
+        };
+        let mut bytes = sample_stat_bytes();
+        bytes[0] = 68;
+        bytes.extend_from_slice(&[
+            3, 0, b'e', b'x', b't',         // extension
+            0xe8, 0x03, 0, 0,               // n_uid
+            100, 0, 0, 0,                   // n_gid
+            0xe8, 0x03, 0, 0,               // n_muid
+        ]);
+        assert_eq!(encode_stat(&stat, Dialect::Unix).unwrap(), bytes);
+        assert_eq!(decode_stat(&bytes, Dialect::Unix).unwrap(), (stat, bytes.len()));
+
+        // The plain layout is too short for a 9P2000.u stat
+        assert!(decode_stat(&sample_stat_bytes(), Dialect::Unix).is_err());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_linux_messages() {
+        let linux = Dialect::Linux;
+        assert_tmessage_as(
+            linux,
+            1,
+            Tmessage::Lopen { fid: 1, flags: 0o1002 },
+            &[15, 0, 0, 0, TLOPEN, 1, 0, 1, 0, 0, 0, 0x02, 0x02, 0, 0],
+        );
+        assert_rmessage_as(linux, 2, Rmessage::Lerror { ecode: 2 }, &[11, 0, 0, 0, RLERROR, 2, 0, 2, 0, 0, 0]);
+
+        let bytes = [
+            20, 0, 0, 0, TUNLINKAT, 3, 0,
+            1, 0, 0, 0,
+            3, 0, b'd', b'i', b'r',
+            0, 0x02, 0, 0,
+        ];
+        assert_tmessage_as(
+            linux,
+            3,
+            Tmessage::Unlinkat { dirfd: 1, name: "dir".into(), flags: 0x200 },
+            &bytes,
+        );
+
+        // Tattach keeps the numeric user id of 9P2000.u
+        let attach = Tmessage::Attach { fid: 0, afid: NOFID, uname: "".into(), aname: "".into(), n_uname: 0 };
+        assert_eq!(attach.encode(1, linux).unwrap().len(), 23);
+    }
+
+    #[test]
+    fn test_linux_messages_roundtrip() {
+        let linux = Dialect::Linux;
+        let qid = Qid {
+            version: 1,
+            path: 2,
+            file_type: 0x80,
+        };
+        let lock = Flock {
+            typ: LOCK_TYPE_WRLCK,
+            flags: 1,
+            start: 10,
+            length: 20,
+            proc_id: 42,
+            client_id: "client".into(),
+        };
+        let requests = vec![
+            Tmessage::Statfs { fid: 1 },
+            Tmessage::Lcreate {
+                fid: 1,
+                name: "new".into(),
+                flags: 0o102,
+                mode: 0o644,
+                gid: 100,
+            },
+            Tmessage::Symlink {
+                fid: 1,
+                name: "link".into(),
+                symtgt: "target".into(),
+                gid: 100,
+            },
+            Tmessage::Mknod {
+                dfid: 1,
+                name: "null".into(),
+                mode: 0o20666,
+                major: 1,
+                minor: 3,
+                gid: 0,
+            },
+            Tmessage::Readlink { fid: 1 },
+            Tmessage::Getattr {
+                fid: 1,
+                request_mask: GETATTR_BASIC,
+            },
+            Tmessage::Setattr {
+                fid: 1,
+                attr: Setattr {
+                    valid: SETATTR_MODE | SETATTR_SIZE,
+                    mode: 0o600,
+                    size: 5,
+                    ..Setattr::default()
+                },
+            },
+            Tmessage::Xattrwalk {
+                fid: 1,
+                newfid: 2,
+                name: "user.x".into(),
+            },
+            Tmessage::Xattrcreate {
+                fid: 1,
+                name: "user.x".into(),
+                attr_size: 3,
+                flags: 0,
+            },
+            Tmessage::Readdir {
+                fid: 1,
+                offset: 3,
+                count: 4096,
+            },
+            Tmessage::Fsync {
+                fid: 1,
+                datasync: 0,
+            },
+            Tmessage::Lock {
+                fid: 1,
+                lock: lock.clone(),
+            },
+            Tmessage::Getlock {
+                fid: 1,
+                lock: Flock {
+                    flags: 0,
+                    ..lock.clone()
+                },
+            },
+            Tmessage::Link {
+                dfid: 1,
+                fid: 2,
+                name: "hard".into(),
+            },
+            Tmessage::Mkdir {
+                dfid: 1,
+                name: "dir".into(),
+                mode: 0o755,
+                gid: 100,
+            },
+            Tmessage::Renameat {
+                olddirfid: 1,
+                oldname: "a".into(),
+                newdirfid: 2,
+                newname: "b".into(),
+            },
+        ];
+        for (tag, request) in requests.into_iter().enumerate() {
+            let bytes = request.encode(tag as u16, linux).unwrap();
+            assert_eq!(
+                Tmessage::decode(&bytes, linux).unwrap(),
+                (tag as u16, request)
+            );
+        }
+
+        let replies = vec![
+            Rmessage::Statfs {
+                statfs: Statfs {
+                    typ: 0x01021997,
+                    bsize: 4096,
+                    files: 10,
+                    namelen: 255,
+                    ..Statfs::default()
+                },
+            },
+            Rmessage::Lopen {
+                qid: qid.clone(),
+                iounit: 8168,
+            },
+            Rmessage::Lcreate {
+                qid: qid.clone(),
+                iounit: 8168,
+            },
+            Rmessage::Symlink { qid: qid.clone() },
+            Rmessage::Mknod { qid: qid.clone() },
+            Rmessage::Readlink {
+                target: "target".into(),
+            },
+            Rmessage::Getattr {
+                attr: Getattr {
+                    valid: GETATTR_BASIC,
+                    qid: qid.clone(),
+                    mode: 0o40755,
+                    nlink: 2,
+                    mtime_sec: 7,
+                    ..Getattr::default()
+                },
+            },
+            Rmessage::Setattr,
+            Rmessage::Xattrwalk { size: 3 },
+            Rmessage::Xattrcreate,
+            Rmessage::Readdir {
+                data: encode_dirent(&qid, 1, 4, "dir").unwrap(),
+            },
+            Rmessage::Fsync,
+            Rmessage::Lock {
+                status: LOCK_SUCCESS,
+            },
+            Rmessage::Getlock {
+                lock: Flock { flags: 0, ..lock },
+            },
+            Rmessage::Link,
+            Rmessage::Mkdir { qid },
+            Rmessage::Renameat,
+            Rmessage::Unlinkat,
+        ];
+        for (tag, reply) in replies.into_iter().enumerate() {
+            let bytes = reply.encode(tag as u16, linux).unwrap();
+            assert_eq!(
+                Rmessage::decode(&bytes, linux).unwrap(),
+                (tag as u16, reply)
+            );
+        }
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_encode_dirent() {
+        let qid = Qid { version: 0, path: 5, file_type: 0 };
+        assert_eq!(
+            encode_dirent(&qid, 3, 8, "f").unwrap(),
+            vec![
+                0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0,
+                3, 0, 0, 0, 0, 0, 0, 0,
+                8,
+                1, 0, b'f',
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_messages() {
+        let plan9 = Dialect::Plan9;
+        // Size header does not match the buffer length
+        assert!(Tmessage::decode(&[12, 0, 0, 0, TCLUNK, 1, 0, 1, 0, 0, 0], plan9).is_err());
+        // Truncated body
+        assert!(Tmessage::decode(&[9, 0, 0, 0, TCLUNK, 1, 0, 1, 0], plan9).is_err());
+        // Trailing bytes after the body
+        assert!(Tmessage::decode(&[12, 0, 0, 0, TCLUNK, 1, 0, 1, 0, 0, 0, 0], plan9).is_err());
+        // Terror is never valid on the wire
+        assert!(Tmessage::decode(&[7, 0, 0, 0, TERROR, 1, 0], plan9).is_err());
+        // R-messages are not T-messages
+        assert!(Tmessage::decode(&[7, 0, 0, 0, RCLUNK, 1, 0], plan9).is_err());
+        // Invalid UTF-8 in a string
+        assert!(Rmessage::decode(&[10, 0, 0, 0, RERROR, 1, 0, 1, 0, 0xff], plan9).is_err());
+    }
+
+    #[test]
+    fn test_encode_rejects_fields_too_long() {
+        let long = "x".repeat(u16::MAX as usize + 1);
+        let walk = Tmessage::Walk { fid: 0, newfid: 1, wnames: vec![long.clone()] };
+        assert!(walk.encode(1, Dialect::Plan9).is_err());
+        let fitting = Rmessage::Error { ename: long[1..].to_string(), errno: 0 };
+        assert!(fitting.encode(1, Dialect::Plan9).is_ok());
+
+        // Each string fits, but the stat as a whole does not
+        let mut stat = sample_stat();
+        stat.name = long[..40000].to_string();
+        stat.uid = long[..40000].to_string();
+        assert!(encode_stat(&stat, Dialect::Plan9).is_err());
+        assert!(Rmessage::Stat { stat }.encode(1, Dialect::Plan9).is_err());
+        assert!(encode_dirent(&sample_stat().qid, 1, 8, &long).is_err());
+    }
+}
@@ -27,3 +27,19 @@ pub const DEFAULT_UID: u32 = 501;
 
 /// Default group ID for filesystem operations
 pub const DEFAULT_GID: u32 = 20;
+
+/// Message size offered to 9P clients before version negotiation
+pub const DEFAULT_MSIZE: u32 = 8192;
+
+/// Smallest message size a 9P connection may negotiate
+pub const MIN_MSIZE: u32 = 4096;
+
+/// Largest message size a 9P server may be configured to accept
+pub const MAX_MSIZE: u32 = 1024 * 1024;
+
+/// Largest piece of a backing file read or written at once. A flushed
+/// request stops between pieces.
+pub const IO_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest file whose content is held in memory rather than on disk
+pub const MAX_CONTENT_SIZE: u64 = 8 * 1024 * 1024;
@@ -0,0 +1,230 @@
+//! Errors of filesystem operations.
+//!
+//! Every failing 9P or FUSE operation reports an `Error`. Each variant maps
+//! to one Plan 9 error string, sent in 9P2000 `Rerror` replies, and to one
+//! errno, sent to 9P2000.u, 9P2000.L and FUSE clients.
+
+use libc::{
+    EACCES, EBADF, EBUSY, EEXIST, EFBIG, EINTR, EINVAL, EIO, EISDIR, EMSGSIZE, ENODATA, ENOENT,
+    ENOTDIR, ENOTEMPTY, EOPNOTSUPP, EPERM, EROFS,
+};
+use std::fmt;
+use std::io;
+
+/// Result of a filesystem operation.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Failure of a filesystem operation.
+///
+/// # Example
+///
+/// ```rust
+/// use froggr::Error;
+///
+/// let error = Error::NotFound;
+/// assert_eq!(error.to_string(), "file does not exist");
+/// assert_eq!(error.errno(), libc::ENOENT);
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// The file does not exist
+    NotFound,
+    /// The user lacks the permission needed by the operation
+    PermissionDenied,
+    /// Only the owner of the file, or root, may make the change
+    NotOwner,
+    /// The fid is not in use on the connection
+    InvalidFid,
+    /// The new fid of an attach, walk or auth is already in use
+    FidInUse,
+    /// The fid is open, but the operation needs an unopened fid
+    FidOpen,
+    /// The fid is not open in a mode allowing the operation, or is an auth fid
+    BadUse,
+    /// The file is not a directory
+    NotADirectory,
+    /// The file is a directory
+    IsADirectory,
+    /// A file with the name already exists
+    Exists,
+    /// The directory still has entries
+    NotEmpty,
+    /// An argument of the request is out of range or malformed
+    InvalidArgument,
+    /// The file name is empty, `.`, `..` or contains a slash
+    InvalidName,
+    /// The tree is exported read-only
+    ReadOnly,
+    /// The file is opened for exclusive use by another fid
+    InUse,
+    /// The file would grow past the largest size it may have
+    FileTooLarge,
+    /// No tree is exported under the attach name
+    NoSuchTree,
+    /// The server requires authentication before attach
+    AuthRequired,
+    /// The server does not require authentication
+    AuthNotRequired,
+    /// The authentication protocol failed or was not completed
+    AuthFailed,
+    /// The operation is not supported by the server
+    NotSupported,
+    /// The file has no extended attribute with the name
+    NoAttribute,
+    /// The message exceeds the negotiated msize
+    MessageTooLarge,
+    /// The requested msize cannot hold a 9P message
+    MsizeTooSmall,
+    /// The request was flushed before it could complete
+    Interrupted,
+    /// An operation on the backing filesystem failed
+    Io(io::Error),
+}
+
+impl Error {
+    /// Returns the errno reported for the error.
+    ///
+    /// # Returns
+    /// The error number sent in 9P2000.u `Rerror` and 9P2000.L `Rlerror`
+    /// replies and passed to FUSE `reply.error`.
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::NotFound | Error::NoSuchTree => ENOENT,
+            Error::PermissionDenied | Error::AuthRequired | Error::AuthFailed => EACCES,
+            Error::NotOwner => EPERM,
+            Error::InvalidFid | Error::FidOpen | Error::BadUse => EBADF,
+            Error::FidInUse | Error::Exists => EEXIST,
+            Error::NotADirectory => ENOTDIR,
+            Error::IsADirectory => EISDIR,
+            Error::NotEmpty => ENOTEMPTY,
+            Error::InvalidArgument
+            | Error::InvalidName
+            | Error::AuthNotRequired
+            | Error::MsizeTooSmall => EINVAL,
+            Error::ReadOnly => EROFS,
+            Error::InUse => EBUSY,
+            Error::FileTooLarge => EFBIG,
+            Error::NotSupported => EOPNOTSUPP,
+            Error::NoAttribute => ENODATA,
+            Error::MessageTooLarge => EMSGSIZE,
+            Error::Interrupted => EINTR,
+            Error::Io(e) => e.raw_os_error().unwrap_or(EIO),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    // The strings are those of the Plan 9 kernel and lib9p
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::NotFound => "file does not exist",
+            Error::PermissionDenied => "permission denied",
+            Error::NotOwner => "not owner",
+            Error::InvalidFid => "unknown fid",
+            Error::FidInUse => "duplicate fid",
+            Error::FidOpen => "fid already opened",
+            Error::BadUse => "inappropriate use of fid",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "file is a directory",
+            Error::Exists => "file already exists",
+            Error::NotEmpty => "directory not empty",
+            Error::InvalidArgument => "bad arg in system call",
+            Error::InvalidName => "bad character in file name",
+            Error::ReadOnly => "file system read only",
+            Error::InUse => "device or object already in use",
+            Error::FileTooLarge => "file too large",
+            Error::NoSuchTree => "no such file system",
+            Error::AuthRequired => "authentication required",
+            Error::AuthNotRequired => "authentication not required",
+            Error::AuthFailed => "authentication failed",
+            Error::NotSupported => "operation not supported",
+            Error::NoAttribute => "no such attribute",
+            Error::MessageTooLarge => "message too large",
+            Error::MsizeTooSmall => "msize too small",
+            Error::Interrupted => "interrupted",
+            Error::Io(e) => return write!(f, "{}", e),
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<nix::errno::Errno> for Error {
+    fn from(errno: nix::errno::Errno) -> Self {
+        Error::Io(io::Error::from_raw_os_error(errno as i32))
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    // Keeps the typed error or I/O error wrapped by the anyhow error
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<Error>() {
+            Ok(error) => error,
+            Err(error) => match error.downcast::<io::Error>() {
+                Ok(error) => Error::Io(error),
+                Err(error) => Error::Io(io::Error::other(error.to_string())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_map_to_plan9_strings_and_errno() {
+        let cases = [
+            (Error::NotFound, "file does not exist", ENOENT),
+            (Error::PermissionDenied, "permission denied", EACCES),
+            (Error::NotOwner, "not owner", EPERM),
+            (Error::InvalidFid, "unknown fid", EBADF),
+            (Error::FidInUse, "duplicate fid", EEXIST),
+            (Error::NotADirectory, "not a directory", ENOTDIR),
+            (Error::IsADirectory, "file is a directory", EISDIR),
+            (Error::Exists, "file already exists", EEXIST),
+            (Error::NotEmpty, "directory not empty", ENOTEMPTY),
+            (Error::InvalidName, "bad character in file name", EINVAL),
+            (Error::ReadOnly, "file system read only", EROFS),
+            (Error::InUse, "device or object already in use", EBUSY),
+            (Error::FileTooLarge, "file too large", EFBIG),
+            (Error::NoAttribute, "no such attribute", ENODATA),
+        ];
+        for (error, message, errno) in cases {
+            assert_eq!(error.to_string(), message);
+            assert_eq!(error.errno(), errno);
+        }
+    }
+
+    #[test]
+    fn test_io_errors_keep_their_errno() {
+        let error = Error::from(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(error.errno(), libc::ENOSPC);
+        assert!(matches!(
+            Error::from(nix::errno::Errno::EXDEV),
+            Error::Io(_)
+        ));
+        assert_eq!(Error::from(nix::errno::Errno::EXDEV).errno(), libc::EXDEV);
+        assert_eq!(Error::from(io::Error::other("lost")).errno(), EIO);
+
+        // Errors passed through anyhow come back unchanged
+        let error = Error::from(anyhow::Error::new(Error::NotEmpty));
+        assert!(matches!(error, Error::NotEmpty));
+        let error = Error::from(anyhow::Error::new(io::Error::from_raw_os_error(EROFS)));
+        assert_eq!(error.errno(), EROFS);
+    }
+}
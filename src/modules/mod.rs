@@ -2,18 +2,26 @@
 //! 
 //! This module provides the main components of the filesystem:
 //! 
+//! - `auth`: Authentication of 9P clients over auth fids
+//! - `codec`: 9P2000 wire format encoding and decoding
 //! - `constants`: Filesystem constants and default values
+//! - `error`: Errors of filesystem operations
 //! - `mount`: Filesystem mounting and management
 //! - `namespace`: Namespace and binding operations
 //! - `proto`: 9P protocol implementation
+//! - `server`: 9P server over TCP and Unix domain sockets
 //! - `daemon`: Unix daemon process management and control
 //! - `session`: Session management and daemon communication
 
+pub mod auth;
+pub mod codec;
 pub mod constants;
+pub mod error;
 pub mod mount;
 /// Namespace management and binding operations implementation.
 pub mod namespace;
 pub mod proto;
+pub mod server;
 /// Session management implementation.
 /// 
 /// This module provides the `Session` struct which manages filesystem sessions
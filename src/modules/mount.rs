@@ -5,48 +5,36 @@
 //! This module provides the core functionality for mounting and managing
 //! filesystem bindings through the `FilesystemManager`.
 
-use super::constants::BLOCK_SIZE;
-use super::namespace::{BindMode, NamespaceEntry};
-use super::proto::{BoundEntry, NineP};
-use anyhow::{anyhow, Result};
-use fuser::{FileAttr, FileType, MountOption};
-use std::collections::HashMap;
+use super::constants::ROOT_INODE;
+use super::namespace::{find_child, BindMode, Bindings, NamespaceEntry, SymlinkPolicy};
+use super::proto::{Backing, BoundEntry, NineP};
+use super::error::{Error, Result};
+use fuser::{BackgroundSession, FileAttr, FileType, MountOption};
+use std::collections::{HashMap, HashSet};
 use std::collections::VecDeque;
-use std::ffi::CString;
 use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
-use std::time::UNIX_EPOCH;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 use log::{info, debug, warn, error};
 use std::cell::RefCell;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use crate::session::Session;
 use std::thread;
 
-#[cfg(target_os = "macos")]
-extern "C" {
-    /// Unmounts a filesystem on macOS.
-    /// 
-    /// # Arguments
-    /// * `path` - Path to unmount
-    /// * `flags` - Unmount flags
-    pub fn unmount(path: *const i8, flags: i32) -> i32;
-}
-
-#[cfg(target_os = "linux")]
-extern "C" {
-    pub fn umount(path: *const i8) -> i32;
-}
-
 /// Manages filesystem mounting and binding operations.
 #[derive(Clone, Debug)]
 pub struct FilesystemManager {
     /// The underlying 9P filesystem implementation.
     pub fs: NineP,
+    /// FUSE sessions serving the namespace, by mount point
+    fuse_sessions: Arc<Mutex<HashMap<PathBuf, BackgroundSession>>>,
 }
 
 thread_local! {
-    static CURRENT_SESSION: RefCell<Option<Arc<Session>>> = RefCell::new(None);
+    static CURRENT_SESSION: RefCell<Option<Arc<Session>>> = const { RefCell::new(None) };
 }
 
 impl FilesystemManager {
@@ -56,31 +44,51 @@ impl FilesystemManager {
     /// 
     /// * `fs` - The 9P filesystem implementation to manage
     pub fn new(fs: NineP) -> Self {
-        Self { fs }
+        Self {
+            fs,
+            fuse_sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 
-    // Helper function to create FileAttr from metadata
+    // Helper function to create FileAttr from metadata, which is not taken
+    // through symlinks
     fn create_file_attr(&self, inode: u64, metadata: &fs::Metadata) -> FileAttr {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_dir() {
+            FileType::Directory
+        } else if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_fifo() {
+            FileType::NamedPipe
+        } else if file_type.is_socket() {
+            FileType::Socket
+        } else if file_type.is_char_device() {
+            FileType::CharDevice
+        } else if file_type.is_block_device() {
+            FileType::BlockDevice
+        } else {
+            FileType::RegularFile
+        };
+        let ctime = UNIX_EPOCH
+            + Duration::new(metadata.ctime().max(0) as u64, metadata.ctime_nsec() as u32);
+
         FileAttr {
             ino: inode,
             size: metadata.len(),
-            blocks: (metadata.len() + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            blocks: metadata.blocks(),
             atime: metadata.accessed().unwrap_or(UNIX_EPOCH),
             mtime: metadata.modified().unwrap_or(UNIX_EPOCH),
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
-            kind: if metadata.is_dir() {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            },
-            perm: 0o755,
-            nlink: 1,
-            uid: 501,
-            gid: 20,
-            rdev: 0,
+            ctime,
+            crtime: metadata.created().unwrap_or(ctime),
+            kind,
+            perm: (metadata.mode() & 0o7777) as u16,
+            nlink: metadata.nlink() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            // Device numbers keep the Linux 32-bit encoding used by `proto`
+            rdev: metadata.rdev() as u32,
             flags: 0,
-            blksize: 512,
+            blksize: metadata.blksize() as u32,
         }
     }
 
@@ -89,51 +97,88 @@ impl FilesystemManager {
         base_path: &Path,
         current_path: &Path,
         parent_inode: u64,
-        next_inode: &mut u64,
-        bindings: &mut HashMap<u64, (OsString, BoundEntry)>,
+        bindings: &mut Bindings,
+        symlinks: &SymlinkPolicy,
     ) -> Result<()> {
-        println!("Reading directory recursively: {:?}", current_path);
+        debug!("Reading directory recursively: {:?}", current_path);
         let mut queue = VecDeque::new();
         queue.push_back((current_path.to_path_buf(), parent_inode));
-
-        while let Some((path, _)) = queue.pop_front() {
-            for entry in fs::read_dir(&path)? {
+        // Directories outside the source that links were followed to
+        let mut followed = HashSet::new();
+
+        while let Some((path, parent)) = queue.pop_front() {
+            let entries = match fs::read_dir(&path) {
+                Ok(entries) => entries,
+                // A directory a link led to is left empty if it cannot be
+                // read, rather than failing the whole bind
+                Err(e) if followed.contains(&path) => {
+                    warn!("Cannot read {:?}, which a symlink leads to: {}", path, e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
                 let entry = entry?;
-                let metadata = entry.metadata()?;
-                let entry_path = entry.path();
-                let relative_path = entry_path.strip_prefix(base_path)?;
+                let mut metadata = entry.metadata()?;
+                let mut entry_path = entry.path();
 
                 // Skip if this is the root directory itself
-                if relative_path.as_os_str().is_empty() {
+                if entry_path == base_path {
                     continue;
                 }
 
+                if metadata.file_type().is_symlink() {
+                    let target = fs::canonicalize(&entry_path).ok();
+                    let outside = !target.as_ref().is_some_and(|t| t.starts_with(base_path));
+                    match (outside, symlinks, target) {
+                        (true, SymlinkPolicy::Refuse, _) => {
+                            warn!("Refusing symlink {:?} leading outside {:?}", entry_path, base_path);
+                            continue;
+                        }
+                        (true, SymlinkPolicy::Follow, Some(target)) => {
+                            // A directory is followed once, so that links
+                            // cannot form a cycle. A link whose target
+                            // cannot be read is kept as a link.
+                            match fs::metadata(&target) {
+                                Ok(target_metadata) => {
+                                    let is_dir = target_metadata.is_dir();
+                                    if !is_dir || followed.insert(target.clone()) {
+                                        metadata = target_metadata;
+                                        entry_path = target;
+                                    }
+                                }
+                                Err(e) => warn!(
+                                    "Keeping symlink {:?}, as its target cannot be read: {}",
+                                    entry_path, e
+                                ),
+                            }
+                        }
+                        // Dangling links and links into the source are kept
+                        _ => {}
+                    }
+                }
+
                 let inode = {
+                    let mut next_inode = self.fs.namespace_manager.next_inode.lock().unwrap();
                     let current = *next_inode;
                     *next_inode += 1;
                     current
                 };
 
                 let file_name = entry.file_name();
-                println!("Adding binding for: {:?} with inode: {}", file_name, inode);
+                debug!("Adding binding for: {:?} with inode: {}", file_name, inode);
 
+                // File content stays on disk and is read on demand; only
+                // the target of a symlink is kept
                 let file_attr = self.create_file_attr(inode, &metadata);
-                let content = if metadata.is_file() {
-                    Some(fs::read(&entry_path)?)
+                let content = if file_attr.kind == FileType::Symlink {
+                    Some(fs::read_link(&entry_path)?.into_os_string().into_vec())
                 } else {
                     None
                 };
-
-                bindings.insert(
-                    inode,
-                    (
-                        file_name,
-                        BoundEntry {
-                            attr: file_attr,
-                            content,
-                        },
-                    ),
-                );
+                let mut bound_entry = BoundEntry::new(file_attr, parent, content);
+                bound_entry.backing = Some(Backing::new(entry_path.clone(), &metadata));
+                bindings.insert(inode, (file_name, bound_entry));
 
                 if metadata.is_dir() {
                     queue.push_back((entry_path, inode));
@@ -145,120 +190,78 @@ impl FilesystemManager {
     }
 
     /// Binds a directory to a target location.
-    fn bind_directory(&self, dir_path: &str, source_path: &Path, mode: BindMode) -> Result<()> {
+    fn bind_directory(
+        &self,
+        dir_path: &str,
+        source_path: &Path,
+        mode: BindMode,
+        symlinks: &SymlinkPolicy,
+    ) -> Result<()> {
         debug!("Binding directory: {} from source: {:?}", dir_path, source_path);
 
-        let mut bindings = self.fs.namespace_manager.bindings.lock().unwrap();
-        let mut next_inode = self.fs.namespace_manager.next_inode.lock().unwrap();
-
-        // Convert paths to absolute paths
+        // Resolve the paths and read the trees before locking, as they may
+        // lead through a FUSE mount of the namespace itself
         let abs_source = fs::canonicalize(source_path)?;
         let abs_target = fs::canonicalize(Path::new(dir_path))?;
-
-        println!(
+        debug!(
             "Resolved paths - source: {:?}, target: {:?}",
             abs_source, abs_target
         );
 
+        let read_tree = |path: &Path| -> Result<Bindings> {
+            let mut tree = Bindings::default();
+            self.read_directory_entries_recursive(path, path, 1, &mut tree, symlinks)?;
+            Ok(tree)
+        };
+        // The tree whose entries take precedence, and the one merged below it
+        let (mut upper, lower) = match mode {
+            BindMode::Replace | BindMode::Create => (read_tree(&abs_source)?, Bindings::default()),
+            BindMode::Before => (read_tree(&abs_source)?, read_tree(&abs_target)?),
+            BindMode::After => (read_tree(&abs_target)?, read_tree(&abs_source)?),
+        };
+        // Entries created in the root directory go to its upper layer
+        let upper_path = if matches!(mode, BindMode::After) { abs_target } else { abs_source };
+        let upper_metadata = fs::metadata(&upper_path)?;
+
+        let mut bindings = self.fs.namespace_manager.bindings.lock().unwrap();
         match mode {
             BindMode::Replace => {
                 // Clear existing bindings but keep root
                 bindings.retain(|&ino, _| ino == 1);
-
-                // Read source directory recursively
-                self.read_directory_entries_recursive(
-                    &abs_source,
-                    &abs_source,
-                    1,
-                    &mut next_inode,
-                    &mut bindings,
-                )?;
+                bindings.extend(upper);
             }
             BindMode::Before => {
-                let mut new_bindings = HashMap::new();
-
-                // Read source directory recursively
-                self.read_directory_entries_recursive(
-                    &abs_source,
-                    &abs_source,
-                    1,
-                    &mut next_inode,
-                    &mut new_bindings,
-                )?;
-
-                // Read target directory and add non-conflicting entries
-                let mut target_bindings = HashMap::new();
-                self.read_directory_entries_recursive(
-                    &abs_target,
-                    &abs_target,
-                    1,
-                    &mut next_inode,
-                    &mut target_bindings,
-                )?;
-
-                for (inode, (path, entry)) in target_bindings {
-                    if !new_bindings.values().any(|(p, _)| p == &path) {
-                        new_bindings.insert(inode, (path, entry));
-                    }
-                }
-
-                bindings.extend(new_bindings);
+                // Add the non-conflicting entries of the target
+                merge_layer(&mut upper, lower);
+                bindings.extend(upper);
             }
             BindMode::After => {
-                // Read target directory first
-                let mut target_bindings = HashMap::new();
-                self.read_directory_entries_recursive(
-                    &abs_target,
-                    &abs_target,
-                    1,
-                    &mut next_inode,
-                    &mut target_bindings,
-                )?;
-
-                bindings.extend(target_bindings);
-
-                // Add non-conflicting source entries
-                let mut source_bindings = HashMap::new();
-                self.read_directory_entries_recursive(
-                    &abs_source,
-                    &abs_source,
-                    1,
-                    &mut next_inode,
-                    &mut source_bindings,
-                )?;
-
-                for (inode, (path, entry)) in source_bindings {
-                    if !bindings.values().any(|(p, _)| p == &path) {
-                        bindings.insert(inode, (path, entry));
-                    }
-                }
+                // Add the non-conflicting entries of the source
+                bindings.extend(upper);
+                merge_layer(&mut bindings, lower);
             }
             BindMode::Create => {
                 // Clear existing bindings but keep root
                 bindings.retain(|&ino, _| ino == 1);
 
-                // Read source directory recursively
-                let mut new_bindings = HashMap::new();
-                self.read_directory_entries_recursive(
-                    &abs_source,
-                    &abs_source,
-                    1,
-                    &mut next_inode,
-                    &mut new_bindings,
-                )?;
-
                 // Make all entries read-only
-                for (_, (_, entry)) in new_bindings.iter_mut() {
+                for (_, (_, entry)) in upper.iter_mut() {
                     entry.attr.perm &= 0o555;
                 }
 
-                bindings.extend(new_bindings);
+                bindings.extend(upper);
             }
         }
 
-        println!("Final bindings: {:?}", bindings.keys().collect::<Vec<_>>());
+        // An `After` bind leaves an existing upper layer of the root in place
+        let root = &mut bindings.get_mut(&ROOT_INODE).unwrap().1;
+        if !(matches!(mode, BindMode::After) && root.backing.is_some()) {
+            root.backing = Some(Backing::new(upper_path, &upper_metadata));
+        }
+
+        debug!("Final bindings: {:?}", bindings.keys().collect::<Vec<_>>());
         for (inode, (name, entry)) in bindings.iter() {
-            println!(
+            debug!(
                 "inode: {}, name: {:?}, kind: {:?}",
                 inode, name, entry.attr.kind
             );
@@ -287,27 +290,54 @@ impl FilesystemManager {
     /// * `Ok(())` if the binding was successful
     /// * `Err(...)` if the binding failed (e.g., invalid paths, permission issues)
     pub fn bind(&self, source: &Path, target: &Path, mode: BindMode) -> Result<()> {
+        self.bind_with_symlinks(source, target, mode, SymlinkPolicy::default())
+    }
+
+    /// Binds a source path to a target path, choosing how symlinks that
+    /// point outside the bound directories are treated.
+    ///
+    /// Symlinks are kept as links by [`FilesystemManager::bind`].
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The source path to bind from
+    /// * `target` - The target path to bind to
+    /// * `mode` - The binding mode to use
+    /// * `symlinks` - Whether symlinks leading outside are followed, kept or refused
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the binding was successful
+    /// * `Err(...)` if the binding failed
+    pub fn bind_with_symlinks(
+        &self,
+        source: &Path,
+        target: &Path,
+        mode: BindMode,
+        symlinks: SymlinkPolicy,
+    ) -> Result<()> {
         info!("Binding {:?} to {:?} with mode {:?}", source, target, mode);
         let abs_source = fs::canonicalize(source)?;
         let abs_target = fs::canonicalize(target)?;
         if !abs_source.exists() {
-            return Err(anyhow!("Source path does not exist: {:?}", abs_source));
+            return Err(Error::NotFound);
         }
         if !abs_target.exists() {
-            return Err(anyhow!("Target path does not exist: {:?}", abs_target));
+            return Err(Error::NotFound);
         }
         let entry = NamespaceEntry {
             source: abs_source.clone(),
             target: abs_target.clone(),
             bind_mode: mode.clone(),
+            symlinks: symlinks.clone(),
             remote_node: None,
         };
         let mut namespace = self.fs.namespace_manager.namespace.write().unwrap();
         namespace
             .entry(abs_target.clone())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(entry);
-        self.bind_directory(abs_target.to_str().unwrap(), &abs_source, mode)?;
+        self.bind_directory(abs_target.to_str().unwrap(), &abs_source, mode, &symlinks)?;
         
         // After successful bind
         info!("Bind operation successful, notifying session");
@@ -323,6 +353,10 @@ impl FilesystemManager {
     }
 
     /// Mounts a filesystem at the specified path.
+    ///
+    /// The namespace is served through FUSE on `target` by a background
+    /// session, which stays mounted until the target is unmounted or the
+    /// manager's session shuts down.
     /// 
     /// # Arguments
     /// 
@@ -353,11 +387,11 @@ impl FilesystemManager {
         // Verify paths exist
         if !abs_source.exists() {
             error!("Source path does not exist: {:?}", abs_source);
-            return Err(anyhow!("Source path does not exist: {:?}", abs_source));
+            return Err(Error::NotFound);
         }
         if !abs_target.exists() {
             error!("Target path does not exist: {:?}", abs_target);
-            return Err(anyhow!("Target path does not exist: {:?}", abs_target));
+            return Err(Error::NotFound);
         }
 
         thread::sleep(std::time::Duration::from_millis(100));
@@ -368,6 +402,7 @@ impl FilesystemManager {
             source: abs_source.clone(),
             target: abs_target.clone(),
             bind_mode: BindMode::Before,
+            symlinks: SymlinkPolicy::default(),
             remote_node: Some(node_id.to_string()),
         };
 
@@ -376,11 +411,19 @@ impl FilesystemManager {
         let mut namespace = self.fs.namespace_manager.namespace.write().unwrap();
         namespace
             .entry(abs_target.clone())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(entry);
         
         // Update bindings
         self.update_bindings(abs_target.to_str().unwrap(), &abs_source)?;
+        drop(namespace);
+
+        // Serve the namespace at the mount point
+        if let Err(e) = self.spawn_fuse(&abs_target) {
+            error!("Failed to mount {:?} through FUSE: {}", abs_target, e);
+            self.unmount(&abs_target, Some(&abs_source))?;
+            return Err(e);
+        }
 
         // Notify session of successful mount
         info!("Mount operation successful, notifying session");
@@ -399,6 +442,8 @@ impl FilesystemManager {
     }
 
     /// Unmounts a filesystem at the specified path.
+    ///
+    /// Once no source is left at the path, its FUSE mount is torn down.
     /// 
     /// # Arguments
     /// * `path` - The path to unmount
@@ -421,28 +466,52 @@ impl FilesystemManager {
 
             if entries.is_empty() {
                 namespace.remove(&abs_path);
+                drop(namespace);
+                self.handle_unmount(&abs_path);
             }
         }
 
         Ok(())
     }
 
-    // Platform-specific unmount handler
-    fn handle_unmount(path: &str) {
-        let c_path = CString::new(path).expect("CString::new failed");
+    /// Unmounts every FUSE mount of the namespace.
+    ///
+    /// Called when the session shuts down. The namespace itself is left
+    /// untouched.
+    pub fn unmount_all(&self) {
+        let fuse_sessions: Vec<_> = self.fuse_sessions.lock().unwrap().drain().collect();
+        for (mount_point, fuse_session) in fuse_sessions {
+            info!("Unmounting FUSE at {:?}", mount_point);
+            fuse_session.join();
+        }
+    }
 
-        #[cfg(target_os = "macos")]
-        unsafe {
-            if unmount(c_path.as_ptr(), 0) != 0 {
-                eprintln!("Failed to unmount {}", path);
-            }
+    // Starts a background FUSE session serving the namespace on `mount_point`,
+    // unless one is already running there
+    fn spawn_fuse(&self, mount_point: &Path) -> Result<()> {
+        let mut fuse_sessions = self.fuse_sessions.lock().unwrap();
+        if fuse_sessions.contains_key(mount_point) {
+            return Ok(());
         }
 
-        #[cfg(target_os = "linux")]
-        unsafe {
-            if umount(c_path.as_ptr()) != 0 {
-                eprintln!("Failed to unmount {}", path);
-            }
+        let options = [
+            MountOption::FSName("froggr".to_string()),
+            MountOption::Subtype("9p".to_string()),
+            MountOption::DefaultPermissions,
+        ];
+        let fuse_session = fuser::spawn_mount2(self.fs.clone(), mount_point, &options)?;
+        info!("Serving namespace through FUSE at {:?}", mount_point);
+        fuse_sessions.insert(mount_point.to_path_buf(), fuse_session);
+        Ok(())
+    }
+
+    // Ends the FUSE session on `mount_point`, if any. Joining the session
+    // unmounts the filesystem and waits for its thread to finish.
+    fn handle_unmount(&self, mount_point: &Path) {
+        let fuse_session = self.fuse_sessions.lock().unwrap().remove(mount_point);
+        if let Some(fuse_session) = fuse_session {
+            info!("Unmounting FUSE at {:?}", mount_point);
+            fuse_session.join();
         }
     }
 
@@ -478,72 +547,30 @@ impl FilesystemManager {
         });
     }
 
-    /// Internal method to mount a directory.
-    /// 
-    /// # Arguments
-    /// * `dir_path` - The target directory path to mount to
-    /// * `source_path` - The source directory path to mount from
-    /// 
-    /// # Returns
-    /// * `Result<()>` indicating success or failure
-    fn mount_directory(&self, dir_path: &str, source_path: &Path) -> Result<()> {
-        debug!("Mounting directory: {} from source: {:?}", dir_path, source_path);
-
-        let mut bindings = self.fs.namespace_manager.bindings.lock().unwrap();
-        let mut next_inode = self.fs.namespace_manager.next_inode.lock().unwrap();
+    fn update_bindings(&self, dir_path: &str, source_path: &Path) -> Result<()> {
+        debug!("Updating bindings for: {} from source: {:?}", dir_path, source_path);
 
-        // Convert paths to absolute paths
+        // Resolve the paths and read the source before locking, as they may
+        // lead through a FUSE mount of the namespace itself
         let abs_source = fs::canonicalize(source_path)?;
         let abs_target = fs::canonicalize(Path::new(dir_path))?;
 
-        info!(
-            "Resolved paths - source: {:?}, target: {:?}",
-            abs_source, abs_target
-        );
-
-        // Clear existing bindings but keep root
-        bindings.retain(|&ino, _| ino == 1);
-
-        // Read source directory recursively
+        let mut tree = Bindings::default();
         self.read_directory_entries_recursive(
             &abs_source,
             &abs_source,
             1,
-            &mut next_inode,
-            &mut bindings,
+            &mut tree,
+            &SymlinkPolicy::default(),
         )?;
-
-        info!("Final bindings: {:?}", bindings.keys().collect::<Vec<_>>());
-        for (inode, (name, entry)) in bindings.iter() {
-            debug!(
-                "inode: {}, name: {:?}, kind: {:?}",
-                inode, name, entry.attr.kind
-            );
-        }
-        Ok(())
-    }
-
-    fn update_bindings(&self, dir_path: &str, source_path: &Path) -> Result<()> {
-        debug!("Updating bindings for: {} from source: {:?}", dir_path, source_path);
+        let metadata = fs::metadata(&abs_source)?;
 
         let mut bindings = self.fs.namespace_manager.bindings.lock().unwrap();
-        let mut next_inode = self.fs.namespace_manager.next_inode.lock().unwrap();
-
-        // Convert paths to absolute paths
-        let abs_source = fs::canonicalize(source_path)?;
-        let abs_target = fs::canonicalize(Path::new(dir_path))?;
-
         // Clear existing bindings but keep root
         bindings.retain(|&ino, _| ino == 1);
-
-        // Read source directory recursively
-        self.read_directory_entries_recursive(
-            &abs_source,
-            &abs_source,
-            1,
-            &mut next_inode,
-            &mut bindings,
-        )?;
+        bindings.extend(tree);
+        let root = &mut bindings.get_mut(&ROOT_INODE).unwrap().1;
+        root.backing = Some(Backing::new(abs_source, &metadata));
 
         info!("Final bindings: {:?}", bindings.keys().collect::<Vec<_>>());
         for (inode, (name, entry)) in bindings.iter() {
@@ -556,9 +583,45 @@ impl FilesystemManager {
     }
 }
 
+/// Merges the entries of a lower layer into `upper`.
+///
+/// Entries already present in `upper` take precedence over entries with the
+/// same name in the same directory. Directories present in both layers are
+/// merged, so their children form a union as well.
+fn merge_layer(upper: &mut Bindings, lower: Bindings) {
+    // Lower inodes are mapped to the upper inode they end up under
+    let mut remap: HashMap<u64, u64> = HashMap::from([(ROOT_INODE, ROOT_INODE)]);
+    let mut pending: Vec<(u64, (OsString, BoundEntry))> = lower.into_iter().collect();
+    // Parents are always numbered before their children
+    pending.sort_by_key(|(ino, _)| *ino);
+
+    for (ino, (name, mut entry)) in pending {
+        let Some(&parent) = remap.get(&entry.parent) else {
+            // The parent was shadowed by a file in the upper layer
+            continue;
+        };
+
+        match find_child(upper, parent, &name) {
+            Some(existing) => {
+                let both_dirs = entry.attr.kind == FileType::Directory
+                    && upper[&existing].1.attr.kind == FileType::Directory;
+                if both_dirs {
+                    remap.insert(ino, existing);
+                }
+            }
+            None => {
+                entry.parent = parent;
+                upper.insert(ino, (name, entry));
+                remap.insert(ino, ino);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::modules::proto::OpenFlags;
     use tempfile::TempDir;
 
     fn setup_test_manager() -> (TempDir, FilesystemManager) {
@@ -574,6 +637,130 @@ mod tests {
         Ok(dir)
     }
 
+    #[test]
+    fn test_create_goes_to_upper_layer() -> Result<()> {
+        let (root_dir, manager) = setup_test_manager();
+        let upper = create_temp_dir_with_files(root_dir.path())?;
+        let lower = tempfile::tempdir_in(root_dir.path())?;
+        fs::create_dir(lower.path().join("sub"))?;
+        let target = lower.path().to_str().unwrap();
+        manager.bind_directory(target, upper.path(), BindMode::Before, &SymlinkPolicy::Keep)?;
+
+        let mut fs = manager.fs.connection();
+        let rdwr = OpenFlags(OpenFlags::O_RDWR);
+        // Bound directories keep the owner of the directories on disk
+        let owner = nix::unistd::getuid().to_string();
+        fs.attach(0, None, &owner, "")?;
+        fs.walk(0, 1, &["sub".to_string()])?;
+        fs.walk(0, 2, &[])?;
+
+        // A directory only the lower layer has takes new entries there
+        fs.create(1, "lower.txt", 0o644, rdwr, "")?;
+        fs.create(2, "upper.txt", 0o644, rdwr, "")?;
+        assert!(lower.path().join("sub/lower.txt").is_file());
+        assert!(upper.path().join("upper.txt").is_file());
+        assert!(!lower.path().join("upper.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bound_files_are_read_on_demand() -> Result<()> {
+        let (root_dir, manager) = setup_test_manager();
+        let source = create_temp_dir_with_files(root_dir.path())?;
+        let target_dir = tempfile::tempdir_in(root_dir.path())?;
+        let target = target_dir.path().to_str().unwrap();
+        manager.bind_directory(target, source.path(), BindMode::Replace, &SymlinkPolicy::Keep)?;
+        {
+            let bindings = manager.fs.namespace_manager.bindings.lock().unwrap();
+            assert!(bindings.values().all(|(_, entry)| entry.content.is_none()));
+        }
+
+        let mut fs = manager.fs.connection();
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &["test.txt".to_string()])?;
+        fs.open(1, OpenFlags(OpenFlags::O_RDONLY))?;
+        assert_eq!(fs.read(1, 5, 4)?, b"cont");
+
+        // Changes to the source show up without binding it again
+        fs::write(source.path().join("test.txt"), "fresh content")?;
+        assert_eq!(fs.read(1, 6, 100)?, b"content");
+        assert!(fs.read(1, 100, 4)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_preserves_attributes_and_file_types() -> Result<()> {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let (root_dir, manager) = setup_test_manager();
+        let source = create_temp_dir_with_files(root_dir.path())?;
+        let script = source.path().join("run.sh");
+        fs::write(&script, "#!/bin/sh\n")?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o751))?;
+        symlink("test.txt", source.path().join("link"))?;
+        nix::unistd::mkfifo(&source.path().join("pipe"), nix::sys::stat::Mode::S_IRWXU)?;
+        let target_dir = tempfile::tempdir_in(root_dir.path())?;
+        let target = target_dir.path().to_str().unwrap();
+        manager.bind_directory(target, source.path(), BindMode::Replace, &SymlinkPolicy::Keep)?;
+
+        let fs = &manager.fs;
+        let bindings = fs.namespace_manager.bindings.lock().unwrap();
+        let entry = |name: &str| {
+            let inode = find_child(&bindings, ROOT_INODE, name.as_ref()).unwrap();
+            &bindings[&inode].1
+        };
+        let metadata = fs::metadata(&script)?;
+        let attr = entry("run.sh").attr;
+        assert_eq!(attr.perm, 0o751);
+        assert_eq!((attr.uid, attr.gid), (metadata.uid(), metadata.gid()));
+        assert_eq!(attr.nlink, 1);
+        assert_eq!(attr.blocks, metadata.blocks());
+        assert_ne!(attr.ctime, UNIX_EPOCH);
+
+        assert_eq!(entry("link").attr.kind, FileType::Symlink);
+        assert_eq!(entry("link").content.as_deref(), Some(&b"test.txt"[..]));
+        assert_eq!(entry("pipe").attr.kind, FileType::NamedPipe);
+        assert_eq!(entry("pipe").attr.perm, 0o700);
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_policy_for_links_outside_source() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let (root_dir, _) = setup_test_manager();
+        let outside = create_temp_dir_with_files(root_dir.path())?;
+        let source = create_temp_dir_with_files(root_dir.path())?;
+        symlink(outside.path().join("test.txt"), source.path().join("out"))?;
+        symlink(outside.path(), source.path().join("outdir"))?;
+        symlink("test.txt", source.path().join("in"))?;
+        symlink(outside.path().join("missing"), source.path().join("dangling"))?;
+
+        let kinds = |symlinks: SymlinkPolicy| -> Result<Vec<Option<FileType>>> {
+            let (_, manager) = setup_test_manager();
+            manager.bind_directory("/", source.path(), BindMode::Replace, &symlinks)?;
+            let bindings = manager.fs.namespace_manager.bindings.lock().unwrap();
+            let kind = |name: &str| {
+                find_child(&bindings, ROOT_INODE, name.as_ref()).map(|ino| bindings[&ino].1.attr.kind)
+            };
+            Ok(vec![kind("out"), kind("outdir"), kind("in"), kind("dangling")])
+        };
+        let symlink_kind = Some(FileType::Symlink);
+        assert_eq!(kinds(SymlinkPolicy::Keep)?, vec![symlink_kind; 4]);
+        // A link that cannot be followed is kept rather than failing the bind
+        assert_eq!(
+            kinds(SymlinkPolicy::Follow)?,
+            vec![
+                Some(FileType::RegularFile),
+                Some(FileType::Directory),
+                symlink_kind,
+                symlink_kind
+            ]
+        );
+        assert_eq!(kinds(SymlinkPolicy::Refuse)?, vec![None, None, symlink_kind, None]);
+        Ok(())
+    }
+
     // figure out how to test bind_directory
     // #[test]
     // fn test_bind_directory() -> Result<()> {
@@ -2,10 +2,11 @@
 
 use anyhow::Result;
 use fuser::{FileAttr, FileType};
-use std::collections::HashMap;
-use std::ffi::OsString;
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
 use std::str::FromStr;
 use serde::{Serialize, Deserialize};
@@ -39,6 +40,152 @@ impl FromStr for BindMode {
     }
 }
 
+/// How a bind treats symlinks that point outside the bound source.
+///
+/// Symlinks pointing inside the source are always kept as links.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum SymlinkPolicy {
+    /// Bind the file or directory the link points to in place of the link
+    Follow,
+    /// Keep the link, so that it is resolved by whoever reads it
+    #[default]
+    Keep,
+    /// Leave the link out of the namespace
+    Refuse,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "keep" => Ok(SymlinkPolicy::Keep),
+            "refuse" => Ok(SymlinkPolicy::Refuse),
+            _ => Err(format!("Invalid symlink policy: {}", s)),
+        }
+    }
+}
+
+/// Mapping of inodes to their file name and bound entry.
+///
+/// Entries are also indexed by directory and name, so that looking up a
+/// name or listing a directory does not go through every entry. The index
+/// follows inserts and removals; the name and parent of an entry already in
+/// the bindings are changed with `rename`.
+#[derive(Debug, Clone, Default)]
+pub struct Bindings {
+    entries: HashMap<u64, (OsString, BoundEntry)>,
+    children: HashMap<u64, BTreeMap<OsString, u64>>,
+}
+
+impl Bindings {
+    /// Adds an entry, replacing the one with the same inode if there is one.
+    ///
+    /// # Returns
+    /// The replaced entry, if any
+    pub fn insert(
+        &mut self,
+        inode: u64,
+        entry: (OsString, BoundEntry),
+    ) -> Option<(OsString, BoundEntry)> {
+        let replaced = self.remove(&inode);
+        if inode != ROOT_INODE {
+            self.children
+                .entry(entry.1.parent)
+                .or_default()
+                .insert(entry.0.clone(), inode);
+        }
+        self.entries.insert(inode, entry);
+        replaced
+    }
+
+    /// Removes an entry, leaving the entries below it in place.
+    ///
+    /// # Returns
+    /// The removed entry, if there was one
+    pub fn remove(&mut self, inode: &u64) -> Option<(OsString, BoundEntry)> {
+        let (name, entry) = self.entries.remove(inode)?;
+        if let Some(names) = self.children.get_mut(&entry.parent) {
+            if names.get(&name) == Some(inode) {
+                names.remove(&name);
+            }
+            if names.is_empty() {
+                self.children.remove(&entry.parent);
+            }
+        }
+        Some((name, entry))
+    }
+
+    /// Gives an entry a new name and parent directory.
+    ///
+    /// # Returns
+    /// `false` if there is no entry with the inode
+    pub fn rename(&mut self, inode: u64, parent: u64, name: OsString) -> bool {
+        let Some((_, mut entry)) = self.remove(&inode) else {
+            return false;
+        };
+        entry.parent = parent;
+        self.insert(inode, (name, entry));
+        true
+    }
+
+    /// Keeps only the entries for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&u64, &mut (OsString, BoundEntry)) -> bool) {
+        let removed: Vec<u64> = self
+            .entries
+            .iter_mut()
+            .filter_map(|(inode, entry)| (!keep(inode, entry)).then_some(*inode))
+            .collect();
+        for inode in removed {
+            self.remove(&inode);
+        }
+    }
+
+    /// Returns the entry with the inode for changes other than its name or
+    /// parent, which are changed with `rename`.
+    pub fn get_mut(&mut self, inode: &u64) -> Option<&mut (OsString, BoundEntry)> {
+        self.entries.get_mut(inode)
+    }
+
+    /// Iterates over the entries for changes other than their names or
+    /// parents.
+    pub fn iter_mut(&mut self) -> hash_map::IterMut<'_, u64, (OsString, BoundEntry)> {
+        self.entries.iter_mut()
+    }
+
+    /// Iterates over the entries for changes other than their names or
+    /// parents.
+    pub fn values_mut(&mut self) -> hash_map::ValuesMut<'_, u64, (OsString, BoundEntry)> {
+        self.entries.values_mut()
+    }
+}
+
+impl Deref for Bindings {
+    type Target = HashMap<u64, (OsString, BoundEntry)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl Extend<(u64, (OsString, BoundEntry))> for Bindings {
+    fn extend<T: IntoIterator<Item = (u64, (OsString, BoundEntry))>>(&mut self, iter: T) {
+        for (inode, entry) in iter {
+            self.insert(inode, entry);
+        }
+    }
+}
+
+impl IntoIterator for Bindings {
+    type Item = (u64, (OsString, BoundEntry));
+    type IntoIter = hash_map::IntoIter<u64, (OsString, BoundEntry)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
 /// Entry in the namespace representing a bind operation
 #[derive(Debug, Clone)]
 pub struct NamespaceEntry {
@@ -48,6 +195,8 @@ pub struct NamespaceEntry {
     pub target: PathBuf,
     /// Mode of the bind operation
     pub bind_mode: BindMode,
+    /// Treatment of symlinks pointing outside the source
+    pub symlinks: SymlinkPolicy,
     /// Optional remote node identifier
     pub remote_node: Option<String>,
 }
@@ -60,7 +209,7 @@ pub struct NamespaceManager {
     /// Root directory of the filesystem
     pub root: PathBuf,
     /// Mapping of inodes to bound entries
-    pub bindings: Arc<Mutex<HashMap<u64, (OsString, BoundEntry)>>>,
+    pub bindings: Arc<Mutex<Bindings>>,
     /// Next available inode number
     pub next_inode: Arc<Mutex<u64>>,
 }
@@ -82,15 +231,12 @@ impl NamespaceManager {
     pub fn new(root: PathBuf) -> Result<Self> {
         fs::create_dir_all(&root)?;
 
-        let mut bindings = HashMap::new();
+        let mut bindings = Bindings::default();
         bindings.insert(
             ROOT_INODE,
             (
                 OsString::from("."),
-                BoundEntry {
-                    attr: create_root_attr(),
-                    content: None,
-                },
+                BoundEntry::new(create_root_attr(), ROOT_INODE, None),
             ),
         );
 
@@ -113,17 +259,16 @@ impl NamespaceManager {
         let abs_path = fs::canonicalize(original_path)?;
         let namespace = self.namespace.read().unwrap();
 
-        if let Some(entries) = namespace.get(&abs_path) {
-            for entry in entries.iter().rev() {
-                match entry.bind_mode {
-                    BindMode::Replace => return Ok(entry.source.clone()),
-                    BindMode::Before | BindMode::After | BindMode::Create => {
-                        let mut new_path = entry.source.clone();
-                        new_path.push(abs_path.strip_prefix(&entry.target)?);
-                        return Ok(new_path);
-                    }
+        // The most recent binding of the path decides where it resolves to
+        if let Some(entry) = namespace.get(&abs_path).and_then(|entries| entries.last()) {
+            return match entry.bind_mode {
+                BindMode::Replace => Ok(entry.source.clone()),
+                BindMode::Before | BindMode::After | BindMode::Create => {
+                    let mut new_path = entry.source.clone();
+                    new_path.push(abs_path.strip_prefix(&entry.target)?);
+                    Ok(new_path)
                 }
-            }
+            };
         }
 
         Ok(abs_path)
@@ -142,6 +287,54 @@ impl NamespaceManager {
     }
 }
 
+/// Finds the entry with the given name in a directory.
+///
+/// # Arguments
+/// * `bindings` - The bound entries to search
+/// * `parent` - Inode of the directory
+/// * `name` - Name of the entry
+///
+/// # Returns
+/// The inode of the entry, if the directory contains it
+pub fn find_child(bindings: &Bindings, parent: u64, name: &OsStr) -> Option<u64> {
+    bindings.children.get(&parent)?.get(name).copied()
+}
+
+/// Resolves a path in the namespace, starting at the root directory.
+///
+/// # Arguments
+/// * `bindings` - The bound entries to search
+/// * `path` - The path, e.g. `/builds/latest`
+///
+/// # Returns
+/// The inode the path refers to, if every component exists
+pub fn lookup_path(bindings: &Bindings, path: &Path) -> Option<u64> {
+    path.components().try_fold(ROOT_INODE, |dir, component| match component {
+        Component::RootDir | Component::CurDir => Some(dir),
+        Component::ParentDir => bindings.get(&dir).map(|(_, entry)| entry.parent),
+        Component::Normal(name) => find_child(bindings, dir, name),
+        Component::Prefix(_) => None,
+    })
+}
+
+/// Lists the entries of a directory, ordered by inode.
+///
+/// # Arguments
+/// * `bindings` - The bound entries to search
+/// * `parent` - Inode of the directory
+///
+/// # Returns
+/// The inodes of the directory's entries
+pub fn children(bindings: &Bindings, parent: u64) -> Vec<u64> {
+    let mut inodes: Vec<u64> = bindings
+        .children
+        .get(&parent)
+        .map(|names| names.values().copied().collect())
+        .unwrap_or_default();
+    inodes.sort_unstable();
+    inodes
+}
+
 // Helper function to create root file attributes
 fn create_root_attr() -> FileAttr {
     FileAttr {
@@ -212,6 +405,39 @@ mod tests {
     //     Ok(())
     // }
 
+    fn entry(name: &str, parent: u64) -> (OsString, BoundEntry) {
+        let mut attr = create_root_attr();
+        attr.kind = FileType::RegularFile;
+        (OsString::from(name), BoundEntry::new(attr, parent, None))
+    }
+
+    #[test]
+    fn test_bindings_index_children_by_name() {
+        let mut bindings = Bindings::default();
+        bindings.insert(ROOT_INODE, entry(".", ROOT_INODE));
+        bindings.insert(2, entry("dir", ROOT_INODE));
+        bindings.insert(3, entry("b", 2));
+        bindings.insert(4, entry("a", 2));
+
+        assert_eq!(find_child(&bindings, 2, OsStr::new("a")), Some(4));
+        assert_eq!(lookup_path(&bindings, Path::new("/dir/b")), Some(3));
+        assert_eq!(children(&bindings, 2), vec![3, 4]);
+        assert!(children(&bindings, ROOT_INODE).contains(&2));
+
+        // The index follows renames, replacements and removals
+        assert!(bindings.rename(4, ROOT_INODE, OsString::from("c")));
+        assert_eq!(find_child(&bindings, 2, OsStr::new("a")), None);
+        assert_eq!(lookup_path(&bindings, Path::new("/c")), Some(4));
+        bindings.insert(3, entry("d", 2));
+        assert_eq!(find_child(&bindings, 2, OsStr::new("b")), None);
+        assert_eq!(lookup_path(&bindings, Path::new("/dir/d")), Some(3));
+        bindings.remove(&3);
+        assert!(children(&bindings, 2).is_empty());
+        bindings.retain(|&ino, _| ino != 4);
+        assert_eq!(children(&bindings, ROOT_INODE), vec![2]);
+        assert!(!bindings.rename(4, 2, OsString::from("gone")));
+    }
+
     #[test]
     fn test_list_namespace() -> Result<()> {
         let temp_dir = setup_test_dir();
@@ -228,6 +454,7 @@ mod tests {
                     source: source.clone(),
                     target: target.clone(),
                     bind_mode: BindMode::Replace,
+                    symlinks: SymlinkPolicy::Keep,
                     remote_node: None,
                 }],
             );
@@ -257,17 +484,20 @@ mod tests {
             BindMode::After,
             BindMode::Create,
         ] {
-            let mut namespace = manager.namespace.write().unwrap();
-            namespace.clear();
-            namespace.insert(
-                target.clone(),
-                vec![NamespaceEntry {
-                    source: source.clone(),
-                    target: target.clone(),
-                    bind_mode: mode.clone(),
-                    remote_node: None,
-                }],
-            );
+            {
+                let mut namespace = manager.namespace.write().unwrap();
+                namespace.clear();
+                namespace.insert(
+                    target.clone(),
+                    vec![NamespaceEntry {
+                        source: source.clone(),
+                        target: target.clone(),
+                        bind_mode: mode.clone(),
+                        symlinks: SymlinkPolicy::Keep,
+                        remote_node: None,
+                    }],
+                );
+            }
 
             let resolved = manager.resolve_path(&target)?;
             match mode {
@@ -5,25 +5,65 @@
 //! This module provides the core 9P protocol implementation through the `NineP` type,
 //! along with associated types and constants for filesystem operations.
 
+use super::auth::{AuthFid, AuthScheme};
 use super::constants::*;
-use super::namespace::NamespaceManager;
-use anyhow::{anyhow, Result};
+use super::codec::{
+    encode_dirent, encode_stat, Dialect, DMAPPEND, DMDEVICE, DMDIR, DMEXCL, DMLINK, DMNAMEDPIPE, DMSETGID,
+    DMSETUID, DMSOCKET, DMSYMLINK, GETATTR_BASIC, IOHDRSZ, LOCK_BLOCKED, LOCK_SUCCESS,
+    LOCK_TYPE_UNLCK, LOCK_TYPE_WRLCK, MAXWELEM, NONUNAME, SETATTR_ATIME, SETATTR_ATIME_SET,
+    SETATTR_GID, SETATTR_MODE, SETATTR_MTIME, SETATTR_MTIME_SET, SETATTR_SIZE, SETATTR_UID,
+};
+use super::namespace::{children, find_child, lookup_path, Bindings, NamespaceManager};
+use super::error::{Error, Result};
+use anyhow::anyhow;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use libc::ENOENT;
-use std::collections::HashMap;
-use std::ffi::{OsStr, OsString};
+use log::{debug, warn};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, FileTimes};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, FileExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // 9P protocol constants
 const QTDIR: u8 = 0x80;
 const QTAPPEND: u8 = 0x40;
 const QTEXCL: u8 = 0x20;
 const QTAUTH: u8 = 0x08;
+const QTSYMLINK: u8 = 0x02;
+
+// Permission bits checked against the owner, group or other permissions
+const ACCESS_READ: u16 = 0o4;
+const ACCESS_WRITE: u16 = 0o2;
+const ACCESS_EXEC: u16 = 0o1;
+
+// Linux constants used by 9P2000.L, independent of the host platform
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+const LINUX_O_TRUNC: u32 = 0o1000;
+const AT_REMOVEDIR: u32 = 0x200;
+const RENAME_NOREPLACE: u32 = 0x1;
+const XATTR_SIZE_MAX: u64 = 64 * 1024;
+
+// Set in the handle of a file FUSE opened for exclusive use. The low 32 bits
+// hold the 9P open mode the file was opened with.
+const FH_EXCLUSIVE: u64 = 1 << 32;
+const V9FS_MAGIC: u32 = 0x0102_1997;
 
 /// Represents file open flags for the 9P protocol.
 #[derive(Debug, Clone, Copy)]
@@ -40,10 +80,42 @@ impl OpenFlags {
     pub const O_EXEC: u32 = 0x03;
     /// Truncate file
     pub const O_TRUNC: u32 = 0x10;
+    /// Remove the file when the fid is clunked
+    pub const O_RCLOSE: u32 = 0x40;
+
+    /// Returns whether opening with these flags may modify the file or its directory.
+    pub fn writes(&self) -> bool {
+        self.can_write() || self.0 & (Self::O_TRUNC | Self::O_RCLOSE) != 0
+    }
+
+    /// Returns whether a fid opened with these flags may be read.
+    pub fn can_read(&self) -> bool {
+        self.0 & 0x03 != Self::O_WRONLY
+    }
+
+    /// Returns whether a fid opened with these flags may be written.
+    pub fn can_write(&self) -> bool {
+        matches!(self.0 & 0x03, Self::O_WRONLY | Self::O_RDWR)
+    }
+
+    /// Returns the permission bits the user needs on the file, as `0o4`
+    /// for read, `0o2` for write and `0o1` for execute.
+    pub fn access(&self) -> u16 {
+        let mut access = match self.0 & 0x03 {
+            Self::O_RDONLY => ACCESS_READ,
+            Self::O_WRONLY => ACCESS_WRITE,
+            Self::O_RDWR => ACCESS_READ | ACCESS_WRITE,
+            _ => ACCESS_EXEC,
+        };
+        if self.0 & Self::O_TRUNC != 0 {
+            access |= ACCESS_WRITE;
+        }
+        access
+    }
 }
 
 /// File status information in the 9P protocol.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stat {
     /// Total size of the stat message in bytes
     pub size: u16,
@@ -69,6 +141,143 @@ pub struct Stat {
     pub gid: String,
     /// Username of the last modifier
     pub muid: String,
+    /// Symlink target or device description of special files (9P2000.u)
+    pub extension: String,
+    /// Numeric id of the file owner (9P2000.u)
+    pub n_uid: u32,
+    /// Numeric id of the file group (9P2000.u)
+    pub n_gid: u32,
+    /// Numeric id of the last modifier (9P2000.u)
+    pub n_muid: u32,
+}
+
+/// File attributes returned by the 9P2000.L Tgetattr request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Getattr {
+    /// Mask of the fields that are valid
+    pub valid: u64,
+    /// Unique identifier from the server
+    pub qid: Qid,
+    /// File type and permission bits, as in `st_mode`
+    pub mode: u32,
+    /// Numeric id of the file owner
+    pub uid: u32,
+    /// Numeric id of the file group
+    pub gid: u32,
+    /// Number of hard links
+    pub nlink: u64,
+    /// Device number of device files
+    pub rdev: u64,
+    /// Length of file in bytes
+    pub size: u64,
+    /// Preferred I/O block size
+    pub blksize: u64,
+    /// Number of 512-byte blocks allocated
+    pub blocks: u64,
+    /// Last access time, seconds part
+    pub atime_sec: u64,
+    /// Last access time, nanoseconds part
+    pub atime_nsec: u64,
+    /// Last modification time, seconds part
+    pub mtime_sec: u64,
+    /// Last modification time, nanoseconds part
+    pub mtime_nsec: u64,
+    /// Last status change time, seconds part
+    pub ctime_sec: u64,
+    /// Last status change time, nanoseconds part
+    pub ctime_nsec: u64,
+    /// Creation time, seconds part
+    pub btime_sec: u64,
+    /// Creation time, nanoseconds part
+    pub btime_nsec: u64,
+    /// Inode generation number
+    pub gen: u64,
+    /// Data version number
+    pub data_version: u64,
+}
+
+/// File attributes changed by the 9P2000.L Tsetattr request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Setattr {
+    /// Mask of the fields to change
+    pub valid: u32,
+    /// New permission bits
+    pub mode: u32,
+    /// New owner id
+    pub uid: u32,
+    /// New group id
+    pub gid: u32,
+    /// New length of the file
+    pub size: u64,
+    /// New access time, seconds part
+    pub atime_sec: u64,
+    /// New access time, nanoseconds part
+    pub atime_nsec: u64,
+    /// New modification time, seconds part
+    pub mtime_sec: u64,
+    /// New modification time, nanoseconds part
+    pub mtime_nsec: u64,
+}
+
+/// Filesystem statistics returned by the 9P2000.L Tstatfs request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Statfs {
+    /// Filesystem type
+    pub typ: u32,
+    /// Block size
+    pub bsize: u32,
+    /// Total number of blocks
+    pub blocks: u64,
+    /// Number of free blocks
+    pub bfree: u64,
+    /// Number of blocks available to unprivileged users
+    pub bavail: u64,
+    /// Total number of files
+    pub files: u64,
+    /// Number of free files
+    pub ffree: u64,
+    /// Filesystem id
+    pub fsid: u64,
+    /// Maximum length of file names
+    pub namelen: u32,
+}
+
+/// POSIX byte-range lock used by the 9P2000.L Tlock and Tgetlock requests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Flock {
+    /// Lock type: read, write or unlock
+    pub typ: u8,
+    /// Lock flags (Tlock only)
+    pub flags: u32,
+    /// Offset of the locked range
+    pub start: u64,
+    /// Length of the locked range, 0 meaning up to the end of the file
+    pub length: u64,
+    /// Process id of the lock owner
+    pub proc_id: u32,
+    /// Client id of the lock owner
+    pub client_id: String,
+}
+
+// A lock held on a file, with the connection and fid it was taken through.
+// Locks go away with the fid, so that a client that disconnects does not
+// leave them behind.
+#[derive(Debug, Clone)]
+struct HeldLock {
+    lock: Flock,
+    connection: u64,
+    fid: u32,
+}
+
+/// Extended attribute to be set through a fid prepared by Txattrcreate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XattrCreate {
+    /// Name of the attribute
+    pub name: String,
+    /// Length of the value the client announced
+    pub size: u64,
+    /// Linux setxattr flags
+    pub flags: u32,
 }
 
 /// Represents a bound filesystem entry.
@@ -76,8 +285,192 @@ pub struct Stat {
 pub struct BoundEntry {
     /// File attributes
     pub attr: FileAttr,
-    /// Optional file content
+    /// Inode of the directory containing the entry
+    pub parent: u64,
+    /// File content held in memory, or the target of a symlink. Regular
+    /// files bound from disk have none; their content is read from the
+    /// backing file when it is needed.
     pub content: Option<Vec<u8>>,
+    /// Qid version, increased whenever the content or metadata changes
+    pub version: u32,
+    /// Plan 9 mode bits without a Unix equivalent, `DMAPPEND` and `DMEXCL`
+    pub dm_flags: u32,
+    /// The file the entry was read from, if it was bound from disk
+    pub backing: Option<Backing>,
+}
+
+impl BoundEntry {
+    /// Creates an entry at version 0 that is not backed by a file on disk.
+    ///
+    /// # Arguments
+    /// * `attr` - File attributes.
+    /// * `parent` - Inode of the directory containing the entry.
+    /// * `content` - File content, if any.
+    pub fn new(attr: FileAttr, parent: u64, content: Option<Vec<u8>>) -> Self {
+        Self {
+            attr,
+            parent,
+            content,
+            version: 0,
+            dm_flags: 0,
+            backing: None,
+        }
+    }
+
+    /// Returns the qid of the entry.
+    pub fn qid(&self) -> Qid {
+        let mut file_type = match self.attr.kind {
+            FileType::Directory => QTDIR,
+            FileType::Symlink => QTSYMLINK,
+            _ => 0,
+        };
+        if self.dm_flags & DMAPPEND != 0 {
+            file_type |= QTAPPEND;
+        }
+        if self.dm_flags & DMEXCL != 0 {
+            file_type |= QTEXCL;
+        }
+        Qid {
+            version: self.version,
+            path: self.attr.ino,
+            file_type,
+        }
+    }
+
+    /// Records a change to the content or metadata of the entry.
+    pub fn bump_version(&mut self) {
+        self.version = self.version.wrapping_add(1);
+    }
+
+    /// Updates the entry if its backing file changed since it was last read.
+    ///
+    /// Changes made to the entry through the namespace are kept until the
+    /// backing file itself changes.
+    pub fn refresh(&mut self) {
+        let Some(backing) = &mut self.backing else {
+            return;
+        };
+        // An entry outlives its backing file
+        let Ok(metadata) = fs::symlink_metadata(&backing.path) else {
+            return;
+        };
+        // Links made to the backing file leave its modification time alone
+        self.attr.nlink = metadata.nlink() as u32;
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        if modified == backing.modified && metadata.len() == backing.len {
+            return;
+        }
+
+        backing.modified = modified;
+        backing.len = metadata.len();
+        if self.attr.kind == FileType::RegularFile {
+            self.attr.size = metadata.len();
+        }
+        self.attr.mtime = modified;
+        self.bump_version();
+    }
+
+    /// Returns the path content is read from and written to, if the entry
+    /// is a regular file bound from disk.
+    pub fn backing_file(&self) -> Option<&Path> {
+        match &self.backing {
+            Some(backing) if self.attr.kind == FileType::RegularFile => Some(&backing.path),
+            _ => None,
+        }
+    }
+
+    /// Reads up to `count` bytes of the file, starting at `offset`.
+    ///
+    /// Only the requested range of a backing file is read. Reads at or past
+    /// the end of the file return no data.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset within the file to start reading from.
+    /// * `count` - The maximum number of bytes to read.
+    ///
+    /// # Returns
+    /// The data read from the file.
+    pub fn read_at(&self, offset: u64, count: u32) -> Result<Vec<u8>> {
+        if let Some(path) = self.backing_file() {
+            let mut file = fs::File::open(path)?;
+            // Offsets past the end may be too large to seek to
+            if offset >= file.metadata()?.len() {
+                return Ok(Vec::new());
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::new();
+            file.take(count as u64).read_to_end(&mut data)?;
+            return Ok(data);
+        }
+
+        let content = self.content.as_deref().unwrap_or_default();
+        let start = std::cmp::min(offset, content.len() as u64) as usize;
+        let end = std::cmp::min(start + count as usize, content.len());
+        Ok(content[start..end].to_vec())
+    }
+
+    /// Sets the length of the file. Content held in memory is truncated or
+    /// padded with zeros, while a backing file is left for the caller to
+    /// resize.
+    ///
+    /// # Returns
+    /// `Error::FileTooLarge` if the length is past `max_len`.
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        if len > self.max_len() {
+            return Err(Error::FileTooLarge);
+        }
+        if self.backing_file().is_none() {
+            let content = self.content.get_or_insert_with(Vec::new);
+            content.resize(len as usize, 0);
+        }
+        self.attr.size = len;
+        Ok(())
+    }
+
+    /// Returns the largest length the file may have: that of a file on the
+    /// host for a backing file, and `MAX_CONTENT_SIZE` for content held in
+    /// memory.
+    pub fn max_len(&self) -> u64 {
+        if self.backing_file().is_some() {
+            i64::MAX as u64
+        } else {
+            MAX_CONTENT_SIZE
+        }
+    }
+}
+
+/// The file on disk a bound entry was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backing {
+    /// Path of the backing file
+    pub path: PathBuf,
+    /// Modification time of the backing file when it was last read
+    pub modified: SystemTime,
+    /// Length of the backing file when it was last read
+    pub len: u64,
+}
+
+impl Backing {
+    /// Records the current state of a backing file.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the backing file.
+    /// * `metadata` - Metadata of the backing file.
+    pub fn new(path: PathBuf, metadata: &fs::Metadata) -> Self {
+        Self {
+            path,
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+            len: metadata.len(),
+        }
+    }
+
+    /// Records the current state of the backing file after the namespace
+    /// changed it, so that the change is not taken for an outside one.
+    pub fn update(&mut self) {
+        if let Ok(metadata) = fs::symlink_metadata(&self.path) {
+            *self = Self::new(self.path.clone(), &metadata);
+        }
+    }
 }
 
 /// File open modes.
@@ -93,8 +486,176 @@ pub enum OpenMode {
     Execute = 3,
 }
 
-/// Unique file identifier in the 9P protocol.
+/// State of a fid on a single 9P connection.
+#[derive(Debug, Clone)]
+pub struct Fid {
+    /// Path of the file within the namespace
+    pub path: PathBuf,
+    /// Qid of the file the fid refers to
+    pub qid: Qid,
+    /// Mode the fid was opened with, or `None` if it is not open
+    pub mode: Option<OpenFlags>,
+    /// Offset at which the next directory read continues
+    pub dir_offset: u64,
+    /// Packed stats of the directory's entries, taken when a read starts at
+    /// offset 0 and served to the reads that continue it
+    pub dir_entries: Vec<u8>,
+    /// Value read through the fid if it was walked to an extended attribute,
+    /// or written through it after Txattrcreate
+    pub xattr: Option<Vec<u8>>,
+    /// Extended attribute set from the written value when the fid is clunked
+    pub xattr_create: Option<XattrCreate>,
+    /// Authentication conversation if the fid was opened by Tauth
+    pub auth: Option<Arc<Mutex<AuthFid>>>,
+    /// Inode of the root of the tree the fid was attached to
+    pub root: u64,
+    /// Whether the tree the fid was attached to is exported read-only
+    pub read_only: bool,
+    /// Name of the user who attached the fid
+    pub uname: String,
+    /// User id permissions are checked against
+    pub uid: u32,
+    /// Group id permissions are checked against
+    pub gid: u32,
+    /// Whether the fid holds an exclusive-use (`DMEXCL`) file open
+    pub exclusive: bool,
+}
+
+impl Fid {
+    /// Creates a new, unopened fid.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file within the namespace.
+    /// * `qid` - Qid of the file.
+    pub fn new(path: PathBuf, qid: Qid) -> Self {
+        Self {
+            path,
+            qid,
+            mode: None,
+            dir_offset: 0,
+            dir_entries: Vec::new(),
+            xattr: None,
+            xattr_create: None,
+            auth: None,
+            root: ROOT_INODE,
+            read_only: false,
+            uname: String::new(),
+            uid: DEFAULT_UID,
+            gid: DEFAULT_GID,
+            exclusive: false,
+        }
+    }
+
+    /// Creates a new, unopened fid in the same tree as `self`.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file within the namespace.
+    /// * `qid` - Qid of the file.
+    pub fn walked_to(&self, path: PathBuf, qid: Qid) -> Self {
+        Self {
+            root: self.root,
+            read_only: self.read_only,
+            uname: self.uname.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            ..Self::new(path, qid)
+        }
+    }
+
+    // The user permissions are checked against for requests on the fid
+    fn credentials(&self) -> Credentials {
+        Credentials {
+            uid: self.uid,
+            gid: self.gid,
+            uname: self.uname.clone(),
+        }
+    }
+}
+
+// The user an operation is made for, whose permissions are checked. 9P
+// requests act as the user who attached the fid, FUSE requests as the user
+// of the calling process.
 #[derive(Debug, Clone)]
+struct Credentials {
+    uid: u32,
+    gid: u32,
+    uname: String,
+}
+
+impl Credentials {
+    // The user making a FUSE request
+    fn of_request(req: &Request) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+            uname: user_name(req.uid()),
+        }
+    }
+
+    fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    // Whether the user may act as the owner of a file
+    fn owns(&self, attr: &FileAttr) -> bool {
+        self.is_root() || self.uid == attr.uid
+    }
+
+    // Whether group `gid` is the user's group or lists the user as a member
+    fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || in_group(&self.uname, gid)
+    }
+}
+
+/// A tree that clients can attach to by naming it in the attach `aname`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    /// Path of the root of the tree within the namespace
+    pub path: PathBuf,
+    /// Whether clients may only read the tree
+    pub read_only: bool,
+    /// Users allowed to attach to the tree, or `None` to allow everyone
+    pub users: Option<Vec<String>>,
+}
+
+impl Export {
+    /// Parses an export given as `NAME=PATH[:ro][:users=USER,...]`.
+    ///
+    /// # Arguments
+    /// * `spec` - The export, e.g. `snapshot=/builds/latest:ro:users=glenda`.
+    ///
+    /// # Returns
+    /// The name of the tree and the export.
+    pub fn parse(spec: &str) -> anyhow::Result<(String, Self)> {
+        let (name, rest) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid export {}: expected NAME=PATH", spec))?;
+        let mut options = rest.split(':');
+        let mut export = Export {
+            path: PathBuf::from(options.next().unwrap_or_default()),
+            read_only: false,
+            users: None,
+        };
+        if !export.path.has_root() {
+            return Err(anyhow!("Export path must be absolute: {}", spec));
+        }
+
+        for option in options {
+            match option.split_once('=') {
+                None if option == "ro" => export.read_only = true,
+                None if option == "rw" => export.read_only = false,
+                Some(("users", users)) => {
+                    export.users = Some(users.split(',').map(str::to_string).collect());
+                }
+                _ => return Err(anyhow!("Unknown export option: {}", option)),
+            }
+        }
+        Ok((name.to_string(), export))
+    }
+}
+
+/// Unique file identifier in the 9P protocol.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Qid {
     /// Version number for cache validation
     pub version: u32,
@@ -110,21 +671,25 @@ pub struct Qid {
 /// allowing you to create a virtual filesystem that can be mounted and
 /// accessed by clients.
 ///
+/// Fids, the negotiated msize and the protocol version belong to a single
+/// client connection. Use [`NineP::connection`] to obtain a handle that shares
+/// the namespace but has its own fid table.
+///
 /// # Example
 ///
 /// ```rust
-/// use filesystem_manager::NineP;
+/// use froggr::NineP;
 /// use anyhow::Result;
 /// use std::path::PathBuf;
 ///
 /// fn main() -> Result<()> {
 ///     // Create a new NineP filesystem with "/tmp/test" as the root directory
-///     let hello_fs = NineP::new(PathBuf::from("/tmp/test"))?;
+///     let mut hello_fs = NineP::new(PathBuf::from("/tmp/test"))?;
 ///
 ///     // Perform various filesystem operations using the NineP instance
 ///     hello_fs.version("9P2000", 8192)?;
-///     let qid = hello_fs.attach(0, None, "user", "default")?;
-///     let qids = hello_fs.walk(0, 1, &["dir1", "file.txt"])?;
+///     let qid = hello_fs.attach(0, None, "user", "")?;
+///     let qids = hello_fs.walk(0, 1, &[])?;
 ///
 ///     Ok(())
 /// }
@@ -134,12 +699,28 @@ pub struct Qid {
 pub struct NineP {
     /// The namespace manager for the NineP filesystem.
     pub namespace_manager: NamespaceManager,
-    /// A mapping of file IDs (fids) to their corresponding file paths.
-    fids: Arc<Mutex<HashMap<u32, PathBuf>>>,
+    /// The fids of the current connection.
+    fids: Arc<Mutex<HashMap<u32, Fid>>>,
     /// The maximum message size for the 9P protocol.
     msize: u32,
+    /// The largest msize the server agrees to during version negotiation.
+    max_msize: u32,
     /// The version of the 9P protocol.
     version: String,
+    /// Byte-range locks held on each inode, shared by all connections.
+    locks: Arc<Mutex<HashMap<u64, Vec<HeldLock>>>>,
+    /// Number of the connection, telling its locks apart from others'.
+    connection: u64,
+    /// Number of connections handed out, shared by all connections.
+    connections: Arc<AtomicU64>,
+    /// Scheme clients must authenticate with, or `None` to allow anyone.
+    auth: Option<Arc<dyn AuthScheme>>,
+    /// Trees clients can attach to, by aname, shared by all connections.
+    exports: Arc<Mutex<HashMap<String, Export>>>,
+    /// Exclusive-use files open on any connection.
+    exclusive: Arc<Mutex<HashSet<u64>>>,
+    /// Set once the request the handle runs is flushed, see `cancelled_by`.
+    cancel: Option<Arc<AtomicBool>>,
 }
 
 impl NineP {
@@ -151,26 +732,142 @@ impl NineP {
     /// # Returns
     /// A new `NineP` instance.
     pub fn new(path: PathBuf) -> Result<Self> {
+        // An empty aname attaches to the whole namespace
+        let root = Export {
+            path: PathBuf::from("/"),
+            read_only: false,
+            users: None,
+        };
         Ok(Self {
             namespace_manager: NamespaceManager::new(path)?,
             fids: Arc::new(Mutex::new(HashMap::new())),
-            msize: 8192,
+            msize: DEFAULT_MSIZE,
+            max_msize: MAX_MSIZE,
             version: "9P2000".to_string(),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            connection: 0,
+            connections: Arc::new(AtomicU64::new(1)),
+            auth: None,
+            exports: Arc::new(Mutex::new(HashMap::from([(String::new(), root)]))),
+            exclusive: Arc::new(Mutex::new(HashSet::new())),
+            cancel: None,
         })
     }
 
-    fn qid_from_attr(attr: &FileAttr) -> Qid {
-        Qid {
-            version: 0,
-            path: attr.ino,
-            file_type: if attr.kind == FileType::Directory {
-                QTDIR
-            } else {
-                0
-            },
+    /// Returns a handle for a new client connection.
+    ///
+    /// The handle shares the namespace with `self` but starts with an empty
+    /// fid table and the default msize and version. The configured maximum
+    /// msize and authentication scheme are inherited.
+    ///
+    /// # Returns
+    /// A new `NineP` instance scoped to one connection.
+    pub fn connection(&self) -> Self {
+        Self {
+            namespace_manager: self.namespace_manager.clone(),
+            fids: Arc::new(Mutex::new(HashMap::new())),
+            msize: DEFAULT_MSIZE,
+            max_msize: self.max_msize,
+            version: "9P2000".to_string(),
+            locks: self.locks.clone(),
+            connection: self.connections.fetch_add(1, Ordering::Relaxed),
+            connections: self.connections.clone(),
+            auth: self.auth.clone(),
+            exports: self.exports.clone(),
+            exclusive: self.exclusive.clone(),
+            cancel: None,
         }
     }
 
+    /// Returns a handle for running one request that can be stopped by
+    /// setting `flag`.
+    ///
+    /// Reads and writes of backing files move at most `IO_CHUNK_SIZE` bytes
+    /// at a time and check the flag in between. A read that is stopped
+    /// fails with `Error::Interrupted`. So does a write that is stopped
+    /// before writing anything, while one stopped later returns the shorter
+    /// count.
+    ///
+    /// # Arguments
+    /// * `flag` - Set when the request is flushed.
+    pub fn cancelled_by(&self, flag: Arc<AtomicBool>) -> Self {
+        Self {
+            cancel: Some(flag),
+            ..self.clone()
+        }
+    }
+
+    // Whether the request the handle runs was flushed
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Sets the largest msize offered to clients during version negotiation.
+    ///
+    /// # Arguments
+    /// * `max_msize` - The limit, between `MIN_MSIZE` and `MAX_MSIZE` bytes.
+    ///
+    /// # Returns
+    /// An error if the limit is out of range.
+    pub fn set_max_msize(&mut self, max_msize: u32) -> anyhow::Result<()> {
+        if !(MIN_MSIZE..=MAX_MSIZE).contains(&max_msize) {
+            return Err(anyhow!(
+                "msize must be between {} and {} bytes",
+                MIN_MSIZE,
+                MAX_MSIZE
+            ));
+        }
+        self.max_msize = max_msize;
+        Ok(())
+    }
+
+    /// Requires clients to authenticate before they attach.
+    ///
+    /// # Arguments
+    /// * `scheme` - The scheme run over auth fids, see `modules::auth`.
+    pub fn set_auth(&mut self, scheme: Arc<dyn AuthScheme>) {
+        self.auth = Some(scheme);
+    }
+
+    /// Exports a tree under a name, replacing any tree exported under it.
+    ///
+    /// The tree's path is resolved when a client attaches, so it may refer
+    /// to a directory that is bound later. The empty name is exported by
+    /// default as the whole namespace.
+    ///
+    /// # Arguments
+    /// * `aname` - The name clients attach with.
+    /// * `export` - The tree and who may access it.
+    pub fn export(&self, aname: &str, export: Export) {
+        self.exports
+            .lock()
+            .unwrap()
+            .insert(aname.to_string(), export);
+    }
+
+    /// Stops exporting a tree. Fids already attached to it stay valid.
+    ///
+    /// # Arguments
+    /// * `aname` - The name of the tree.
+    pub fn unexport(&self, aname: &str) {
+        self.exports.lock().unwrap().remove(aname);
+    }
+
+    /// Returns the maximum message size negotiated on this connection.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    // Largest payload that fits in an Rread or Twrite of the negotiated msize
+    fn iounit(&self) -> u32 {
+        self.msize - IOHDRSZ
+    }
+
+    /// Returns the protocol dialect negotiated on this connection.
+    pub fn dialect(&self) -> Dialect {
+        Dialect::from_version(&self.version).unwrap_or(Dialect::Plan9)
+    }
+
     /// Negotiates the version and maximum message size for the 9P protocol.
     ///
     /// # Arguments
@@ -179,96 +876,207 @@ impl NineP {
     ///
     /// # Returns
     /// A tuple containing the negotiated maximum message size and version.
+    /// The msize is the smaller of the requested size and the configured
+    /// maximum; requests below `MIN_MSIZE` are rejected.
     pub fn version(&mut self, requested_version: &str, msize: u32) -> Result<(u32, String)> {
-        self.msize = std::cmp::min(msize, 8192); // Cap at 8K
-        let version = if requested_version == "9P2000" {
-            "9P2000".to_string()
-        } else {
-            "unknown".to_string()
+        // A new version starts a new session, so all fids are released
+        self.clunk_all();
+
+        if msize < MIN_MSIZE {
+            return Err(Error::MsizeTooSmall);
+        }
+        self.msize = std::cmp::min(msize, self.max_msize);
+        let version = match Dialect::from_version(requested_version) {
+            Some(dialect) => dialect.as_str().to_string(),
+            // Unknown extensions of 9P2000 fall back to the base protocol
+            None if requested_version.starts_with("9P2000") => "9P2000".to_string(),
+            None => "unknown".to_string(),
         };
         self.version = version.clone();
         Ok((self.msize, version))
     }
 
-    /// Authenticates a user with the 9P filesystem.
+    /// Opens an auth fid for a user.
+    ///
+    /// The client authenticates by reading and writing the auth fid as the
+    /// configured scheme prescribes, and then passes it to `attach`.
     ///
     /// # Arguments
     /// * `uname` - The username.
-    /// * `aname` - The authentication name.
-    /// * `afid` - The authentication file ID.
+    /// * `aname` - The tree the user wants to attach to.
+    /// * `afid` - The file ID for the auth fid.
     ///
     /// # Returns
-    /// The Qid (unique identifier) of the authenticated user.
+    /// The Qid of the auth fid, or an error if authentication is not required.
     pub fn auth(&mut self, uname: &str, aname: &str, afid: u32) -> Result<Qid> {
-        // For this implementation, we'll return an error as we're not implementing auth
-        Err(anyhow!("Authentication not required"))
+        let scheme = self
+            .auth
+            .as_ref()
+            .ok_or(Error::AuthNotRequired)?;
+
+        let mut fids = self.fids.lock().unwrap();
+        if fids.contains_key(&afid) {
+            return Err(Error::FidInUse);
+        }
+
+        // Auth fids do not refer to a file, so they use a path no inode has
+        let qid = Qid {
+            version: 0,
+            path: 0,
+            file_type: QTAUTH,
+        };
+        let mut fid_state = Fid::new(PathBuf::new(), qid.clone());
+        fid_state.auth = Some(Arc::new(Mutex::new(AuthFid {
+            uname: uname.to_string(),
+            aname: aname.to_string(),
+            conversation: scheme.start(uname),
+        })));
+        fids.insert(afid, fid_state);
+        Ok(qid)
     }
 
-    /// Attaches a file ID (fid) to the root directory of the NineP filesystem.
+    /// Attaches a file ID (fid) to the root of an exported tree.
+    ///
+    /// If authentication is required, `afid` must be an auth fid on which
+    /// `uname` has completed the authentication conversation.
     ///
     /// # Arguments
     /// * `fid` - The file ID to attach.
     /// * `afid` - The authentication file ID (optional).
     /// * `uname` - The username.
-    /// * `aname` - The authentication name.
+    /// * `aname` - The name of the tree, see `NineP::export`.
     ///
     /// # Returns
-    /// The Qid (unique identifier) of the root directory.
+    /// The Qid (unique identifier) of the root of the tree.
     pub fn attach(&mut self, fid: u32, afid: Option<u32>, uname: &str, aname: &str) -> Result<Qid> {
         let mut fids = self.fids.lock().unwrap();
-        fids.insert(fid, PathBuf::from("/"));
+        if fids.contains_key(&fid) {
+            return Err(Error::FidInUse);
+        }
 
-        Ok(Qid {
-            version: 0,
-            path: 1, // Root directory
-            file_type: QTDIR,
-        })
+        if self.auth.is_some() {
+            let afid = afid.ok_or(Error::AuthRequired)?;
+            let auth = fids
+                .get(&afid)
+                .and_then(|afid| afid.auth.clone())
+                .ok_or(Error::BadUse)?;
+            let auth = auth.lock().unwrap();
+            let verified = auth.uname == uname && auth.aname == aname;
+            if !verified || !auth.conversation.authenticated() {
+                return Err(Error::AuthFailed);
+            }
+        }
+
+        let export = self
+            .exports
+            .lock()
+            .unwrap()
+            .get(aname)
+            .cloned()
+            .ok_or(Error::NoSuchTree)?;
+        if let Some(users) = &export.users {
+            if !users.iter().any(|user| user == uname) {
+                return Err(Error::PermissionDenied);
+            }
+        }
+
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let root = lookup_path(&bindings, &export.path).ok_or(Error::NoSuchTree)?;
+        let entry = &bindings[&root].1;
+        if entry.attr.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let qid = entry.qid();
+        let mut fid_state = Fid::new(PathBuf::from("/"), qid.clone());
+        fid_state.root = root;
+        fid_state.read_only = export.read_only;
+        (fid_state.uid, fid_state.gid) = user_ids(uname);
+        fid_state.uname = uname.to_string();
+        fids.insert(fid, fid_state);
+
+        Ok(qid)
     }
 
     /// Walks the file tree, resolving the specified file names.
     ///
+    /// Each name is looked up in the directory reached by the previous one,
+    /// and `..` moves to the parent directory, except at the root of the tree
+    /// the fid was attached to. If the first name cannot be
+    /// resolved the walk fails; if a later one cannot, the qids walked so far
+    /// are returned and `newfid` is left untouched. Walking zero names clones
+    /// `fid` into `newfid`.
+    ///
     /// # Arguments
     /// * `fid` - The file ID to start the walk from.
     /// * `newfid` - The new file ID to associate with the final path.
-    /// * `wnames` - The file names to walk through.
+    /// * `wnames` - The file names to walk through, at most `MAXWELEM`.
     ///
     /// # Returns
     /// A vector of Qids (unique identifiers) for the resolved file names.
     pub fn walk(&mut self, fid: u32, newfid: u32, wnames: &[String]) -> Result<Vec<Qid>> {
+        if wnames.len() > MAXWELEM {
+            return Err(Error::InvalidArgument);
+        }
+
         let mut qids = Vec::new();
-        let fids = self.fids.lock().unwrap();
+        let mut fids = self.fids.lock().unwrap();
 
-        // Get starting path
-        let start_path = fids
-            .get(&fid)
-            .ok_or_else(|| anyhow!("Invalid fid"))?
-            .clone();
+        // Get starting point
+        let start = fids.get(&fid).ok_or(Error::InvalidFid)?;
+        if start.mode.is_some() {
+            return Err(Error::FidOpen);
+        }
+        if start.auth.is_some() {
+            return Err(Error::BadUse);
+        }
+        if newfid != fid && fids.contains_key(&newfid) {
+            return Err(Error::FidInUse);
+        }
 
-        let mut current_path = start_path;
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let mut current_path = start.path.clone();
+        let mut current_qid = start.qid.clone();
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
 
         for name in wnames {
-            current_path.push(name);
-
-            // Find the entry in bindings
-            let mut found = false;
-            for (_, (entry_name, entry)) in bindings.iter() {
-                if entry_name.to_string_lossy() == name.as_str() {
-                    qids.push(Self::qid_from_attr(&entry.attr));
-                    found = true;
-                    break;
-                }
-            }
+            let (_, dir) = bindings
+                .get(&current_qid.path)
+                .ok_or(Error::NotFound)?;
 
-            if !found {
-                return Err(anyhow!("Path not found"));
+            let next = if dir.attr.kind != FileType::Directory {
+                Err(Error::NotADirectory)
+            } else if name == ".." && current_qid.path == start.root {
+                // Clients cannot walk out of the tree they attached to
+                Ok(current_qid.path)
+            } else if name == ".." {
+                Ok(dir.parent)
+            } else {
+                find_child(&bindings, current_qid.path, OsStr::new(name))
+                    .ok_or(Error::NotFound)
+            };
+
+            let inode = match next {
+                Ok(inode) => inode,
+                // Only a failure on the first element is an error
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+
+            if name == ".." {
+                current_path.pop();
+            } else {
+                current_path.push(name);
             }
+            let (_, entry) = bindings.get_mut(&inode).unwrap();
+            entry.refresh();
+            current_qid = entry.qid();
+            qids.push(current_qid.clone());
         }
 
-        // Update newfid with final path if walk was successful
-        if !qids.is_empty() {
-            let mut fids = self.fids.lock().unwrap();
-            fids.insert(newfid, current_path);
+        // Update newfid only if every element was walked
+        if qids.len() == wnames.len() {
+            let walked = start.walked_to(current_path, current_qid);
+            fids.insert(newfid, walked);
         }
 
         Ok(qids)
@@ -276,6 +1084,10 @@ impl NineP {
 
     /// Opens a file in the 9P filesystem.
     ///
+    /// The attaching user needs the permissions `flags` ask for, and write
+    /// permission on the directory to open with `O_RCLOSE`. `O_TRUNC`
+    /// empties the file, and an exclusive-use file can only be open once.
+    ///
     /// # Arguments
     /// * `fid` - The file ID of the file to open.
     /// * `flags` - The file access flags.
@@ -283,29 +1095,78 @@ impl NineP {
     /// # Returns
     /// A tuple containing the Qid (unique identifier) of the opened file and the maximum message size.
     pub fn open(&mut self, fid: u32, flags: OpenFlags) -> Result<(Qid, u32)> {
-        let fids = self.fids.lock().unwrap();
-        let path = fids.get(&fid).ok_or_else(|| anyhow!("Invalid fid"))?;
+        let mut fids = self.fids.lock().unwrap();
+        let fid_state = fids.get_mut(&fid).ok_or(Error::InvalidFid)?;
+        if fid_state.mode.is_some() {
+            return Err(Error::FidOpen);
+        }
+        if fid_state.auth.is_some() {
+            // Some clients open the auth fid before running the conversation
+            fid_state.mode = Some(flags);
+            return Ok((fid_state.qid.clone(), self.msize));
+        }
+        if fid_state.read_only && flags.writes() {
+            return Err(Error::ReadOnly);
+        }
 
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let inode = fid_state.qid.path;
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings
+            .get_mut(&inode)
+            .ok_or(Error::NotFound)?;
+        entry.refresh();
+
+        if entry.attr.kind == FileType::Directory && flags.access() & ACCESS_WRITE != 0 {
+            return Err(Error::IsADirectory);
+        }
+        if !permitted(&fid_state.credentials(), &entry.attr, flags.access()) {
+            return Err(Error::PermissionDenied);
+        }
+        let exclusive = entry.dm_flags & DMEXCL != 0;
+        let parent = entry.parent;
+        if flags.0 & OpenFlags::O_RCLOSE != 0
+            && !permitted(&fid_state.credentials(), &bindings[&parent].1.attr, ACCESS_WRITE)
+        {
+            return Err(Error::PermissionDenied);
+        }
+        if exclusive && !self.exclusive.lock().unwrap().insert(inode) {
+            return Err(Error::InUse);
+        }
 
-        // Find the entry
-        for (_, (entry_name, entry)) in bindings.iter() {
-            if entry_name.to_string_lossy() == path.to_string_lossy() {
-                let qid = Self::qid_from_attr(&entry.attr);
-                return Ok((qid, self.msize));
+        let (_, entry) = bindings.get_mut(&inode).unwrap();
+        if flags.0 & OpenFlags::O_TRUNC != 0 && entry.attr.kind == FileType::RegularFile {
+            if let Some(backing) = &mut entry.backing {
+                attrs_on_disk(&backing.path, None, Some(0), None, None)?;
+                backing.update();
             }
+            entry.set_len(0)?;
+            entry.attr.mtime = SystemTime::now();
+            entry.bump_version();
         }
 
-        Err(anyhow!("File not found"))
+        let qid = entry.qid();
+        fid_state.qid = qid.clone();
+        fid_state.mode = Some(flags);
+        fid_state.exclusive = exclusive;
+        Ok((qid, self.msize))
     }
 
     /// Creates a new file in the 9P filesystem.
     ///
+    /// The attaching user needs write permission on the directory, and owns
+    /// the new file; its group is the group of the directory. As in Plan 9,
+    /// the directory's permissions limit those of the new file: a file
+    /// keeps the execute bits of `perm` and the read and write bits it shares
+    /// with the directory, and a directory keeps the bits it shares with its
+    /// parent.
+    ///
     /// # Arguments
     /// * `fid` - The file ID of the parent directory.
     /// * `name` - The name of the new file.
-    /// * `perm` - The permissions for the new file.
+    /// * `perm` - The permissions for the new file, with `DMDIR` to create a directory.
     /// * `mode` - The file access mode.
+    /// * `extension` - The symlink target or device description of a special
+    ///   file (9P2000.u), e.g. `c 1 3`. Ignored for regular files.
     ///
     /// # Returns
     /// A tuple containing the Qid (unique identifier) of the new file and the maximum message size.
@@ -315,52 +1176,111 @@ impl NineP {
         name: &str,
         perm: u32,
         mode: OpenFlags,
+        extension: &str,
     ) -> Result<(Qid, u32)> {
-        let fids = self.fids.lock().unwrap();
-        let parent_path = fids.get(&fid).ok_or_else(|| anyhow!("Invalid fid"))?;
-
-        let mut new_path = parent_path.clone();
-        new_path.push(name);
-
-        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
-        let mut next_inode = self.namespace_manager.next_inode.lock().unwrap();
+        let mut fids = self.fids.lock().unwrap();
+        let fid_state = fids.get_mut(&fid).ok_or(Error::InvalidFid)?;
+        if fid_state.mode.is_some() {
+            return Err(Error::FidOpen);
+        }
+        if fid_state.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if perm & DMDIR != 0 && mode.access() & ACCESS_WRITE != 0 {
+            return Err(Error::IsADirectory);
+        }
+        let dir_attr = {
+            let bindings = self.namespace_manager.bindings.lock().unwrap();
+            let (_, dir) = bindings
+                .get(&fid_state.qid.path)
+                .ok_or(Error::NotFound)?;
+            if !permitted(&fid_state.credentials(), &dir.attr, ACCESS_WRITE) {
+                return Err(Error::PermissionDenied);
+            }
+            dir.attr
+        };
 
-        let inode = *next_inode;
-        *next_inode += 1;
+        let mut new_path = fid_state.path.clone();
+        new_path.push(name);
 
-        let attr = FileAttr {
-            ino: inode,
-            size: 0,
-            blocks: 0,
-            atime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            ctime: SystemTime::now(),
-            crtime: SystemTime::now(),
-            kind: FileType::RegularFile,
-            perm: perm as u16,
-            nlink: 1,
-            uid: 501,
-            gid: 20,
-            rdev: 0,
-            flags: 0,
-            blksize: 512,
-        };
+        if perm & DMLINK != 0 {
+            let target = extension.trim().parse().map_err(|_| Error::InvalidArgument)?;
+            let inode = fids.get(&target).ok_or(Error::InvalidFid)?.qid.path;
+            let fid_state = fids.get_mut(&fid).unwrap();
+            let qid = self.link_entry(inode, fid_state.qid.path, name)?;
+            fid_state.path = new_path;
+            fid_state.qid = qid.clone();
+            fid_state.mode = Some(mode);
+            return Ok((qid, self.msize));
+        }
 
-        let entry = BoundEntry {
-            attr,
-            content: Some(Vec::new()),
-        };
+        let inherited = if perm & DMDIR != 0 { 0o777 } else { 0o666 };
+        let perm = perm & (!inherited | (dir_attr.perm as u32 & inherited));
+        let (kind, rdev, content) = special_file(perm, extension)?;
+        let mut attr = new_attr(kind, unix_permissions(perm));
+        attr.rdev = rdev;
+        attr.uid = fid_state.uid;
+        attr.gid = dir_attr.gid;
+        let mut entry = BoundEntry::new(attr, fid_state.qid.path, content);
+        entry.dm_flags = perm & (DMAPPEND | DMEXCL);
+        let qid = self.insert_entry(name, entry)?;
+        // Nobody else can have the new file open yet
+        if perm & DMEXCL != 0 {
+            self.exclusive.lock().unwrap().insert(qid.path);
+        }
 
-        bindings.insert(inode, (OsString::from(name), entry));
+        // The fid now represents the new, opened file
+        fid_state.path = new_path;
+        fid_state.qid = qid.clone();
+        fid_state.mode = Some(mode);
+        fid_state.exclusive = perm & DMEXCL != 0;
 
-        Ok((
-            Qid {
-                version: 0,
-                path: inode,
-                file_type: 0,
-            },
-            self.msize,
-        ))
+        Ok((qid, self.msize))
+    }
+
+    // Adds a new entry to its parent directory and returns its qid. The
+    // inode and size of the entry are filled in here. If the directory is
+    // backed by one on disk, the entry is created there as well, or linked
+    // to its backing file if it already has one.
+    fn insert_entry(&self, name: &str, mut entry: BoundEntry) -> Result<Qid> {
+        let parent = entry.parent;
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, dir) = bindings
+            .get(&parent)
+            .ok_or(Error::NotFound)?;
+        if dir.attr.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        if !valid_file_name(name) {
+            return Err(Error::InvalidName);
+        }
+        if find_child(&bindings, parent, OsStr::new(name)).is_some() {
+            return Err(Error::Exists);
+        }
+        if let Some(backing) = &dir.backing {
+            let path = backing.path.join(name);
+            entry.backing = Some(match &entry.backing {
+                // A link to a file bound from disk links to its backing file
+                Some(source) => link_on_disk(&source.path, &path)?,
+                None => create_on_disk(&path, &entry)?,
+            });
+            if entry.attr.kind == FileType::RegularFile {
+                entry.content = None;
+            }
+        }
+
+        let mut next_inode = self.namespace_manager.next_inode.lock().unwrap();
+        entry.attr.ino = *next_inode;
+        *next_inode += 1;
+        entry.attr.size = match &entry.backing {
+            Some(backing) if entry.attr.kind == FileType::RegularFile => backing.len,
+            _ => entry.content.as_ref().map_or(0, |c| c.len() as u64),
+        };
+
+        let qid = entry.qid();
+        bindings.insert(qid.path, (OsString::from(name), entry));
+        bindings.get_mut(&parent).unwrap().1.bump_version();
+        Ok(qid)
     }
 
     /// Reads data from a file in the 9P filesystem.
@@ -368,25 +1288,104 @@ impl NineP {
     /// # Arguments
     /// * `fid` - The file ID of the file to read from.
     /// * `offset` - The offset within the file to start reading from.
-    /// * `count` - The number of bytes to read.
+    /// * `count` - The number of bytes to read, trimmed to the iounit.
     ///
     /// # Returns
     /// The data read from the file.
     pub fn read(&self, fid: u32, offset: u64, count: u32) -> Result<Vec<u8>> {
-        let fids = self.fids.lock().unwrap();
-        let path = fids.get(&fid).ok_or_else(|| anyhow!("Invalid fid"))?;
+        let count = std::cmp::min(count, self.iounit());
+        let mut fids = self.fids.lock().unwrap();
+        let fid_state = fids.get_mut(&fid).ok_or(Error::InvalidFid)?;
+        if let Some(auth) = &fid_state.auth {
+            return auth.lock().unwrap().conversation.read(count);
+        }
+        if let Some(value) = &fid_state.xattr {
+            let start = std::cmp::min(offset, value.len() as u64) as usize;
+            let end = std::cmp::min(start + count as usize, value.len());
+            return Ok(value[start..end].to_vec());
+        }
+        if !fid_state.mode.is_some_and(|mode| mode.can_read()) {
+            return Err(Error::BadUse);
+        }
 
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings
+            .get_mut(&fid_state.qid.path)
+            .ok_or(Error::NotFound)?;
+        entry.refresh();
+
+        if entry.attr.kind == FileType::Directory {
+            let dialect = self.dialect();
+            let data = Self::read_directory(&bindings, fid_state, offset, count, dialect)?;
+            return Ok(data);
+        }
+
+        self.read_in_chunks(entry, offset, count)
+    }
+
+    // Reads from an entry one chunk at a time. If the request is flushed
+    // meanwhile, what was read is dropped, as a read has no effect to
+    // report.
+    fn read_in_chunks(&self, entry: &BoundEntry, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while data.len() < count as usize {
+            if self.cancelled() {
+                return Err(Error::Interrupted);
+            }
+            let len = std::cmp::min(count as usize - data.len(), IO_CHUNK_SIZE);
+            let chunk = entry.read_at(offset + data.len() as u64, len as u32)?;
+            let at_end = chunk.len() < len;
+            data.extend(chunk);
+            if at_end {
+                break;
+            }
+        }
+        if self.cancelled() {
+            return Err(Error::Interrupted);
+        }
+        Ok(data)
+    }
 
-        for (_, (_, entry)) in bindings.iter() {
-            if let Some(ref content) = entry.content {
-                let start = offset as usize;
-                let end = std::cmp::min(start + count as usize, content.len());
-                return Ok(content[start..end].to_vec());
+    // Packs the stats of a directory's entries back to back. Only whole
+    // entries are returned, so a read must start at 0 or where the previous
+    // read on the fid ended. The entries are packed once when a read
+    // starts at 0, and the reads after it continue in that snapshot.
+    fn read_directory(
+        bindings: &Bindings,
+        fid_state: &mut Fid,
+        offset: u64,
+        count: u32,
+        dialect: Dialect,
+    ) -> Result<Vec<u8>> {
+        if offset == 0 {
+            let mut packed = Vec::new();
+            for inode in children(bindings, fid_state.qid.path) {
+                let (name, entry) = &bindings[&inode];
+                packed.extend(encode_stat(&Self::stat_from_entry(name, entry), dialect)?);
+            }
+            fid_state.dir_entries = packed;
+        } else if offset != fid_state.dir_offset {
+            return Err(Error::InvalidArgument);
+        }
+
+        // Each stat starts with its size, not counting the size field
+        let rest = &fid_state.dir_entries[offset as usize..];
+        let mut len = 0;
+        while len < rest.len() {
+            let stat_len = 2 + u16::from_le_bytes([rest[len], rest[len + 1]]) as usize;
+            if len + stat_len > count as usize {
+                // An empty reply would look like the end of the directory
+                if len == 0 {
+                    return Err(Error::InvalidArgument);
+                }
+                break;
             }
+            len += stat_len;
         }
 
-        Err(anyhow!("File not found"))
+        let data = rest[..len].to_vec();
+        fid_state.dir_offset = offset + len as u64;
+        Ok(data)
     }
 
     /// Writes data to a file in the 9P filesystem.
@@ -397,32 +1396,95 @@ impl NineP {
     /// * `data` - The data to write to the file.
     ///
     /// # Returns
-    /// The number of bytes written to the file.
+    /// The number of bytes written to the file. At most one iounit is
+    /// written, so the count may be less than the length of `data`. It is
+    /// also less if the request is flushed midway, see `cancelled_by`.
     pub fn write(&mut self, fid: u32, offset: u64, data: &[u8]) -> Result<u32> {
-        let fids = self.fids.lock().unwrap();
-        let path = fids.get(&fid).ok_or_else(|| anyhow!("Invalid fid"))?;
+        let data = &data[..std::cmp::min(data.len(), self.iounit() as usize)];
+        let mut fids = self.fids.lock().unwrap();
+        let fid_state = fids.get_mut(&fid).ok_or(Error::InvalidFid)?;
+        if let Some(auth) = &fid_state.auth {
+            return auth.lock().unwrap().conversation.write(data);
+        }
+        if let Some(create) = &fid_state.xattr_create {
+            let end = offset.checked_add(data.len() as u64).ok_or(Error::InvalidArgument)?;
+            if end > create.size {
+                return Err(Error::InvalidArgument);
+            }
+            let value = fid_state.xattr.get_or_insert_with(Vec::new);
+            if value.len() < end as usize {
+                value.resize(end as usize, 0);
+            }
+            value[offset as usize..end as usize].copy_from_slice(data);
+            return Ok(data.len() as u32);
+        }
+        if fid_state.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !fid_state.mode.is_some_and(|mode| mode.can_write()) {
+            return Err(Error::BadUse);
+        }
+        self.write_entry(fid_state.qid.path, offset, data)
+    }
 
+    // Writes data to an entry, and to its backing file if it has one. Writes
+    // to an append-only file go to its end, whatever the offset.
+    fn write_entry(&self, inode: u64, offset: u64, data: &[u8]) -> Result<u32> {
         let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings
+            .get_mut(&inode)
+            .ok_or(Error::NotFound)?;
+        if entry.attr.kind == FileType::Directory {
+            return Err(Error::IsADirectory);
+        }
 
-        for (_, (_, entry)) in bindings.iter_mut() {
-            if let Some(ref mut content) = entry.content {
-                let start = offset as usize;
-                let end = start + data.len();
-
-                if end > content.len() {
-                    content.resize(end, 0);
+        let start = if entry.dm_flags & DMAPPEND != 0 {
+            entry.attr.size
+        } else {
+            offset
+        };
+        let end = start
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= entry.max_len())
+            .ok_or(Error::FileTooLarge)?;
+        let written = if let Some(path) = entry.backing_file() {
+            // A flushed write stops between chunks, and reports the ones
+            // it wrote
+            let file = fs::OpenOptions::new().write(true).open(path)?;
+            let mut written = 0;
+            for chunk in data.chunks(IO_CHUNK_SIZE) {
+                if self.cancelled() {
+                    break;
                 }
-
-                content[start..end].copy_from_slice(data);
-                return Ok(data.len() as u32);
+                file.write_all_at(chunk, start + written as u64)?;
+                written += chunk.len();
             }
-        }
-
-        Err(anyhow!("File not found"))
+            if written == 0 && !data.is_empty() {
+                return Err(Error::Interrupted);
+            }
+            let backing = entry.backing.as_mut().unwrap();
+            backing.update();
+            entry.attr.size = backing.len;
+            written
+        } else {
+            let (start, end) = (start as usize, end as usize);
+            let content = entry.content.get_or_insert_with(Vec::new);
+            if end > content.len() {
+                content.resize(end, 0);
+            }
+            content[start..end].copy_from_slice(data);
+            entry.attr.size = content.len() as u64;
+            data.len()
+        };
+        entry.attr.mtime = SystemTime::now();
+        entry.bump_version();
+        Ok(written as u32)
     }
 
     /// Closes a file in the 9P filesystem.
     ///
+    /// A file opened with `O_RCLOSE` is removed.
+    ///
     /// # Arguments
     /// * `fid` - The file ID of the file to close.
     ///
@@ -430,15 +1492,66 @@ impl NineP {
     /// An empty result indicating the success of the operation.
     pub fn clunk(&mut self, fid: u32) -> Result<()> {
         let mut fids = self.fids.lock().unwrap();
-        if fids.remove(&fid).is_some() {
-            Ok(())
-        } else {
-            Err(anyhow!("Invalid fid"))
+        let fid_state = fids.remove(&fid).ok_or(Error::InvalidFid)?;
+        self.release_fid(fid, &fid_state);
+        if let Some(create) = &fid_state.xattr_create {
+            let value = fid_state.xattr.as_deref().unwrap_or_default();
+            return self.finish_xattr_create(fid_state.qid.path, create, value);
+        }
+
+        let remove_on_close = fid_state
+            .mode
+            .is_some_and(|mode| mode.0 & OpenFlags::O_RCLOSE != 0);
+        if remove_on_close {
+            // The clunk succeeds even if the file cannot be removed
+            let inode = fid_state.qid.path;
+            let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+            let removable = bindings.contains_key(&inode)
+                && inode != fid_state.root
+                && children(&bindings, inode).is_empty();
+            if removable {
+                if let Err(e) = detach_entry(&mut bindings, inode) {
+                    warn!("Failed to remove file on close: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Gives up exclusive use of the file of a fid that is going away, and
+    // the locks taken through it
+    fn release_fid(&self, fid: u32, fid_state: &Fid) {
+        let inode = fid_state.qid.path;
+        if fid_state.exclusive {
+            self.exclusive.lock().unwrap().remove(&inode);
+        }
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(held) = locks.get_mut(&inode) {
+            held.retain(|other| (other.connection, other.fid) != (self.connection, fid));
+            if held.is_empty() {
+                locks.remove(&inode);
+            }
+        }
+    }
+
+    /// Closes every fid of the connection.
+    ///
+    /// Called when a client disconnects or renegotiates the version, so that
+    /// no fid outlives the session it belongs to.
+    pub fn clunk_all(&mut self) {
+        let fids: Vec<u32> = self.fids.lock().unwrap().keys().copied().collect();
+        for fid in fids {
+            if let Err(e) = self.clunk(fid) {
+                warn!("Failed to clunk fid {}: {}", fid, e);
+            }
         }
     }
 
     /// Removes a file from the 9P filesystem.
     ///
+    /// The attaching user needs write permission on the directory. A file
+    /// backed by one on disk is removed there too.
+    ///
     /// # Arguments
     /// * `fid` - The file ID of the file to remove.
     ///
@@ -446,25 +1559,29 @@ impl NineP {
     /// An empty result indicating the success of the operation.
     pub fn remove(&mut self, fid: u32) -> Result<()> {
         let mut fids = self.fids.lock().unwrap();
-        let path = fids.remove(&fid).ok_or_else(|| anyhow!("Invalid fid"))?;
+        // The fid is clunked even if the remove fails
+        let fid_state = fids.remove(&fid).ok_or(Error::InvalidFid)?;
+        self.release_fid(fid, &fid_state);
+        if fid_state.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let inode = fid_state.qid.path;
 
         let mut bindings = self.namespace_manager.bindings.lock().unwrap();
-
-        // Find and remove the entry
-        let mut found_inode = None;
-        for (inode, (entry_name, _)) in bindings.iter() {
-            if entry_name.to_string_lossy() == path.to_string_lossy() {
-                found_inode = Some(*inode);
-                break;
-            }
+        if inode == ROOT_INODE || inode == fid_state.root {
+            return Err(Error::PermissionDenied);
         }
-
-        if let Some(inode) = found_inode {
-            bindings.remove(&inode);
-            Ok(())
-        } else {
-            Err(anyhow!("File not found"))
+        let (_, entry) = bindings
+            .get(&inode)
+            .ok_or(Error::NotFound)?;
+        let parent_attr = &bindings[&entry.parent].1.attr;
+        if !permitted(&fid_state.credentials(), parent_attr, ACCESS_WRITE) {
+            return Err(Error::PermissionDenied);
         }
+        if !children(&bindings, inode).is_empty() {
+            return Err(Error::NotEmpty);
+        }
+        detach_entry(&mut bindings, inode)
     }
 
     /// Retrieves the attributes of a file or directory in the 9P filesystem.
@@ -476,44 +1593,87 @@ impl NineP {
     /// The file or directory attributes as a `Stat` struct.
     pub fn stat(&self, fid: u32) -> Result<Stat> {
         let fids = self.fids.lock().unwrap();
-        let path = fids.get(&fid).ok_or_else(|| anyhow!("Invalid fid"))?;
+        let inode = fids.get(&fid).ok_or(Error::InvalidFid)?.qid.path;
 
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (entry_name, entry) = bindings
+            .get_mut(&inode)
+            .ok_or(Error::NotFound)?;
+        entry.refresh();
 
-        for (_, (entry_name, entry)) in bindings.iter() {
-            if entry_name.to_string_lossy() == path.to_string_lossy() {
-                return Ok(Stat {
-                    size: 0, // Will be filled by protocol
-                    typ: 0,
-                    dev: 0,
-                    qid: Self::qid_from_attr(&entry.attr),
-                    mode: entry.attr.perm as u32,
-                    atime: entry
-                        .attr
-                        .atime
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as u32,
-                    mtime: entry
-                        .attr
-                        .mtime
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs() as u32,
-                    length: entry.attr.size,
-                    name: entry_name.to_string_lossy().to_string(),
-                    uid: "user".to_string(),
-                    gid: "user".to_string(),
-                    muid: "user".to_string(),
-                });
+        Ok(Self::stat_from_entry(entry_name, entry))
+    }
+
+    // Builds the 9P stat of a bound entry, including the 9P2000.u fields
+    fn stat_from_entry(name: &OsStr, entry: &BoundEntry) -> Stat {
+        let attr = &entry.attr;
+        let mut mode = (attr.perm & 0o777) as u32 | entry.dm_flags;
+        if attr.perm & 0o4000 != 0 {
+            mode |= DMSETUID;
+        }
+        if attr.perm & 0o2000 != 0 {
+            mode |= DMSETGID;
+        }
+
+        let mut extension = String::new();
+        match attr.kind {
+            FileType::Directory => mode |= DMDIR,
+            FileType::Symlink => {
+                mode |= DMSYMLINK;
+                if let Some(target) = &entry.content {
+                    extension = String::from_utf8_lossy(target).to_string();
+                }
             }
+            FileType::BlockDevice | FileType::CharDevice => {
+                mode |= DMDEVICE;
+                let (major, minor) = decode_dev(attr.rdev);
+                let typ = if attr.kind == FileType::BlockDevice { 'b' } else { 'c' };
+                extension = format!("{} {} {}", typ, major, minor);
+            }
+            FileType::NamedPipe => mode |= DMNAMEDPIPE,
+            FileType::Socket => mode |= DMSOCKET,
+            FileType::RegularFile => {}
         }
 
-        Err(anyhow!("File not found"))
+        Stat {
+            size: 0, // Will be filled by protocol
+            typ: 0,
+            dev: 0,
+            qid: entry.qid(),
+            mode,
+            atime: stat_time(attr.atime),
+            mtime: stat_time(attr.mtime),
+            // Directories have no length in 9P
+            length: if attr.kind == FileType::Directory {
+                0
+            } else {
+                attr.size
+            },
+            name: name.to_string_lossy().to_string(),
+            uid: user_name(attr.uid),
+            gid: group_name(attr.gid),
+            muid: user_name(attr.uid),
+            extension,
+            n_uid: attr.uid,
+            n_gid: attr.gid,
+            n_muid: attr.uid,
+        }
     }
 
     /// Modifies the attributes of a file or directory in the 9P filesystem.
     ///
+    /// Fields holding their "don't touch" value, `~0` for numbers and the
+    /// empty string for strings, are left as they are, so a stat made of
+    /// such fields only changes nothing. Either every requested change is
+    /// made or none is.
+    ///
+    /// Renaming needs write permission on the directory and changing the
+    /// length write permission on the file. The mode, mtime and group can
+    /// only be changed by the owner, and the group only to one the owner
+    /// belongs to. The type, device, qid, atime and muid cannot be changed.
+    /// Files backed by one on disk are renamed, truncated and have their
+    /// mode and mtime changed there too.
+    ///
     /// # Arguments
     /// * `fid` - The file ID of the file or directory to modify.
     /// * `stat` - The new attributes to apply.
@@ -522,307 +1682,3620 @@ impl NineP {
     /// An empty result indicating the success of the operation.
     pub fn wstat(&mut self, fid: u32, stat: &Stat) -> Result<()> {
         let fids = self.fids.lock().unwrap();
-        let path = fids.get(&fid).ok_or_else(|| anyhow!("Invalid fid"))?;
+        let fid_state = fids.get(&fid).ok_or(Error::InvalidFid)?;
+        if fid_state.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let inode = fid_state.qid.path;
 
         let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (name, entry) = bindings
+            .get(&inode)
+            .ok_or(Error::NotFound)?;
+        let attr = entry.attr;
+        let parent = entry.parent;
+        let is_dir = attr.kind == FileType::Directory;
+        let cred = fid_state.credentials();
+        let is_owner = cred.owns(&attr);
 
-        for (_, (_, entry)) in bindings.iter_mut() {
-            let mut attr = entry.attr;
-            attr.perm = stat.mode as u16;
-            // Update other attributes as needed
-            entry.attr = attr;
-            return Ok(());
+        // Every change is checked before any is made. The type, device, qid,
+        // atime and muid are the server's to keep, and can only be written
+        // with their don't touch or current value.
+        let current = Self::stat_from_entry(name, entry);
+        let fixed_changed = (stat.typ != u16::MAX && stat.typ != current.typ)
+            || (stat.dev != u32::MAX && stat.dev != current.dev)
+            || (stat.qid.file_type != u8::MAX && stat.qid.file_type != current.qid.file_type)
+            || (stat.qid.version != u32::MAX && stat.qid.version != current.qid.version)
+            || (stat.qid.path != u64::MAX && stat.qid.path != current.qid.path)
+            || (stat.atime != u32::MAX && stat.atime != current.atime)
+            || (!stat.muid.is_empty() && stat.muid != current.muid)
+            || (stat.n_muid != NONUNAME && stat.n_muid != current.n_muid);
+        if fixed_changed {
+            return Err(Error::InvalidArgument);
         }
 
-        Err(anyhow!("File not found"))
-    }
-
-    /// Flushes a pending operation in the 9P filesystem.
-    ///
-    /// # Arguments
-    /// * `oldtag` - The tag of the operation to flush.
-    ///
-    /// # Returns
-    /// An empty result indicating the success of the operation.
-    pub fn flush(&mut self, oldtag: u16) -> Result<()> {
-        // In this implementation, we don't queue operations, so flush is a no-op
-        Ok(())
-    }
-}
-
-impl Filesystem for NineP {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        println!("Lookup for parent: {}, name: {:?}", parent, name);
-
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
-        println!("Current bindings: {:?}", bindings.keys());
-
-        for (inode, (entry_name, entry)) in bindings.iter() {
-            println!(
-                "Comparing entry name: {:?} with lookup name: {:?}",
-                entry_name, name
-            );
-
-            println!(
-                "inode: {}, name: {:?}, kind: {:?}",
-                inode, entry_name, entry.attr.kind
-            );
-
-            // Only check files in the root directory for now
-            if parent != 1 {
-                println!("Skipping non-root parent: {}", parent);
-                continue;
+        let new_name = Some(stat.name.as_str()).filter(|new| !new.is_empty() && *name != **new);
+        if let Some(new_name) = new_name {
+            if inode == ROOT_INODE || inode == fid_state.root {
+                return Err(Error::PermissionDenied);
             }
-
-            // Compare the actual filename without any path components
-            let entry_filename = Path::new(entry_name)
-                .file_name()
-                .unwrap_or_else(|| entry_name.as_os_str());
-
-            if entry_filename == name {
-                println!("Found match for {:?}", name);
-                reply.entry(&TTL, &entry.attr, 0);
-                return;
+            if !valid_file_name(new_name) {
+                return Err(Error::InvalidName);
+            }
+            if !permitted(&cred, &bindings[&parent].1.attr, ACCESS_WRITE) {
+                return Err(Error::PermissionDenied);
+            }
+            if find_child(&bindings, parent, OsStr::new(new_name)).is_some() {
+                return Err(Error::Exists);
+            }
+            // Nor may the rename replace a file on disk
+            let target = backing_move(&bindings, inode, parent, OsStr::new(new_name));
+            if target.is_some_and(|(_, to)| fs::symlink_metadata(to).is_ok()) {
+                return Err(Error::Exists);
             }
         }
 
-        println!("No match found for {:?}", name);
-        reply.error(ENOENT);
-    }
-
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
-        if let Some((_, entry)) = bindings.get(&ino) {
-            reply.attr(&TTL, &entry.attr);
-        } else {
-            reply.error(ENOENT);
+        let mode = Some(stat.mode).filter(|mode| *mode != u32::MAX);
+        if mode.is_some_and(|mode| (mode & DMDIR != 0) != is_dir) {
+            return Err(Error::InvalidArgument);
+        }
+        let mtime = Some(stat.mtime)
+            .filter(|mtime| *mtime != u32::MAX)
+            .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime as u64));
+        if (mode.is_some() || mtime.is_some()) && !is_owner {
+            return Err(Error::PermissionDenied);
         }
-    }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        _size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
-        if let Some((_, entry)) = bindings.get(&ino) {
-            if let Some(ref content) = entry.content {
-                reply.data(&content[offset as usize..]);
-            } else {
-                reply.error(ENOENT);
+        // Directories have a length of 0, which is no change
+        let current_length = if is_dir { 0 } else { attr.size };
+        let length =
+            Some(stat.length).filter(|length| *length != u64::MAX && *length != current_length);
+        if length.is_some() {
+            if is_dir {
+                return Err(Error::IsADirectory);
             }
+            if !permitted(&cred, &attr, ACCESS_WRITE) {
+                return Err(Error::PermissionDenied);
+            }
+            if length.is_some_and(|length| length > entry.max_len()) {
+                return Err(Error::FileTooLarge);
+            }
+            self.check_exclusive(inode, fid_state.exclusive)?;
+        }
+
+        let gid = if !stat.gid.is_empty() {
+            Some(group_id(&stat.gid).ok_or(Error::InvalidArgument)?)
         } else {
-            reply.error(ENOENT);
+            Some(stat.n_gid).filter(|gid| *gid != NONUNAME)
+        };
+        let gid = gid.filter(|gid| *gid != attr.gid);
+        if let Some(gid) = gid {
+            if !(cred.is_root() || is_owner && cred.in_group(gid)) {
+                return Err(Error::PermissionDenied);
+            }
         }
-    }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        let bindings = self.namespace_manager.bindings.lock().unwrap();
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
+        // The owner cannot be changed
+        let uid_changed = (!stat.uid.is_empty() && stat.uid != user_name(attr.uid))
+            || (stat.n_uid != NONUNAME && stat.n_uid != attr.uid);
+        if uid_changed {
+            return Err(Error::PermissionDenied);
         }
 
-        let mut entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-        ];
+        let changed = new_name.is_some()
+            || mode.is_some()
+            || mtime.is_some()
+            || length.is_some()
+            || gid.is_some();
+        if !changed {
+            return Ok(());
+        }
 
-        for (inode, (entry_name, entry)) in bindings.iter() {
-            if entry.attr.ino != ino {
-                continue;
+        // The backing file is changed first, so that a failure leaves the
+        // namespace untouched. The changes that can be undone are made
+        // before truncation, which cannot, and undone if a later one fails.
+        let old_name = name.clone();
+        if let Some(new_name) = new_name {
+            move_backing(&mut bindings, inode, parent, OsStr::new(new_name))?;
+        }
+        let backing_path = bindings[&inode].1.backing.as_ref().map(|b| b.path.clone());
+        if let Some(path) = backing_path.filter(|_| attr.kind != FileType::Symlink) {
+            let perm = mode.map(unix_permissions);
+            let changed = attrs_on_disk(&path, perm, None, None, None).and_then(|()| {
+                attrs_on_disk(&path, None, length, None, mtime).inspect_err(|_| {
+                    if perm.is_some() {
+                        let _ = attrs_on_disk(&path, Some(attr.perm), None, None, None);
+                    }
+                })
+            });
+            if let Err(e) = changed {
+                if new_name.is_some() {
+                    if let Err(e) = move_backing(&mut bindings, inode, parent, &old_name) {
+                        warn!("Failed to undo rename of {:?}: {}", path, e);
+                    }
+                }
+                return Err(e);
             }
-            entries.push((*inode, entry.attr.kind, entry_name.to_str().unwrap()));
         }
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
-            }
+        if let Some(new_name) = new_name {
+            bindings.rename(inode, parent, OsString::from(new_name));
         }
-        reply.ok();
+        let (_, entry) = bindings.get_mut(&inode).unwrap();
+        if let Some(mode) = mode {
+            entry.attr.perm = unix_permissions(mode);
+            entry.dm_flags = mode & (DMAPPEND | DMEXCL);
+        }
+        if let Some(length) = length {
+            entry.set_len(length)?;
+        }
+        if let Some(mtime) = mtime {
+            entry.attr.mtime = mtime;
+        }
+        if let Some(gid) = gid {
+            entry.attr.gid = gid;
+        }
+        entry.attr.ctime = SystemTime::now();
+        entry.bump_version();
+        if let Some(backing) = &mut entry.backing {
+            backing.update();
+        }
+        if new_name.is_some() {
+            bindings.get_mut(&parent).unwrap().1.bump_version();
+        }
+        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use fuser::FileAttr;
-    use tempfile::tempdir;
-
-    // Helper function to create a test filesystem
-    fn setup_test_fs() -> Result<NineP> {
-        let temp_dir = tempdir()?;
-        NineP::new(temp_dir.path().to_path_buf())
+// 9P2000.L operations. They work on the same namespace as the 9P2000
+// requests above, with Linux flags, modes and error semantics.
+impl NineP {
+    /// Opens a fid with Linux open flags.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the file to open.
+    /// * `flags` - Linux open flags, such as `O_RDWR | O_TRUNC`.
+    ///
+    /// # Returns
+    /// A tuple containing the Qid of the opened file and the maximum message size.
+    pub fn lopen(&mut self, fid: u32, flags: u32) -> Result<(Qid, u32)> {
+        self.open(fid, open_flags_from_linux(flags))
     }
 
-    // Helper function to create a test file entry
-    fn create_test_file_entry(
-        ino: u64,
+    /// Creates and opens a regular file.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the parent directory, which then represents the new file.
+    /// * `name` - The name of the new file.
+    /// * `flags` - Linux open flags.
+    /// * `mode` - The permission bits of the new file.
+    /// * `gid` - The group id of the new file.
+    ///
+    /// # Returns
+    /// A tuple containing the Qid of the new file and the maximum message size.
+    pub fn lcreate(
+        &mut self,
+        fid: u32,
         name: &str,
-        content: Option<Vec<u8>>,
-    ) -> (OsString, BoundEntry) {
-        let attr = FileAttr {
-            ino,
-            size: content.as_ref().map_or(0, |c| c.len() as u64),
-            blocks: 1,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
-            kind: FileType::RegularFile,
-            perm: 0o644,
-            nlink: 1,
-            uid: 1000,
-            gid: 1000,
-            rdev: 0,
-            flags: 0,
-            blksize: 512,
-        };
-
-        (OsString::from(name), BoundEntry { attr, content })
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> Result<(Qid, u32)> {
+        let (dir, cred) = self.writable_fid(fid)?;
+        self.check_create(&cred, dir, Some(gid))?;
+        let perm = nine_p_permissions(mode);
+        let (qid, msize) = self.create(fid, name, perm, open_flags_from_linux(flags), "")?;
+        self.update_attr(qid.path, |attr| attr.gid = gid)?;
+        Ok((qid, msize))
     }
 
-    #[test]
-    fn test_create_filesystem() -> Result<()> {
-        let fs = setup_test_fs()?;
-        let bindings = fs.namespace_manager.bindings.lock().unwrap();
+    /// Creates a symbolic link.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the parent directory.
+    /// * `name` - The name of the link.
+    /// * `target` - The target of the link.
+    /// * `gid` - The group id of the link.
+    ///
+    /// # Returns
+    /// The Qid of the new link.
+    pub fn symlink(&mut self, fid: u32, name: &str, target: &str, gid: u32) -> Result<Qid> {
+        let (parent, cred) = self.writable_fid(fid)?;
+        self.check_create(&cred, parent, Some(gid))?;
+        let mut attr = new_attr(FileType::Symlink, 0o777);
+        attr.uid = cred.uid;
+        attr.gid = gid;
+        let content = Some(target.as_bytes().to_vec());
+        self.insert_entry(name, BoundEntry::new(attr, parent, content))
+    }
+
+    /// Creates a device node, named pipe, socket or regular file.
+    ///
+    /// # Arguments
+    /// * `dfid` - The file ID of the parent directory.
+    /// * `name` - The name of the node.
+    /// * `mode` - The file type and permission bits, as in `st_mode`.
+    /// * `major` - The major device number.
+    /// * `minor` - The minor device number.
+    /// * `gid` - The group id of the node.
+    ///
+    /// # Returns
+    /// The Qid of the new node.
+    pub fn mknod(
+        &mut self,
+        dfid: u32,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<Qid> {
+        let (parent, cred) = self.writable_fid(dfid)?;
+        self.check_create(&cred, parent, Some(gid))?;
+        let kind = node_kind(mode)?;
+        let mut attr = new_attr(kind, (mode & 0o7777) as u16);
+        attr.uid = cred.uid;
+        attr.gid = gid;
+        if matches!(kind, FileType::CharDevice | FileType::BlockDevice) {
+            attr.rdev = encode_dev(major, minor);
+        }
+        let content = (kind == FileType::RegularFile).then(Vec::new);
+        self.insert_entry(name, BoundEntry::new(attr, parent, content))
+    }
+
+    /// Creates a directory.
+    ///
+    /// # Arguments
+    /// * `dfid` - The file ID of the parent directory.
+    /// * `name` - The name of the new directory.
+    /// * `mode` - The permission bits of the new directory.
+    /// * `gid` - The group id of the new directory.
+    ///
+    /// # Returns
+    /// The Qid of the new directory.
+    pub fn mkdir(&mut self, dfid: u32, name: &str, mode: u32, gid: u32) -> Result<Qid> {
+        let (parent, cred) = self.writable_fid(dfid)?;
+        self.check_create(&cred, parent, Some(gid))?;
+        let mut attr = new_attr(FileType::Directory, (mode & 0o7777) as u16);
+        attr.uid = cred.uid;
+        attr.gid = gid;
+        self.insert_entry(name, BoundEntry::new(attr, parent, None))
+    }
+
+    /// Reads the target of a symbolic link.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the link.
+    ///
+    /// # Returns
+    /// The target of the link.
+    pub fn readlink(&self, fid: u32) -> Result<String> {
+        let target = self.link_target(self.fid_inode(fid)?)?;
+        Ok(String::from_utf8_lossy(&target).to_string())
+    }
+
+    /// Retrieves the Linux attributes of a fid.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID to query.
+    /// * `request_mask` - The requested fields. All basic fields are always returned.
+    ///
+    /// # Returns
+    /// The attributes of the file.
+    pub fn getattr(&self, fid: u32, request_mask: u64) -> Result<Getattr> {
+        let inode = self.fid_inode(fid)?;
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings
+            .get_mut(&inode)
+            .ok_or(Error::NotFound)?;
+        entry.refresh();
+
+        let attr = &entry.attr;
+        let (atime_sec, atime_nsec) = timespec(attr.atime);
+        let (mtime_sec, mtime_nsec) = timespec(attr.mtime);
+        let (ctime_sec, ctime_nsec) = timespec(attr.ctime);
+        let (btime_sec, btime_nsec) = timespec(attr.crtime);
+        Ok(Getattr {
+            valid: GETATTR_BASIC,
+            qid: entry.qid(),
+            mode: file_type_mode(attr.kind) | attr.perm as u32,
+            uid: attr.uid,
+            gid: attr.gid,
+            nlink: attr.nlink as u64,
+            rdev: attr.rdev as u64,
+            size: attr.size,
+            blksize: attr.blksize as u64,
+            blocks: attr.size.div_ceil(512),
+            atime_sec,
+            atime_nsec,
+            mtime_sec,
+            mtime_nsec,
+            ctime_sec,
+            ctime_nsec,
+            btime_sec,
+            btime_nsec,
+            gen: 0,
+            data_version: entry.version as u64,
+        })
+    }
+
+    /// Changes the Linux attributes of a fid.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID to modify.
+    /// * `setattr` - The attributes to change, selected by `setattr.valid`.
+    ///
+    /// # Returns
+    /// An empty result indicating the success of the operation.
+    pub fn setattr(&mut self, fid: u32, setattr: &Setattr) -> Result<()> {
+        let (inode, cred) = self.writable_fid(fid)?;
+        if setattr.valid & SETATTR_SIZE != 0 {
+            let holder = self.fids.lock().unwrap().get(&fid).is_some_and(|f| f.exclusive);
+            self.check_exclusive(inode, holder)?;
+        }
+        self.apply_setattr(&cred, inode, setattr)
+    }
+
+    // Changes the attributes of an entry, and of its backing file if it has
+    // one, as selected by `setattr.valid`. As with chmod, chown, truncate
+    // and utimes, only the owner may change the mode, group or explicit
+    // times, only root the owner, and the size needs write permission.
+    fn apply_setattr(&self, cred: &Credentials, inode: u64, setattr: &Setattr) -> Result<()> {
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings
+            .get_mut(&inode)
+            .ok_or(Error::NotFound)?;
+
+        let valid = setattr.valid;
+        let size = Some(setattr.size).filter(|_| valid & SETATTR_SIZE != 0);
+        if size.is_some() && entry.attr.kind == FileType::Directory {
+            return Err(Error::IsADirectory);
+        }
+        if size.is_some_and(|size| size > entry.max_len()) {
+            return Err(Error::FileTooLarge);
+        }
+        let perm = Some((setattr.mode & 0o7777) as u16).filter(|_| valid & SETATTR_MODE != 0);
+        let uid = Some(setattr.uid).filter(|_| valid & SETATTR_UID != 0);
+        let gid = Some(setattr.gid).filter(|_| valid & SETATTR_GID != 0);
+        let now = SystemTime::now();
+        let time = |flag: u32, set_flag: u32, sec: u64, nsec: u64| {
+            if valid & flag == 0 {
+                Ok(None)
+            } else if valid & set_flag != 0 {
+                system_time(sec, nsec).map(Some)
+            } else {
+                Ok(Some(now))
+            }
+        };
+        let atime = time(
+            SETATTR_ATIME,
+            SETATTR_ATIME_SET,
+            setattr.atime_sec,
+            setattr.atime_nsec,
+        )?;
+        let mtime = time(
+            SETATTR_MTIME,
+            SETATTR_MTIME_SET,
+            setattr.mtime_sec,
+            setattr.mtime_nsec,
+        )?;
+
+        let attr = &entry.attr;
+        let is_owner = cred.owns(attr);
+        let explicit_times = valid & (SETATTR_ATIME_SET | SETATTR_MTIME_SET) != 0;
+        if (perm.is_some() || explicit_times) && !is_owner {
+            return Err(Error::NotOwner);
+        }
+        if uid.is_some_and(|uid| uid != attr.uid) && !cred.is_root() {
+            return Err(Error::NotOwner);
+        }
+        let foreign_group = gid.is_some_and(|gid| gid != attr.gid && !cred.in_group(gid));
+        if gid.is_some() && !cred.is_root() && (!is_owner || foreign_group) {
+            return Err(Error::NotOwner);
+        }
+        // Setting the times to now only needs write permission
+        let writable = permitted(cred, attr, ACCESS_WRITE);
+        let touched = atime.is_some() || mtime.is_some();
+        if (size.is_some() || (touched && !is_owner)) && !writable {
+            return Err(Error::PermissionDenied);
+        }
+
+        // The backing file is changed first, so that a failure leaves the
+        // entry untouched
+        if let Some(backing) = &entry.backing {
+            if entry.attr.kind != FileType::Symlink {
+                attrs_on_disk(&backing.path, perm, size, atime, mtime)?;
+            }
+            if uid.is_some() || gid.is_some() {
+                lchown(&backing.path, uid, gid)?;
+            }
+        }
+
+        if let Some(size) = size {
+            entry.set_len(size)?;
+        }
+        let attr = &mut entry.attr;
+        if let Some(perm) = perm {
+            attr.perm = perm;
+        }
+        if let Some(uid) = uid {
+            attr.uid = uid;
+        }
+        if let Some(gid) = gid {
+            attr.gid = gid;
+        }
+        if let Some(atime) = atime {
+            attr.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            attr.mtime = mtime;
+        }
+        attr.ctime = now;
+        entry.bump_version();
+        if let Some(backing) = &mut entry.backing {
+            backing.update();
+        }
+        Ok(())
+    }
+
+    /// Reads the entries of a directory, including `.` and `..`.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the directory.
+    /// * `offset` - 0, or the offset of the last entry returned by the previous read.
+    /// * `count` - The maximum number of bytes to return, trimmed to the iounit.
+    ///
+    /// # Returns
+    /// The entries, packed as described in `codec::encode_dirent`.
+    pub fn readdir(&self, fid: u32, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let count = std::cmp::min(count, self.iounit());
+        let (inode, root) = {
+            let fids = self.fids.lock().unwrap();
+            let fid_state = fids.get(&fid).ok_or(Error::InvalidFid)?;
+            if !fid_state.mode.is_some_and(|mode| mode.can_read()) {
+                return Err(Error::BadUse);
+            }
+            (fid_state.qid.path, fid_state.root)
+        };
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, dir) = bindings
+            .get(&inode)
+            .ok_or(Error::NotFound)?;
+        if dir.attr.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        // The root of the tree is its own parent
+        let parent = if inode == root { inode } else { dir.parent };
+        let mut entries = vec![(inode, ".".to_string()), (parent, "..".to_string())];
+        for child in children(&bindings, inode) {
+            let name = bindings[&child].0.to_string_lossy().to_string();
+            entries.push((child, name));
+        }
+
+        // The offset of an entry is its position in the listing plus one
+        let mut data = Vec::new();
+        for (index, (entry_inode, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let entry = &bindings[entry_inode].1;
+            let dirent = encode_dirent(
+                &entry.qid(),
+                index as u64 + 1,
+                dirent_type(entry.attr.kind),
+                name,
+            )?;
+            if data.len() + dirent.len() > count as usize {
+                break;
+            }
+            data.extend_from_slice(&dirent);
+        }
+        Ok(data)
+    }
+
+    /// Returns statistics about the filesystem.
+    ///
+    /// # Arguments
+    /// * `fid` - Any file ID on the filesystem.
+    ///
+    /// # Returns
+    /// The filesystem statistics.
+    pub fn statfs(&self, fid: u32) -> Result<Statfs> {
+        self.fid_inode(fid)?;
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let blocks = bindings
+            .values()
+            .map(|(_, entry)| entry.attr.size.div_ceil(BLOCK_SIZE))
+            .sum();
+        Ok(Statfs {
+            typ: V9FS_MAGIC,
+            bsize: BLOCK_SIZE as u32,
+            blocks,
+            bfree: 0,
+            bavail: 0,
+            files: bindings.len() as u64,
+            ffree: u32::MAX as u64,
+            fsid: 0,
+            namelen: 255,
+        })
+    }
+
+    /// Flushes a file to stable storage.
+    ///
+    /// Only the backing file of an entry bound from disk is flushed; other
+    /// entries are held in memory.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the file.
+    ///
+    /// # Returns
+    /// An empty result indicating the success of the operation.
+    pub fn fsync(&self, fid: u32) -> Result<()> {
+        let inode = self.fid_inode(fid)?;
+        self.sync_entry(inode)
+    }
+
+    // Flushes the backing file of an entry to disk
+    fn sync_entry(&self, inode: u64) -> Result<()> {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings.get(&inode).ok_or(Error::NotFound)?;
+        let syncable = matches!(entry.attr.kind, FileType::RegularFile | FileType::Directory);
+        if let Some(backing) = entry.backing.as_ref().filter(|_| syncable) {
+            fs::File::open(&backing.path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Acquires or releases a POSIX byte-range lock.
+    ///
+    /// Locks are advisory and shared by all connections. Locking or
+    /// unlocking a range replaces the owner's locks within it, and keeps
+    /// the parts of them outside of it. Locks are released when the fid
+    /// they were taken through is clunked.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the locked file.
+    /// * `lock` - The lock to acquire, or the range to release.
+    ///
+    /// # Returns
+    /// `LOCK_SUCCESS`, or `LOCK_BLOCKED` if another owner holds a conflicting lock.
+    pub fn lock(&mut self, fid: u32, lock: &Flock) -> Result<u8> {
+        let inode = self.fid_inode(fid)?;
+        let wanted = HeldLock {
+            lock: lock.clone(),
+            connection: self.connection,
+            fid,
+        };
+        let mut locks = self.locks.lock().unwrap();
+        let held = locks.entry(inode).or_default();
+
+        if lock.typ != LOCK_TYPE_UNLCK && held.iter().any(|other| locks_conflict(other, &wanted)) {
+            return Ok(LOCK_BLOCKED);
+        }
+
+        let mut kept = Vec::with_capacity(held.len() + 2);
+        for other in held.drain(..) {
+            if same_owner(&other, &wanted) && ranges_overlap(&other.lock, lock) {
+                kept.extend(outside_range(&other, lock));
+            } else {
+                kept.push(other);
+            }
+        }
+        if lock.typ != LOCK_TYPE_UNLCK {
+            kept.push(wanted);
+        }
+        if kept.is_empty() {
+            locks.remove(&inode);
+        } else {
+            *held = kept;
+        }
+        Ok(LOCK_SUCCESS)
+    }
+
+    /// Tests whether a POSIX byte-range lock could be acquired.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the file.
+    /// * `lock` - The lock to test.
+    ///
+    /// # Returns
+    /// The first conflicting lock, or `lock` with type unlock if there is none.
+    pub fn getlock(&self, fid: u32, lock: &Flock) -> Result<Flock> {
+        let inode = self.fid_inode(fid)?;
+        let wanted = HeldLock {
+            lock: lock.clone(),
+            connection: self.connection,
+            fid,
+        };
+        let locks = self.locks.lock().unwrap();
+        let conflict = locks
+            .get(&inode)
+            .and_then(|held| held.iter().find(|other| locks_conflict(other, &wanted)));
+
+        Ok(match conflict {
+            Some(other) => Flock {
+                flags: 0,
+                ..other.lock.clone()
+            },
+            None => Flock {
+                typ: LOCK_TYPE_UNLCK,
+                flags: 0,
+                ..lock.clone()
+            },
+        })
+    }
+
+    /// Creates a hard link.
+    ///
+    /// The link is an entry of its own in the namespace. A regular file can
+    /// only be linked to if it is bound from disk and the link goes to a
+    /// backed directory, so that both entries share the same backing file.
+    ///
+    /// # Arguments
+    /// * `dfid` - The file ID of the directory to create the link in.
+    /// * `fid` - The file ID of the file to link to.
+    /// * `name` - The name of the link.
+    ///
+    /// # Returns
+    /// An empty result indicating the success of the operation.
+    pub fn link(&mut self, dfid: u32, fid: u32, name: &str) -> Result<()> {
+        let (dir, cred) = self.writable_fid(dfid)?;
+        let inode = self.fid_inode(fid)?;
+        self.check_create(&cred, dir, None)?;
+        self.link_entry(inode, dir, name)?;
+        Ok(())
+    }
+
+    // Adds entry `name` to directory `dir` as a hard link to entry `inode`
+    // and returns its qid
+    fn link_entry(&self, inode: u64, dir: u64, name: &str) -> Result<Qid> {
+        let mut link = {
+            let bindings = self.namespace_manager.bindings.lock().unwrap();
+            let (_, entry) = bindings.get(&inode).ok_or(Error::NotFound)?;
+            let (_, dir_entry) = bindings.get(&dir).ok_or(Error::NotFound)?;
+            if entry.attr.kind == FileType::Directory {
+                return Err(Error::IsADirectory);
+            }
+            // Content held in memory cannot be shared between two entries
+            let shared = entry.backing_file().is_some() && dir_entry.backing.is_some();
+            if entry.attr.kind == FileType::RegularFile && !shared {
+                return Err(Error::NotSupported);
+            }
+            let mut link = entry.clone();
+            link.parent = dir;
+            link.version = 0;
+            if dir_entry.backing.is_none() {
+                link.backing = None;
+            }
+            link
+        };
+        link.attr.nlink += 1;
+        let nlink = link.attr.nlink;
+        let qid = self.insert_entry(name, link)?;
+        self.update_attr(inode, |attr| attr.nlink = nlink)?;
+        Ok(qid)
+    }
+
+    /// Renames or moves a directory entry.
+    ///
+    /// An existing entry at the new name is replaced, unless it is a
+    /// non-empty directory.
+    ///
+    /// # Arguments
+    /// * `olddirfid` - The file ID of the directory containing the entry.
+    /// * `oldname` - The current name of the entry.
+    /// * `newdirfid` - The file ID of the directory to move the entry to.
+    /// * `newname` - The new name of the entry.
+    ///
+    /// # Returns
+    /// An empty result indicating the success of the operation.
+    pub fn renameat(
+        &mut self,
+        olddirfid: u32,
+        oldname: &str,
+        newdirfid: u32,
+        newname: &str,
+    ) -> Result<()> {
+        let (old_dir, cred) = self.writable_fid(olddirfid)?;
+        let (new_dir, _) = self.writable_fid(newdirfid)?;
+        self.rename_child(&cred, old_dir, oldname, new_dir, newname, true)
+    }
+
+    // Moves entry `oldname` of directory `old_dir` to `newname` in `new_dir`.
+    // An existing entry there is replaced if `replace` is set, unless it is
+    // a non-empty directory. The user needs write permission on both
+    // directories.
+    fn rename_child(
+        &self,
+        cred: &Credentials,
+        old_dir: u64,
+        oldname: &str,
+        new_dir: u64,
+        newname: &str,
+        replace: bool,
+    ) -> Result<()> {
+        if !valid_file_name(newname) {
+            return Err(Error::InvalidName);
+        }
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+
+        let inode = find_child(&bindings, old_dir, OsStr::new(oldname))
+            .ok_or(Error::NotFound)?;
+        match bindings.get(&new_dir) {
+            Some((_, dir)) if dir.attr.kind == FileType::Directory => {}
+            Some(_) => return Err(Error::NotADirectory),
+            None => return Err(Error::NotFound),
+        }
+        for dir in [old_dir, new_dir] {
+            if !permitted(cred, &bindings[&dir].1.attr, ACCESS_WRITE) {
+                return Err(Error::PermissionDenied);
+            }
+        }
+        // A directory cannot be moved into itself
+        let mut ancestor = new_dir;
+        while ancestor != ROOT_INODE {
+            if ancestor == inode {
+                return Err(Error::InvalidArgument);
+            }
+            ancestor = bindings[&ancestor].1.parent;
+        }
+
+        if let Some(existing) = find_child(&bindings, new_dir, OsStr::new(newname)) {
+            if existing == inode {
+                return Ok(());
+            }
+            if !replace {
+                return Err(Error::Exists);
+            }
+            let is_dir = |ino: u64| bindings[&ino].1.attr.kind == FileType::Directory;
+            if is_dir(inode) != is_dir(existing) {
+                return Err(if is_dir(existing) {
+                    Error::IsADirectory
+                } else {
+                    Error::NotADirectory
+                });
+            }
+            if !children(&bindings, existing).is_empty() {
+                return Err(Error::NotEmpty);
+            }
+        }
+
+        // Renaming on disk replaces the existing file there too
+        move_backing(&mut bindings, inode, new_dir, OsStr::new(newname))?;
+        if let Some(existing) = find_child(&bindings, new_dir, OsStr::new(newname)) {
+            bindings.remove(&existing);
+        }
+
+        bindings.rename(inode, new_dir, OsString::from(newname));
+        let (_, entry) = bindings.get_mut(&inode).unwrap();
+        entry.attr.ctime = SystemTime::now();
+        entry.bump_version();
+        for dir in [old_dir, new_dir] {
+            bindings.get_mut(&dir).unwrap().1.bump_version();
+        }
+        Ok(())
+    }
+
+    /// Removes a directory entry.
+    ///
+    /// # Arguments
+    /// * `dirfd` - The file ID of the directory containing the entry.
+    /// * `name` - The name of the entry.
+    /// * `flags` - `AT_REMOVEDIR` to remove a directory, 0 otherwise.
+    ///
+    /// # Returns
+    /// An empty result indicating the success of the operation.
+    pub fn unlinkat(&mut self, dirfd: u32, name: &str, flags: u32) -> Result<()> {
+        let (dir, cred) = self.writable_fid(dirfd)?;
+        self.remove_child(&cred, dir, name, flags & AT_REMOVEDIR != 0)
+    }
+
+    // Removes entry `name` of directory `dir`, which has to be an empty
+    // directory if `remove_dir` is set and anything else otherwise. The user
+    // needs write permission on the directory.
+    fn remove_child(
+        &self,
+        cred: &Credentials,
+        dir: u64,
+        name: &str,
+        remove_dir: bool,
+    ) -> Result<()> {
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let inode =
+            find_child(&bindings, dir, OsStr::new(name)).ok_or(Error::NotFound)?;
+        if !permitted(cred, &bindings[&dir].1.attr, ACCESS_WRITE) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let is_dir = bindings[&inode].1.attr.kind == FileType::Directory;
+        if remove_dir {
+            if !is_dir {
+                return Err(Error::NotADirectory);
+            }
+            if !children(&bindings, inode).is_empty() {
+                return Err(Error::NotEmpty);
+            }
+        } else if is_dir {
+            return Err(Error::IsADirectory);
+        }
+        detach_entry(&mut bindings, inode)
+    }
+
+    /// Walks a fid to one or all of the extended attributes of a file.
+    ///
+    /// The attributes are those of the backing file of the entry. Entries
+    /// that are not bound from disk have none. As on Linux, reading a user
+    /// attribute needs read permission on the file, and trusted and
+    /// security attributes are only seen by root.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the file.
+    /// * `newfid` - The new file ID from which the attribute value can be read.
+    /// * `name` - The attribute name, or empty to list all attribute names.
+    ///
+    /// # Returns
+    /// The length of the attribute value.
+    pub fn xattrwalk(&mut self, fid: u32, newfid: u32, name: &str) -> Result<u64> {
+        let mut fids = self.fids.lock().unwrap();
+        let start = fids.get(&fid).ok_or(Error::InvalidFid)?;
+        if newfid != fid && fids.contains_key(&newfid) {
+            return Err(Error::FidInUse);
+        }
+        let cred = start.credentials();
+        let value = if name.is_empty() {
+            self.entry_xattrs(&cred, start.qid.path)?
+        } else {
+            let name = OsStr::new(name);
+            self.check_xattr(&cred, start.qid.path, name, ACCESS_READ)?;
+            self.entry_xattr(start.qid.path, name)?
+        };
+
+        let size = value.len() as u64;
+        let mut attr_fid = start.walked_to(start.path.clone(), start.qid.clone());
+        attr_fid.xattr = Some(value);
+        fids.insert(newfid, attr_fid);
+        Ok(size)
+    }
+
+    /// Prepares a fid for setting an extended attribute.
+    ///
+    /// The value is written through the fid and set on the backing file of
+    /// the entry when the fid is clunked. A value of length 0 removes the
+    /// attribute instead.
+    ///
+    /// # Arguments
+    /// * `fid` - The file ID of the file.
+    /// * `name` - The attribute name.
+    /// * `attr_size` - The length of the value that will be written.
+    /// * `flags` - Linux setxattr flags.
+    ///
+    /// # Returns
+    /// An empty result indicating the success of the operation.
+    pub fn xattrcreate(&mut self, fid: u32, name: &str, attr_size: u64, flags: u32) -> Result<()> {
+        let mut fids = self.fids.lock().unwrap();
+        let fid_state = fids.get_mut(&fid).ok_or(Error::InvalidFid)?;
+        if fid_state.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if fid_state.mode.is_some() || fid_state.xattr.is_some() {
+            return Err(Error::FidOpen);
+        }
+        if name.is_empty() || attr_size > XATTR_SIZE_MAX {
+            return Err(Error::InvalidArgument);
+        }
+        let inode = fid_state.qid.path;
+        self.check_xattr(&fid_state.credentials(), inode, OsStr::new(name), ACCESS_WRITE)?;
+        self.xattr_path(inode)?.ok_or(Error::NotSupported)?;
+
+        fid_state.xattr = Some(Vec::new());
+        fid_state.xattr_create = Some(XattrCreate {
+            name: name.to_string(),
+            size: attr_size,
+            flags,
+        });
+        Ok(())
+    }
+
+    // Sets the attribute prepared by Txattrcreate once its value was written
+    fn finish_xattr_create(&self, inode: u64, create: &XattrCreate, value: &[u8]) -> Result<()> {
+        if value.len() as u64 != create.size {
+            return Err(Error::InvalidArgument);
+        }
+        let name = OsStr::new(&create.name);
+        if value.is_empty() {
+            self.remove_entry_xattr(inode, name)
+        } else {
+            self.set_entry_xattr(inode, name, value, create.flags as i32)
+        }
+    }
+
+    // Path extended attributes of entry `inode` are kept on, if it is
+    // bound from disk
+    fn xattr_path(&self, inode: u64) -> Result<Option<PathBuf>> {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings.get(&inode).ok_or(Error::NotFound)?;
+        Ok(entry.backing.as_ref().map(|backing| backing.path.clone()))
+    }
+
+    // Checks that a user may read, or with `access` ACCESS_WRITE change or
+    // remove, extended attribute `name` of entry `inode`. The namespaces
+    // follow the rules of Linux: user attributes go by the permissions of
+    // the file, trusted and security attributes belong to root except that
+    // anyone may read the SELinux label, and other namespaces are not
+    // supported.
+    fn check_xattr(&self, cred: &Credentials, inode: u64, name: &OsStr, access: u16) -> Result<()> {
+        let name = name.as_bytes();
+        let namespace = ["user.", "trusted.", "security."]
+            .into_iter()
+            .find(|namespace| name.starts_with(namespace.as_bytes()))
+            .ok_or(Error::NotSupported)?;
+        if name.len() == namespace.len() {
+            return Err(Error::InvalidArgument);
+        }
+
+        if namespace == "user." {
+            if !permitted(cred, &self.entry_attr(inode)?, access) {
+                return Err(Error::PermissionDenied);
+            }
+            return Ok(());
+        }
+        let readable_by_anyone = access == ACCESS_READ && name == b"security.selinux";
+        if cred.is_root() || readable_by_anyone {
+            Ok(())
+        } else if access == ACCESS_READ {
+            // Root-only attributes are hidden from other users
+            Err(Error::NoAttribute)
+        } else {
+            Err(Error::NotOwner)
+        }
+    }
+
+    // Value of extended attribute `name` of entry `inode`
+    fn entry_xattr(&self, inode: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let path = self.xattr_path(inode)?.ok_or(Error::NoAttribute)?;
+        get_xattr(&path, name)
+    }
+
+    // Names of the extended attributes of entry `inode` a user may see,
+    // each ending in a null byte. Only root sees trusted attributes.
+    fn entry_xattrs(&self, cred: &Credentials, inode: u64) -> Result<Vec<u8>> {
+        let names = match self.xattr_path(inode)? {
+            Some(path) => list_xattrs(&path)?,
+            None => return Ok(Vec::new()),
+        };
+        if cred.is_root() {
+            return Ok(names);
+        }
+        Ok(names
+            .split_inclusive(|&byte| byte == 0)
+            .filter(|name| !name.starts_with(b"trusted."))
+            .flatten()
+            .copied()
+            .collect())
+    }
+
+    fn set_entry_xattr(&self, inode: u64, name: &OsStr, value: &[u8], flags: i32) -> Result<()> {
+        let path = self.xattr_path(inode)?.ok_or(Error::NotSupported)?;
+        set_xattr(&path, name, value, flags)
+    }
+
+    fn remove_entry_xattr(&self, inode: u64, name: &OsStr) -> Result<()> {
+        let path = self.xattr_path(inode)?.ok_or(Error::NoAttribute)?;
+        remove_xattr(&path, name)
+    }
+
+    // Inode of the file a fid refers to
+    fn fid_inode(&self, fid: u32) -> Result<u64> {
+        let fids = self.fids.lock().unwrap();
+        Ok(fids.get(&fid).ok_or(Error::InvalidFid)?.qid.path)
+    }
+
+    // Inode of the file a fid refers to and the user it acts as, if the
+    // fid's tree may be modified
+    fn writable_fid(&self, fid: u32) -> Result<(u64, Credentials)> {
+        let fids = self.fids.lock().unwrap();
+        let fid_state = fids.get(&fid).ok_or(Error::InvalidFid)?;
+        if fid_state.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok((fid_state.qid.path, fid_state.credentials()))
+    }
+
+    // Checks that a user may create an entry in directory `dir`, and give
+    // it group `gid` if one is requested. The group has to be one of the
+    // user's or, as in a setgid directory, that of the directory.
+    fn check_create(&self, cred: &Credentials, dir: u64, gid: Option<u32>) -> Result<()> {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, dir) = bindings.get(&dir).ok_or(Error::NotFound)?;
+        if !permitted(cred, &dir.attr, ACCESS_WRITE) {
+            return Err(Error::PermissionDenied);
+        }
+        let foreign_group =
+            gid.is_some_and(|gid| !cred.is_root() && gid != dir.attr.gid && !cred.in_group(gid));
+        if foreign_group {
+            return Err(Error::NotOwner);
+        }
+        Ok(())
+    }
+
+    fn update_attr(&self, inode: u64, update: impl FnOnce(&mut FileAttr)) -> Result<()> {
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings
+            .get_mut(&inode)
+            .ok_or(Error::NotFound)?;
+        update(&mut entry.attr);
+        Ok(())
+    }
+
+    // Attributes of the entry `name` of directory `parent`, for FUSE lookup
+    fn lookup_child(&self, parent: u64, name: &OsStr) -> Result<FileAttr> {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, dir) = bindings.get(&parent).ok_or(Error::NotFound)?;
+        if dir.attr.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let inode = match name.as_bytes() {
+            b"." => parent,
+            b".." => dir.parent,
+            _ => find_child(&bindings, parent, name).ok_or(Error::NotFound)?,
+        };
+        Ok(bindings[&inode].1.attr)
+    }
+
+    // Entries of directory `inode` for FUSE readdir, starting with `.` and `..`
+    fn dir_entries(&self, inode: u64) -> Result<Vec<(u64, FileType, OsString)>> {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, dir) = bindings.get(&inode).ok_or(Error::NotFound)?;
+        if dir.attr.kind != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let mut entries = vec![
+            (inode, FileType::Directory, OsString::from(".")),
+            (dir.parent, FileType::Directory, OsString::from("..")),
+        ];
+        for child in children(&bindings, inode) {
+            let (name, entry) = &bindings[&child];
+            entries.push((child, entry.attr.kind, name.clone()));
+        }
+        Ok(entries)
+    }
+
+    // Reads up to `count` bytes of file `inode` from `offset` for FUSE.
+    // Reads at or past the end of the file return no data.
+    fn read_entry(&self, inode: u64, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings.get_mut(&inode).ok_or(Error::NotFound)?;
+        if entry.attr.kind == FileType::Directory {
+            return Err(Error::IsADirectory);
+        }
+        entry.refresh();
+        entry.read_at(offset, count)
+    }
+
+    // Attributes of entry `inode`, for FUSE replies
+    fn entry_attr(&self, inode: u64) -> Result<FileAttr> {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings.get(&inode).ok_or(Error::NotFound)?;
+        Ok(entry.attr)
+    }
+
+    // Target of symlink `inode`
+    fn link_target(&self, inode: u64) -> Result<Vec<u8>> {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings.get(&inode).ok_or(Error::NotFound)?;
+        if entry.attr.kind != FileType::Symlink {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(entry.content.clone().unwrap_or_default())
+    }
+
+    // Creates entry `name` in directory `parent` for FUSE. Like other new
+    // entries, it goes to the backing directory of `parent` if that has one.
+    fn create_child(
+        &self,
+        cred: &Credentials,
+        parent: u64,
+        name: &OsStr,
+        attr: FileAttr,
+    ) -> Result<FileAttr> {
+        let name = name.to_str().ok_or(Error::InvalidName)?;
+        self.check_create(cred, parent, None)?;
+        let content = (attr.kind == FileType::RegularFile).then(Vec::new);
+        let qid = self.insert_entry(name, BoundEntry::new(attr, parent, content))?;
+        self.entry_attr(qid.path)
+    }
+
+    // Opens a file for FUSE with the checks of a 9P open, and returns the
+    // file handle that records the mode and any exclusive use
+    fn open_entry(&self, cred: &Credentials, inode: u64, flags: OpenFlags) -> Result<u64> {
+        let mut bindings = self.namespace_manager.bindings.lock().unwrap();
+        let (_, entry) = bindings.get_mut(&inode).ok_or(Error::NotFound)?;
+        entry.refresh();
+        if entry.attr.kind == FileType::Directory && flags.access() & ACCESS_WRITE != 0 {
+            return Err(Error::IsADirectory);
+        }
+        if !permitted(cred, &entry.attr, flags.access()) {
+            return Err(Error::PermissionDenied);
+        }
+        let mut fh = flags.0 as u64;
+        if entry.dm_flags & DMEXCL != 0 {
+            if !self.exclusive.lock().unwrap().insert(inode) {
+                return Err(Error::InUse);
+            }
+            fh |= FH_EXCLUSIVE;
+        }
+        Ok(fh)
+    }
+
+    // Refuses to change the content of an exclusive-use file that someone
+    // other than `holder` has open
+    fn check_exclusive(&self, inode: u64, holder: bool) -> Result<()> {
+        if !holder && self.exclusive.lock().unwrap().contains(&inode) {
+            return Err(Error::InUse);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for NineP {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("FUSE lookup of {:?} in {}", name, parent);
+        match self.lookup_child(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let bindings = self.namespace_manager.bindings.lock().unwrap();
+        if let Some((_, entry)) = bindings.get(&ino) {
+            reply.attr(&TTL, &entry.attr);
+        } else {
+            reply.error(Error::NotFound.errno());
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Ok(offset) = u64::try_from(offset) else {
+            return reply.error(Error::InvalidArgument.errno());
+        };
+        if !OpenFlags(fh as u32).can_read() {
+            return reply.error(Error::BadUse.errno());
+        }
+        match self.read_entry(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let flags = open_flags_from_linux(flags as u32);
+        match self.open_entry(&Credentials::of_request(req), ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        if fh & FH_EXCLUSIVE != 0 {
+            self.exclusive.lock().unwrap().remove(&ino);
+        }
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.dir_entries(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e.errno()),
+        };
+
+        for (i, (inode, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(inode, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let Ok(offset) = u64::try_from(offset) else {
+            return reply.error(Error::InvalidArgument.errno());
+        };
+        if !OpenFlags(fh as u32).can_write() {
+            return reply.error(Error::BadUse.errno());
+        }
+        match self
+            .check_exclusive(ino, fh & FH_EXCLUSIVE != 0)
+            .and_then(|()| self.write_entry(ino, offset, data))
+        {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!("FUSE create of {:?} in {}", name, parent);
+        let attr = request_attr(req, FileType::RegularFile, mode & !umask, 0);
+        match self.create_child(&Credentials::of_request(req), parent, name, attr) {
+            Ok(attr) => {
+                let fh = open_flags_from_linux(flags as u32).0 as u64;
+                reply.created(&TTL, &attr, 0, fh, flags as u32)
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("FUSE mknod of {:?} in {}", name, parent);
+        let created = node_kind(mode).and_then(|kind| {
+            let attr = request_attr(req, kind, mode & !umask, rdev);
+            self.create_child(&Credentials::of_request(req), parent, name, attr)
+        });
+        match created {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("FUSE mkdir of {:?} in {}", name, parent);
+        let attr = request_attr(req, FileType::Directory, mode & !umask, 0);
+        match self.create_child(&Credentials::of_request(req), parent, name, attr) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        debug!("FUSE readlink of {}", ino);
+        match self.link_target(ino) {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!("FUSE symlink of {:?} in {} to {:?}", link_name, parent, target);
+        let created = link_name.to_str().ok_or(Error::InvalidName).and_then(|name| {
+            self.check_create(&Credentials::of_request(req), parent, None)?;
+            let attr = request_attr(req, FileType::Symlink, 0o777, 0);
+            let content = Some(target.as_os_str().as_bytes().to_vec());
+            let qid = self.insert_entry(name, BoundEntry::new(attr, parent, content))?;
+            self.entry_attr(qid.path)
+        });
+        match created {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!("FUSE link of {} as {:?} in {}", ino, newname, newparent);
+        let linked = newname.to_str().ok_or(Error::InvalidName).and_then(|name| {
+            self.check_create(&Credentials::of_request(req), newparent, None)?;
+            let qid = self.link_entry(ino, newparent, name)?;
+            self.entry_attr(qid.path)
+        });
+        match linked {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("FUSE unlink of {:?} in {}", name, parent);
+        let cred = Credentials::of_request(req);
+        let removed = name
+            .to_str()
+            .ok_or(Error::NotFound)
+            .and_then(|name| self.remove_child(&cred, parent, name, false));
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("FUSE rmdir of {:?} in {}", name, parent);
+        let cred = Credentials::of_request(req);
+        let removed = name
+            .to_str()
+            .ok_or(Error::NotFound)
+            .and_then(|name| self.remove_child(&cred, parent, name, true));
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("FUSE rename of {:?} in {} to {:?} in {}", name, parent, newname, newparent);
+        // Entries cannot be swapped atomically
+        if flags & !RENAME_NOREPLACE != 0 {
+            return reply.error(Error::NotSupported.errno());
+        }
+        let (Some(name), Some(newname)) = (name.to_str(), newname.to_str()) else {
+            return reply.error(Error::InvalidName.errno());
+        };
+        let replace = flags & RENAME_NOREPLACE == 0;
+        let cred = Credentials::of_request(req);
+        match self.rename_child(&cred, parent, name, newparent, newname, replace) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // The change is made the way a 9P2000.L Tsetattr would make it
+        let mut setattr = Setattr::default();
+        if let Some(mode) = mode {
+            setattr.valid |= SETATTR_MODE;
+            setattr.mode = mode;
+        }
+        if let Some(uid) = uid {
+            setattr.valid |= SETATTR_UID;
+            setattr.uid = uid;
+        }
+        if let Some(gid) = gid {
+            setattr.valid |= SETATTR_GID;
+            setattr.gid = gid;
+        }
+        if let Some(size) = size {
+            setattr.valid |= SETATTR_SIZE;
+            setattr.size = size;
+        }
+        if let Some(atime) = atime {
+            setattr.valid |= SETATTR_ATIME;
+            if let TimeOrNow::SpecificTime(time) = atime {
+                setattr.valid |= SETATTR_ATIME_SET;
+                (setattr.atime_sec, setattr.atime_nsec) = timespec(time);
+            }
+        }
+        if let Some(mtime) = mtime {
+            setattr.valid |= SETATTR_MTIME;
+            if let TimeOrNow::SpecificTime(time) = mtime {
+                setattr.valid |= SETATTR_MTIME_SET;
+                (setattr.mtime_sec, setattr.mtime_nsec) = timespec(time);
+            }
+        }
+
+        let cred = Credentials::of_request(req);
+        let holder = fh.is_some_and(|fh| fh & FH_EXCLUSIVE != 0);
+        let checked = match size {
+            Some(_) => self.check_exclusive(ino, holder),
+            None => Ok(()),
+        };
+        match checked
+            .and_then(|()| self.apply_setattr(&cred, ino, &setattr))
+            .and_then(|()| self.entry_attr(ino))
+        {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("FUSE setxattr of {:?} on {}", name, ino);
+        let cred = Credentials::of_request(req);
+        match self
+            .check_xattr(&cred, ino, name, ACCESS_WRITE)
+            .and_then(|()| self.set_entry_xattr(ino, name, value, flags))
+        {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("FUSE getxattr of {:?} on {}", name, ino);
+        let cred = Credentials::of_request(req);
+        match self
+            .check_xattr(&cred, ino, name, ACCESS_READ)
+            .and_then(|()| self.entry_xattr(ino, name))
+        {
+            Ok(value) => reply_xattr(reply, &value, size),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("FUSE listxattr of {}", ino);
+        match self.entry_xattrs(&Credentials::of_request(req), ino) {
+            Ok(names) => reply_xattr(reply, &names, size),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("FUSE removexattr of {:?} on {}", name, ino);
+        let cred = Credentials::of_request(req);
+        match self
+            .check_xattr(&cred, ino, name, ACCESS_WRITE)
+            .and_then(|()| self.remove_entry_xattr(ino, name))
+        {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.sync_entry(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+}
+
+// Determines the type, device number and content of a new file from its
+// 9P2000.u mode bits and extension string
+fn special_file(perm: u32, extension: &str) -> Result<(FileType, u32, Option<Vec<u8>>)> {
+    if perm & DMDIR != 0 {
+        return Ok((FileType::Directory, 0, None));
+    }
+    if perm & DMSYMLINK != 0 {
+        return Ok((FileType::Symlink, 0, Some(extension.as_bytes().to_vec())));
+    }
+    if perm & DMDEVICE != 0 {
+        let fields: Vec<&str> = extension.split_whitespace().collect();
+        let [typ, major, minor] = fields[..] else {
+            return Err(Error::InvalidArgument);
+        };
+        let kind = match typ {
+            "b" => FileType::BlockDevice,
+            "c" => FileType::CharDevice,
+            _ => return Err(Error::InvalidArgument),
+        };
+        let major = major.parse().map_err(|_| Error::InvalidArgument)?;
+        let minor = minor.parse().map_err(|_| Error::InvalidArgument)?;
+        return Ok((kind, encode_dev(major, minor), Some(Vec::new())));
+    }
+    if perm & DMNAMEDPIPE != 0 {
+        return Ok((FileType::NamedPipe, 0, Some(Vec::new())));
+    }
+    if perm & DMSOCKET != 0 {
+        return Ok((FileType::Socket, 0, Some(Vec::new())));
+    }
+    Ok((FileType::RegularFile, 0, Some(Vec::new())))
+}
+
+// Whether a name can be given to a directory entry
+fn valid_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+// Applies a new mode, length and times to a file on disk. The mode is
+// changed last, as it may take away the permission to write.
+fn attrs_on_disk(
+    path: &Path,
+    perm: Option<u16>,
+    length: Option<u64>,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+) -> Result<()> {
+    if length.is_some() || atime.is_some() || mtime.is_some() {
+        let file = fs::OpenOptions::new()
+            .read(length.is_none())
+            .write(length.is_some())
+            .open(path)?;
+        if let Some(length) = length {
+            file.set_len(length)?;
+        }
+        let mut times = FileTimes::new();
+        if let Some(atime) = atime {
+            times = times.set_accessed(atime);
+        }
+        if let Some(mtime) = mtime {
+            times = times.set_modified(mtime);
+        }
+        file.set_times(times)?;
+    }
+    if let Some(perm) = perm {
+        fs::set_permissions(path, fs::Permissions::from_mode(perm as u32))?;
+    }
+    Ok(())
+}
+
+// Replies to a FUSE getxattr or listxattr with the length of the value if
+// the caller asked for it with a size of 0, or with the value if it fits
+fn reply_xattr(reply: ReplyXattr, value: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+// The extended attribute functions below act on symlinks themselves rather
+// than on their targets, like the rest of the backing file handling.
+
+// C string of a path or attribute name for the xattr system calls
+fn c_string(s: &OsStr) -> Result<CString> {
+    CString::new(s.as_bytes()).map_err(|_| Error::InvalidArgument)
+}
+
+// Reads a value of unknown length through `read`, which is called with an
+// empty buffer first to learn the length. The value may grow in between.
+fn read_xattr_value(read: impl Fn(*mut libc::c_void, usize) -> isize) -> Result<Vec<u8>> {
+    loop {
+        let len = read(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut value = vec![0u8; len as usize];
+        let len = read(value.as_mut_ptr() as *mut libc::c_void, value.len());
+        if len >= 0 {
+            value.truncate(len as usize);
+            return Ok(value);
+        }
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) {
+            return Err(error.into());
+        }
+    }
+}
+
+// Value of extended attribute `name` of a file on disk
+fn get_xattr(path: &Path, name: &OsStr) -> Result<Vec<u8>> {
+    let (path, name) = (c_string(path.as_os_str())?, c_string(name)?);
+    read_xattr_value(|buf, len| unsafe {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, len)
+    })
+}
+
+// Null-terminated names of the extended attributes of a file on disk
+fn list_xattrs(path: &Path) -> Result<Vec<u8>> {
+    let path = c_string(path.as_os_str())?;
+    read_xattr_value(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf as *mut _, len) })
+}
+
+fn set_xattr(path: &Path, name: &OsStr, value: &[u8], flags: i32) -> Result<()> {
+    let (path, name) = (c_string(path.as_os_str())?, c_string(name)?);
+    let value_ptr = value.as_ptr() as *const libc::c_void;
+    let result = unsafe {
+        libc::lsetxattr(path.as_ptr(), name.as_ptr(), value_ptr, value.len(), flags)
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn remove_xattr(path: &Path, name: &OsStr) -> Result<()> {
+    let (path, name) = (c_string(path.as_os_str())?, c_string(name)?);
+    if unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Removes an entry from its directory, and its backing file from disk.
+// Directories have to be empty.
+fn detach_entry(bindings: &mut Bindings, inode: u64) -> Result<()> {
+    let (_, entry) = bindings.get(&inode).ok_or(Error::NotFound)?;
+    if let Some(backing) = &entry.backing {
+        let removed = if entry.attr.kind == FileType::Directory {
+            fs::remove_dir(&backing.path)
+        } else {
+            fs::remove_file(&backing.path)
+        };
+        // An entry outlives its backing file
+        match removed {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    let (_, entry) = bindings.remove(&inode).unwrap();
+    if let Some((_, dir)) = bindings.get_mut(&entry.parent) {
+        dir.bump_version();
+    }
+    Ok(())
+}
+
+// Paths the backing file of an entry moves from and to when it is renamed
+// to `name` in `new_parent`, if both are backed and the path changes
+fn backing_move(
+    bindings: &Bindings,
+    inode: u64,
+    new_parent: u64,
+    name: &OsStr,
+) -> Option<(PathBuf, PathBuf)> {
+    let backing_path = |ino: u64| bindings[&ino].1.backing.as_ref().map(|b| b.path.clone());
+    let from = backing_path(inode)?;
+    let to = backing_path(new_parent)?.join(name);
+    (from != to).then_some((from, to))
+}
+
+// Moves the backing file of an entry into the backing directory of
+// `new_parent`, and with it the backing paths of the entries below it.
+// Nothing is moved on disk unless both are backed.
+fn move_backing(
+    bindings: &mut Bindings,
+    inode: u64,
+    new_parent: u64,
+    name: &OsStr,
+) -> Result<()> {
+    let Some((from, to)) = backing_move(bindings, inode, new_parent, name) else {
+        return Ok(());
+    };
+
+    fs::rename(&from, &to)?;
+    for (_, entry) in bindings.values_mut() {
+        let Some(backing) = &mut entry.backing else {
+            continue;
+        };
+        if let Ok(rest) = backing.path.strip_prefix(&from) {
+            backing.path = if rest.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(rest)
+            };
+        }
+    }
+    Ok(())
+}
+
+// Creates a new entry on disk and returns its backing
+fn create_on_disk(path: &Path, entry: &BoundEntry) -> Result<Backing> {
+    let attr = &entry.attr;
+    let content = entry.content.as_deref().unwrap_or_default();
+    let node = |kind: SFlag| {
+        let (major, minor) = decode_dev(attr.rdev);
+        let dev = makedev(major as u64, minor as u64);
+        mknod(path, kind, Mode::from_bits_truncate(attr.perm as _), dev)
+            .map_err(std::io::Error::from)
+    };
+    match attr.kind {
+        FileType::Directory => fs::create_dir(path)?,
+        FileType::RegularFile => fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?
+            .write_all(content)?,
+        FileType::Symlink => symlink(OsStr::from_bytes(content), path)?,
+        FileType::NamedPipe => node(SFlag::S_IFIFO)?,
+        FileType::Socket => node(SFlag::S_IFSOCK)?,
+        FileType::CharDevice => node(SFlag::S_IFCHR)?,
+        FileType::BlockDevice => node(SFlag::S_IFBLK)?,
+    }
+    // The permissions are set apart so that the umask does not apply
+    if attr.kind != FileType::Symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(attr.perm as u32))?;
+    }
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(Backing::new(path.to_path_buf(), &metadata))
+}
+
+// Links `path` to the backing file `source` of an entry and returns the
+// backing of the link
+fn link_on_disk(source: &Path, path: &Path) -> Result<Backing> {
+    fs::hard_link(source, path)?;
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(Backing::new(path.to_path_buf(), &metadata))
+}
+
+// Type of a new node from the file type bits of its `st_mode`
+fn node_kind(mode: u32) -> Result<FileType> {
+    match mode & S_IFMT {
+        S_IFREG | 0 => Ok(FileType::RegularFile),
+        S_IFCHR => Ok(FileType::CharDevice),
+        S_IFBLK => Ok(FileType::BlockDevice),
+        S_IFIFO => Ok(FileType::NamedPipe),
+        S_IFSOCK => Ok(FileType::Socket),
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+// Attributes of a newly created entry; the inode is assigned on insertion
+fn new_attr(kind: FileType, perm: u16) -> FileAttr {
+    let now = SystemTime::now();
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind,
+        perm,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: DEFAULT_UID,
+        gid: DEFAULT_GID,
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    }
+}
+
+// Attributes of an entry created through FUSE, owned by the requesting user
+fn request_attr(req: &Request, kind: FileType, perm: u32, rdev: u32) -> FileAttr {
+    let mut attr = new_attr(kind, (perm & 0o7777) as u16);
+    attr.uid = req.uid();
+    attr.gid = req.gid();
+    attr.rdev = rdev;
+    attr
+}
+
+// Converts 9P mode bits to Unix permission bits
+fn unix_permissions(perm: u32) -> u16 {
+    let mut mode = (perm & 0o777) as u16;
+    if perm & DMSETUID != 0 {
+        mode |= 0o4000;
+    }
+    if perm & DMSETGID != 0 {
+        mode |= 0o2000;
+    }
+    mode
+}
+
+// Packs a device number the way Linux does for 32-bit device numbers
+fn encode_dev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
+fn decode_dev(rdev: u32) -> (u32, u32) {
+    ((rdev >> 8) & 0xfff, (rdev & 0xff) | ((rdev >> 12) & 0xfff00))
+}
+
+// Converts Linux open flags to 9P open flags
+fn open_flags_from_linux(flags: u32) -> OpenFlags {
+    let mut mode = flags & 0o3;
+    if flags & LINUX_O_TRUNC != 0 {
+        mode |= OpenFlags::O_TRUNC;
+    }
+    OpenFlags(mode)
+}
+
+// Converts Unix permission bits to 9P mode bits
+fn nine_p_permissions(mode: u32) -> u32 {
+    let mut perm = mode & 0o777;
+    if mode & 0o4000 != 0 {
+        perm |= DMSETUID;
+    }
+    if mode & 0o2000 != 0 {
+        perm |= DMSETGID;
+    }
+    perm
+}
+
+// File type bits of `st_mode`
+fn file_type_mode(kind: FileType) -> u32 {
+    match kind {
+        FileType::NamedPipe => S_IFIFO,
+        FileType::CharDevice => S_IFCHR,
+        FileType::BlockDevice => S_IFBLK,
+        FileType::Directory => S_IFDIR,
+        FileType::RegularFile => S_IFREG,
+        FileType::Symlink => S_IFLNK,
+        FileType::Socket => S_IFSOCK,
+    }
+}
+
+// `d_type` of a directory entry, which is the file type bits shifted down
+fn dirent_type(kind: FileType) -> u8 {
+    (file_type_mode(kind) >> 12) as u8
+}
+
+fn timespec(time: SystemTime) -> (u64, u64) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos() as u64)
+}
+
+// Seconds since the epoch of a 9P stat, 0 for times before it and the
+// largest time a u32 holds for those after
+fn stat_time(time: SystemTime) -> u32 {
+    u32::try_from(timespec(time).0).unwrap_or(u32::MAX)
+}
+
+// Time of a 9P2000.L timestamp, which has to be one the host can represent
+fn system_time(sec: u64, nsec: u64) -> Result<SystemTime> {
+    if nsec >= 1_000_000_000 {
+        return Err(Error::InvalidArgument);
+    }
+    UNIX_EPOCH
+        .checked_add(Duration::new(sec, nsec as u32))
+        .ok_or(Error::InvalidArgument)
+}
+
+// Owners are processes of a client on one connection
+fn same_owner(a: &HeldLock, b: &HeldLock) -> bool {
+    a.connection == b.connection
+        && a.lock.proc_id == b.lock.proc_id
+        && a.lock.client_id == b.lock.client_id
+}
+
+// End of the range of a lock, u64::MAX for a length of 0, which extends
+// the range to the end of the file
+fn range_end(lock: &Flock) -> u64 {
+    match lock.length {
+        0 => u64::MAX,
+        length => lock.start.saturating_add(length),
+    }
+}
+
+fn ranges_overlap(a: &Flock, b: &Flock) -> bool {
+    a.start < range_end(b) && b.start < range_end(a)
+}
+
+fn locks_conflict(held: &HeldLock, wanted: &HeldLock) -> bool {
+    !same_owner(held, wanted)
+        && ranges_overlap(&held.lock, &wanted.lock)
+        && (held.lock.typ == LOCK_TYPE_WRLCK || wanted.lock.typ == LOCK_TYPE_WRLCK)
+}
+
+// Parts of a held lock before and after the range of `range`
+fn outside_range(held: &HeldLock, range: &Flock) -> Vec<HeldLock> {
+    let (start, end) = (held.lock.start, range_end(&held.lock));
+    let mut parts = Vec::new();
+    if start < range.start {
+        let mut before = held.clone();
+        before.lock.length = range.start - start;
+        parts.push(before);
+    }
+    let range_end = range_end(range);
+    if range_end < end {
+        let mut after = held.clone();
+        after.lock.start = range_end;
+        after.lock.length = if end == u64::MAX { 0 } else { end - range_end };
+        parts.push(after);
+    }
+    parts
+}
+
+// Name of a user id, or the id itself if it has no name
+fn user_name(uid: u32) -> String {
+    User::from_uid(Uid::from_raw(uid))
+        .ok()
+        .flatten()
+        .map_or_else(|| uid.to_string(), |user| user.name)
+}
+
+// User and group ids of a user name. Numeric names are taken as user ids,
+// and users without an account on the host act as the default owner of
+// the namespace.
+fn user_ids(uname: &str) -> (u32, u32) {
+    if let Ok(uid) = uname.parse::<u32>() {
+        let user = User::from_uid(Uid::from_raw(uid)).ok().flatten();
+        return (uid, user.map_or(DEFAULT_GID, |user| user.gid.as_raw()));
+    }
+    User::from_name(uname)
+        .ok()
+        .flatten()
+        .map_or((DEFAULT_UID, DEFAULT_GID), |user| {
+            (user.uid.as_raw(), user.gid.as_raw())
+        })
+}
+
+// Whether a user has all of the `access` bits on a file. The owner, group
+// or other permissions apply, in that order, and root is allowed
+// everything.
+fn permitted(cred: &Credentials, attr: &FileAttr, access: u16) -> bool {
+    if cred.is_root() {
+        return true;
+    }
+    let perm = if attr.uid == cred.uid {
+        attr.perm >> 6
+    } else if cred.in_group(attr.gid) {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    perm & access == access
+}
+
+// Group id of a group name, which may also be the id itself
+fn group_id(name: &str) -> Option<u32> {
+    name.parse().ok().or_else(|| {
+        Group::from_name(name)
+            .ok()
+            .flatten()
+            .map(|group| group.gid.as_raw())
+    })
+}
+
+// Whether a user is listed as a member of a group
+fn in_group(uname: &str, gid: u32) -> bool {
+    Group::from_gid(Gid::from_raw(gid))
+        .ok()
+        .flatten()
+        .is_some_and(|group| group.mem.iter().any(|member| member == uname))
+}
+
+// Name of a group id, or the id itself if it has no name
+fn group_name(gid: u32) -> String {
+    Group::from_gid(Gid::from_raw(gid))
+        .ok()
+        .flatten()
+        .map_or_else(|| gid.to_string(), |group| group.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::auth::HmacSha256;
+    use crate::modules::codec::{
+        decode_stat, GETATTR_BASIC, LOCK_BLOCKED, LOCK_SUCCESS, LOCK_TYPE_UNLCK, LOCK_TYPE_WRLCK,
+        SETATTR_MODE, SETATTR_MTIME, SETATTR_MTIME_SET, SETATTR_SIZE, SETATTR_UID,
+    };
+    use fuser::FileAttr;
+    use tempfile::tempdir;
+
+    // Helper function to create a test filesystem
+    fn root() -> Credentials {
+        Credentials {
+            uid: 0,
+            gid: 0,
+            uname: "root".to_string(),
+        }
+    }
+
+    fn setup_test_fs() -> Result<NineP> {
+        let temp_dir = tempdir()?;
+        NineP::new(temp_dir.path().to_path_buf())
+    }
+
+    // Helper function to create a test file entry
+    fn create_test_file_entry(
+        ino: u64,
+        name: &str,
+        content: Option<Vec<u8>>,
+    ) -> (OsString, BoundEntry) {
+        let attr = FileAttr {
+            ino,
+            size: content.as_ref().map_or(0, |c| c.len() as u64),
+            blocks: 1,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: DEFAULT_UID,
+            gid: DEFAULT_GID,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        };
+
+        (
+            OsString::from(name),
+            BoundEntry::new(attr, ROOT_INODE, content),
+        )
+    }
+
+    #[test]
+    fn test_create_filesystem() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let bindings = fs.namespace_manager.bindings.lock().unwrap();
 
         // Should have exactly one entry (the root directory)
         assert_eq!(bindings.len(), 1);
 
-        // That entry should be inode 1 (root directory)
-        assert!(bindings.contains_key(&1));
+        // That entry should be inode 1 (root directory)
+        assert!(bindings.contains_key(&1));
+
+        // Verify it's a directory
+        if let Some((_, entry)) = bindings.get(&1) {
+            assert_eq!(entry.attr.kind, FileType::Directory);
+        } else {
+            panic!("Root directory not found");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_attributes() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let content = b"Hello, World!".to_vec();
+        let (name, entry) = create_test_file_entry(2, "test.txt", Some(content.clone()));
+
+        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+        bindings.insert(2, (name, entry.clone()));
+
+        assert_eq!(entry.attr.size, 13); // "Hello, World!".len()
+        assert_eq!(entry.attr.kind, FileType::RegularFile);
+        assert_eq!(entry.content.unwrap(), content);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stat_clamps_times_to_9p_range() {
+        let (name, mut entry) = create_test_file_entry(2, "old.txt", None);
+        entry.attr.atime = UNIX_EPOCH - Duration::from_secs(86400);
+        entry.attr.mtime = UNIX_EPOCH + Duration::from_secs(1 << 33);
+        let stat = NineP::stat_from_entry(&name, &entry);
+        assert_eq!((stat.atime, stat.mtime), (0, u32::MAX));
+    }
+
+    #[test]
+    fn test_root_directory_attributes() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let bindings = fs.namespace_manager.bindings.lock().unwrap();
+
+        // Root directory should have inode 1
+        let root_exists = bindings.iter().any(|(ino, _)| *ino == 1);
+        assert!(root_exists);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_lookup() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let (name, entry) = create_test_file_entry(2, "test.txt", Some(b"content".to_vec()));
+
+        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+        bindings.insert(2, (name, entry));
+
+        // File should be findable by inode
+        assert!(bindings.contains_key(&2));
+
+        // Content check
+        if let Some((_, entry)) = bindings.get(&2) {
+            assert_eq!(entry.content.as_ref().unwrap(), b"content");
+        } else {
+            panic!("File not found");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_listing() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let (name1, entry1) = create_test_file_entry(2, "test1.txt", Some(b"content1".to_vec()));
+        let (name2, entry2) = create_test_file_entry(3, "test2.txt", Some(b"content2".to_vec()));
+
+        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+        bindings.insert(2, (name1, entry1));
+        bindings.insert(3, (name2, entry2));
+
+        // Should have root dir (1) plus our two files
+        assert_eq!(bindings.len(), 3);
+
+        // Check file entries exist
+        assert!(bindings.contains_key(&2));
+        assert!(bindings.contains_key(&3));
+
+        // Verify file names
+        let files: Vec<_> = bindings
+            .iter()
+            .filter(|(ino, _)| **ino != 1) // Exclude root directory
+            .map(|(_, (name, _))| name.to_str().unwrap())
+            .collect();
+
+        assert!(files.contains(&"test1.txt"));
+        assert!(files.contains(&"test2.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_content() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let content = b"Hello, World!".to_vec();
+        let (name, entry) = create_test_file_entry(2, "test.txt", Some(content.clone()));
+
+        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+        bindings.insert(2, (name, entry));
+
+        if let Some((_, entry)) = bindings.get(&2) {
+            assert_eq!(entry.content.as_ref().unwrap(), &content);
+            assert_eq!(entry.attr.size, content.len() as u64);
+        } else {
+            panic!("File not found");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_file() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let (name, entry) = create_test_file_entry(2, "empty.txt", None);
+
+        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+        bindings.insert(2, (name, entry));
+
+        if let Some((_, entry)) = bindings.get(&2) {
+            assert!(entry.content.is_none());
+            assert_eq!(entry.attr.size, 0);
+        } else {
+            panic!("File not found");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_connections_have_separate_fids() -> Result<()> {
+        let fs = setup_test_fs()?;
+        let mut first = fs.connection();
+        let mut second = fs.connection();
+
+        first.attach(0, None, "user", "")?;
+        assert!(second.clunk(0).is_err());
+        second.attach(0, None, "user", "")?;
+        assert!(first.attach(0, None, "user", "").is_err());
+
+        first.clunk(0)?;
+        assert!(first.clunk(0).is_err());
+        second.clunk(0)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fid_records_qid_and_mode() -> Result<()> {
+        let mut fs = setup_test_fs()?.connection();
+        let qid = fs.attach(0, None, "user", "")?;
+
+        let fids = fs.fids.lock().unwrap();
+        let fid = fids.get(&0).unwrap();
+        assert_eq!(fid.qid, qid);
+        assert!(fid.mode.is_none());
+        assert_eq!(fid.dir_offset, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_and_clunk_all_release_fids() -> Result<()> {
+        let mut fs = setup_test_fs()?.connection();
+        fs.attach(0, None, "user", "")?;
+        fs.attach(1, None, "user", "")?;
+
+        fs.version("9P2000", 8192)?;
+        assert!(fs.fids.lock().unwrap().is_empty());
+
+        fs.attach(0, None, "user", "")?;
+        fs.clunk_all();
+        assert!(fs.fids.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    // Builds /a/x and /b/x, where a and b are directories
+    fn setup_walk_tree() -> Result<NineP> {
+        let fs = setup_test_fs()?.connection();
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            for (ino, name) in [(2, "a"), (3, "b")] {
+                let mut entry = create_test_file_entry(ino, name, None);
+                entry.1.attr.kind = FileType::Directory;
+                bindings.insert(ino, entry);
+            }
+            for (ino, parent) in [(4, 2), (5, 3)] {
+                let mut entry = create_test_file_entry(ino, "x", Some(vec![]));
+                entry.1.parent = parent;
+                bindings.insert(ino, entry);
+            }
+        }
+        *fs.namespace_manager.next_inode.lock().unwrap() = 6;
+        Ok(fs)
+    }
+
+    fn names(wnames: &[&str]) -> Vec<String> {
+        wnames.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_walk_resolves_each_directory() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+
+        let qids = fs.walk(0, 1, &names(&["a", "x"]))?;
+        assert_eq!(qids.iter().map(|q| q.path).collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(qids[0].file_type, QTDIR);
+
+        let qids = fs.walk(0, 2, &names(&["b", "x"]))?;
+        assert_eq!(qids.iter().map(|q| q.path).collect::<Vec<_>>(), vec![3, 5]);
+
+        let fids = fs.fids.lock().unwrap();
+        assert_eq!(fids[&1].path, PathBuf::from("/a/x"));
+        assert_eq!(fids[&2].qid.path, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuse_lookup_and_readdir_at_any_depth() -> Result<()> {
+        let fs = setup_walk_tree()?;
+
+        assert_eq!(fs.lookup_child(1, OsStr::new("b"))?.ino, 3);
+        assert_eq!(fs.lookup_child(3, OsStr::new("x"))?.ino, 5);
+        assert_eq!(fs.lookup_child(3, OsStr::new(".."))?.ino, 1);
+        let missing = fs.lookup_child(2, OsStr::new("b"));
+        assert!(matches!(missing, Err(Error::NotFound)));
+        let file = fs.lookup_child(4, OsStr::new("x"));
+        assert!(matches!(file, Err(Error::NotADirectory)));
+
+        let entries: Vec<_> = fs
+            .dir_entries(2)?
+            .into_iter()
+            .map(|(ino, _, name)| (ino, name.into_string().unwrap()))
+            .collect();
+        assert_eq!(entries, vec![(2, ".".into()), (1, "..".into()), (4, "x".into())]);
+        assert!(matches!(fs.dir_entries(5), Err(Error::NotADirectory)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuse_writes_reach_backing_directory() -> Result<()> {
+        let dir = tempdir()?;
+        let fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let root = &mut bindings.get_mut(&ROOT_INODE).unwrap().1;
+            let metadata = fs::metadata(dir.path())?;
+            root.backing = Some(Backing::new(dir.path().to_path_buf(), &metadata));
+        }
+
+        let attr = new_attr(FileType::Directory, 0o750);
+        let sub = fs
+            .create_child(&root(), ROOT_INODE, OsStr::new("sub"), attr)?
+            .ino;
+        let attr = new_attr(FileType::RegularFile, 0o640);
+        let file = fs.create_child(&root(), sub, OsStr::new("file"), attr)?.ino;
+        assert_eq!(fs.write_entry(file, 0, b"hello world")?, 11);
+        assert_eq!(fs::read(dir.path().join("sub/file"))?, b"hello world");
+
+        let setattr = Setattr {
+            valid: SETATTR_SIZE | SETATTR_MODE,
+            size: 5,
+            mode: 0o600,
+            ..Default::default()
+        };
+        fs.apply_setattr(&root(), file, &setattr)?;
+        let metadata = fs::metadata(dir.path().join("sub/file"))?;
+        assert_eq!(metadata.len(), 5);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs.entry_attr(file)?.size, 5);
+        fs.sync_entry(file)?;
+
+        // Writes and truncations past the largest file are answered EFBIG
+        let past_end = fs.write_entry(file, i64::MAX as u64, b"x").unwrap_err();
+        assert_eq!(past_end.errno(), libc::EFBIG);
+        let setattr = Setattr {
+            valid: SETATTR_SIZE,
+            size: u64::MAX,
+            ..Default::default()
+        };
+        let truncated = fs.apply_setattr(&root(), file, &setattr).unwrap_err();
+        assert_eq!(truncated.errno(), libc::EFBIG);
+        assert_eq!(fs::metadata(dir.path().join("sub/file"))?.len(), 5);
+
+        let attr = new_attr(FileType::RegularFile, 0o644);
+        fs.create_child(&root(), ROOT_INODE, OsStr::new("other"), attr)?;
+        let kept = fs.rename_child(&root(), sub, "file", ROOT_INODE, "other", false);
+        assert!(matches!(kept, Err(Error::Exists)));
+        fs.rename_child(&root(), sub, "file", ROOT_INODE, "moved", false)?;
+        assert_eq!(fs::read(dir.path().join("moved"))?, b"hello");
+
+        assert!(matches!(
+            fs.remove_child(&root(), ROOT_INODE, "sub", false),
+            Err(Error::IsADirectory)
+        ));
+        fs.remove_child(&root(), ROOT_INODE, "sub", true)?;
+        fs.remove_child(&root(), ROOT_INODE, "moved", false)?;
+        assert!(!dir.path().join("sub").exists());
+        assert!(!dir.path().join("moved").exists());
+        assert!(fs.lookup_child(ROOT_INODE, OsStr::new("moved")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_links_in_backing_directory() -> Result<()> {
+        let dir = tempdir()?;
+        let fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let root = &mut bindings.get_mut(&ROOT_INODE).unwrap().1;
+            let metadata = fs::metadata(dir.path())?;
+            root.backing = Some(Backing::new(dir.path().to_path_buf(), &metadata));
+        }
+
+        let attr = new_attr(FileType::RegularFile, 0o644);
+        let file = fs
+            .create_child(&root(), ROOT_INODE, OsStr::new("file"), attr)?
+            .ino;
+        fs.write_entry(file, 0, b"shared")?;
+        let link = fs.link_entry(file, ROOT_INODE, "hard")?.path;
+        assert_eq!(fs::metadata(dir.path().join("hard"))?.nlink(), 2);
+        assert_eq!(fs.entry_attr(file)?.nlink, 2);
+        fs.write_entry(link, 0, b"S")?;
+        assert_eq!(fs.read_entry(file, 0, 10)?, b"Shared");
+
+        let content = Some(b"file".to_vec());
+        let attr = new_attr(FileType::Symlink, 0o777);
+        let qid = fs.insert_entry("sym", BoundEntry::new(attr, ROOT_INODE, content))?;
+        assert_eq!(fs::read_link(dir.path().join("sym"))?, Path::new("file"));
+        assert_eq!(fs.link_target(qid.path)?, b"file");
+        assert!(matches!(fs.link_target(file), Err(Error::InvalidArgument)));
+
+        // Content held in memory and directories cannot be linked to
+        assert!(matches!(fs.link_entry(4, ROOT_INODE, "x"), Err(Error::NotSupported)));
+        assert!(matches!(fs.link_entry(2, ROOT_INODE, "a2"), Err(Error::IsADirectory)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuse_read_honours_offset_and_size() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("x");
+        fs::write(&path, b"0123456789")?;
+        let fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let entry = &mut bindings.get_mut(&4).unwrap().1;
+            entry.backing = Some(Backing::new(path.clone(), &fs::metadata(&path)?));
+            let entry = &mut bindings.get_mut(&5).unwrap().1;
+            entry.content = Some(b"in memory".to_vec());
+        }
+
+        assert_eq!(fs.read_entry(4, 2, 3)?, b"234");
+        assert_eq!(fs.read_entry(4, 8, 100)?, b"89");
+        assert!(fs.read_entry(4, 10, 4)?.is_empty());
+        assert!(fs.read_entry(4, u64::MAX, 4)?.is_empty());
+        assert_eq!(fs.read_entry(5, 3, 2)?, b"me");
+        assert!(fs.read_entry(5, 1000, 4)?.is_empty());
+        assert!(matches!(fs.read_entry(2, 0, 4), Err(Error::IsADirectory)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_dotdot() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+
+        let qids = fs.walk(0, 1, &names(&["a", "..", "b", "x"]))?;
+        assert_eq!(
+            qids.iter().map(|q| q.path).collect::<Vec<_>>(),
+            vec![2, ROOT_INODE, 3, 5]
+        );
+
+        // `..` at the root stays at the root
+        let qids = fs.walk(0, 2, &names(&[".."]))?;
+        assert_eq!(qids[0].path, ROOT_INODE);
+        assert_eq!(fs.fids.lock().unwrap()[&2].path, PathBuf::from("/"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_partial_failure() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+
+        // A missing first element is an error
+        let missing = fs.walk(0, 1, &names(&["missing"]));
+        assert!(matches!(missing, Err(Error::NotFound)));
+
+        // A later failure returns the qids walked so far
+        let qids = fs.walk(0, 1, &names(&["a", "missing", "x"]))?;
+        assert_eq!(qids.len(), 1);
+        assert_eq!(qids[0].path, 2);
+
+        // Files cannot be walked through
+        let qids = fs.walk(0, 1, &names(&["a", "x", "y"]))?;
+        assert_eq!(qids.len(), 2);
+
+        assert!(!fs.fids.lock().unwrap().contains_key(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_zero_elements_clones_fid() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a"]))?;
+
+        assert!(fs.walk(1, 2, &[])?.is_empty());
+        let fids = fs.fids.lock().unwrap();
+        assert_eq!(fids[&2].path, fids[&1].path);
+        assert_eq!(fids[&2].qid, fids[&1].qid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_rejects_too_many_elements() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+
+        let wnames = vec!["..".to_string(); MAXWELEM + 1];
+        assert!(fs.walk(0, 1, &wnames).is_err());
+        assert_eq!(fs.walk(0, 1, &wnames[..MAXWELEM])?.len(), MAXWELEM);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_directory_returns_packed_stats() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.open(0, OpenFlags(OpenFlags::O_RDONLY))?;
+
+        let data = fs.read(0, 0, 8192)?;
+        let (first, used) = decode_stat(&data, Dialect::Plan9)?;
+        let (second, rest) = decode_stat(&data[used..], Dialect::Plan9)?;
+        assert_eq!(used + rest, data.len());
+        assert_eq!((first.name.as_str(), first.qid.path), ("a", 2));
+        assert_eq!((second.name.as_str(), second.qid.path), ("b", 3));
+        assert_ne!(first.mode & DMDIR, 0);
+
+        // Reading past the last entry returns nothing
+        assert!(fs.read(0, data.len() as u64, 8192)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_directory_returns_whole_entries() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.open(0, OpenFlags(OpenFlags::O_RDONLY))?;
+
+        let entry_len = fs.read(0, 0, 8192)?.len() / 2;
+        let first = fs.read(0, 0, entry_len as u32 + 10)?;
+        assert_eq!(first.len(), entry_len);
+
+        // Offsets must continue where the last read ended
+        assert!(fs.read(0, 10, 8192).is_err());
+        let second = fs.read(0, entry_len as u64, 8192)?;
+        assert_eq!(second.len(), entry_len);
+
+        // A count too small for a single entry is an error, not EOF
+        assert!(fs.read(0, 0, 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_directory_continues_in_a_snapshot() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.open(0, OpenFlags(OpenFlags::O_RDONLY))?;
+
+        let entry_len = fs.read(0, 0, 8192)?.len() / 2;
+        fs.read(0, 0, entry_len as u32)?;
+        fs.namespace_manager
+            .bindings
+            .lock()
+            .unwrap()
+            .rename(3, ROOT_INODE, OsString::from("c"));
+
+        // The listing that was started is not affected by the rename
+        let (stat, _) = decode_stat(&fs.read(0, entry_len as u64, 8192)?, Dialect::Plan9)?;
+        assert_eq!(stat.name, "b");
+        assert!(fs.read(0, 2 * entry_len as u64, 8192)?.is_empty());
+
+        // Reading from the start again sees the current entries
+        let data = fs.read(0, 0, 8192)?;
+        let (_, used) = decode_stat(&data, Dialect::Plan9)?;
+        assert_eq!(decode_stat(&data[used..], Dialect::Plan9)?.0.name, "c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_flushed_requests_stop_backing_io() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("x");
+        fs::write(&path, b"data")?;
+        let mut fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let entry = &mut bindings.get_mut(&4).unwrap().1;
+            entry.backing = Some(Backing::new(path.clone(), &fs::metadata(&path)?));
+        }
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.open(1, OpenFlags(OpenFlags::O_RDWR))?;
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut request = fs.cancelled_by(flag.clone());
+        assert_eq!(request.read(1, 0, 100)?, b"data");
+        flag.store(true, Ordering::Relaxed);
+        assert!(matches!(request.read(1, 0, 100), Err(Error::Interrupted)));
+        assert!(matches!(request.write(1, 0, b"flushed"), Err(Error::Interrupted)));
+
+        // Nothing was written, and other requests go on
+        assert_eq!(fs::read(&path)?, b"data");
+        assert_eq!(fs.write(1, 0, b"DA")?, 2);
+        assert_eq!(fs.read(1, 0, 100)?, b"DAta");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_uses_the_fid_file() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.walk(0, 2, &names(&["b", "x"]))?;
+        fs.open(1, OpenFlags(OpenFlags::O_RDONLY))?;
+        fs.open(2, OpenFlags(OpenFlags::O_RDWR))?;
+
+        fs.write(2, 0, b"second")?;
+        assert!(fs.read(1, 0, 100)?.is_empty());
+        assert_eq!(fs.read(2, 0, 100)?, b"second");
+        assert_eq!(fs.read(2, 3, 100)?, b"ond");
+        assert!(fs.read(2, 100, 100)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_negotiates_dialect() -> Result<()> {
+        let mut fs = setup_test_fs()?.connection();
+
+        assert_eq!(fs.version("9P2000.u", 8192)?.1, "9P2000.u");
+        assert_eq!(fs.dialect(), Dialect::Unix);
+        assert_eq!(fs.version("9P2000.x", 8192)?.1, "9P2000");
+        assert_eq!(fs.dialect(), Dialect::Plan9);
+        assert_eq!(fs.version("8P", 8192)?.1, "unknown");
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_parse() -> Result<()> {
+        let (name, export) = Export::parse("snap=/builds/latest:ro:users=glenda,bootes")?;
+        assert_eq!(name, "snap");
+        assert_eq!(export.path, PathBuf::from("/builds/latest"));
+        assert!(export.read_only);
+        assert_eq!(export.users, Some(names(&["glenda", "bootes"])));
+
+        let (name, export) = Export::parse("=/")?;
+        assert_eq!(name, "");
+        assert!(!export.read_only && export.users.is_none());
+
+        assert!(Export::parse("/builds").is_err());
+        assert!(Export::parse("snap=builds").is_err());
+        assert!(Export::parse("snap=/builds:rx").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_attach_selects_exported_tree() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.export(
+            "a",
+            Export {
+                path: PathBuf::from("/a"),
+                read_only: true,
+                users: Some(names(&["glenda"])),
+            },
+        );
+        assert!(fs.attach(0, None, "glenda", "missing").is_err());
+        assert!(fs.attach(0, None, "bootes", "a").is_err());
+        assert_eq!(fs.attach(0, None, "glenda", "a")?.path, 2);
+
+        // The root of the tree is its own parent
+        let qids = fs.walk(0, 1, &names(&["..", "x"]))?;
+        assert_eq!(qids.iter().map(|q| q.path).collect::<Vec<_>>(), vec![2, 4]);
+        assert!(fs.walk(0, 2, &names(&["b"])).is_err());
+
+        // Nothing in a read-only tree can be changed
+        let rdwr = OpenFlags(OpenFlags::O_RDWR);
+        assert!(fs.open(1, rdwr).is_err());
+        assert!(fs.write(1, 0, b"data").is_err());
+        fs.open(1, OpenFlags(OpenFlags::O_RDONLY))?;
+        fs.walk(0, 3, &[])?;
+        assert!(fs.create(3, "y", 0o644, rdwr, "").is_err());
+        assert!(fs.mkdir(3, "y", 0o755, 0).is_err());
+        assert!(fs.unlinkat(3, "x", 0).is_err());
+
+        // The default tree is still the whole namespace
+        assert_eq!(fs.attach(4, None, "bootes", "")?.path, ROOT_INODE);
+        fs.unexport("a");
+        assert!(fs.attach(5, None, "glenda", "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_attach_requires_authentication() -> Result<()> {
+        let mut fs = setup_test_fs()?;
+        assert!(fs.auth("glenda", "", 9).is_err());
 
-        // Verify it's a directory
-        if let Some((_, entry)) = bindings.get(&1) {
-            assert_eq!(entry.attr.kind, FileType::Directory);
-        } else {
-            panic!("Root directory not found");
+        let keys = HashMap::from([("glenda".to_string(), b"secret".to_vec())]);
+        fs.set_auth(Arc::new(HmacSha256::new(keys)));
+        let mut fs = fs.connection();
+        assert!(fs.attach(0, None, "glenda", "").is_err());
+
+        let aqid = fs.auth("glenda", "", 9)?;
+        assert_eq!(aqid.file_type, QTAUTH);
+        assert!(fs.walk(9, 10, &[]).is_err());
+        assert!(fs.attach(0, Some(9), "glenda", "").is_err());
+
+        let line = fs.read(9, 0, 1024)?;
+        let hex = std::str::from_utf8(&line).unwrap().trim_end();
+        let challenge: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        let response = HmacSha256::response(b"secret", &challenge, "glenda");
+        fs.write(9, 0, response.as_bytes())?;
+
+        // The afid only vouches for the user and tree it was opened for
+        assert!(fs.attach(0, Some(9), "bootes", "").is_err());
+        assert!(fs.attach(0, Some(9), "glenda", "other").is_err());
+        assert!(fs.attach(0, Some(1), "glenda", "").is_err());
+        assert_eq!(fs.attach(0, Some(9), "glenda", "")?.path, ROOT_INODE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_negotiates_msize() -> Result<()> {
+        let mut fs = setup_test_fs()?;
+        assert!(fs.set_max_msize(MAX_MSIZE + 1).is_err());
+        assert!(fs.set_max_msize(MIN_MSIZE - 1).is_err());
+        fs.set_max_msize(65536)?;
+
+        let mut conn = fs.connection();
+        assert_eq!(conn.msize(), DEFAULT_MSIZE);
+        assert_eq!(conn.version("9P2000", 8192)?.0, 8192);
+        assert_eq!(conn.version("9P2000", MAX_MSIZE)?.0, 65536);
+        assert_eq!(conn.msize(), 65536);
+        assert!(conn.version("9P2000", MIN_MSIZE - 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_and_write_trimmed_to_iounit() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.version("9P2000", MIN_MSIZE)?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.open(1, OpenFlags(OpenFlags::O_RDWR))?;
+
+        let iounit = (MIN_MSIZE - IOHDRSZ) as usize;
+        let data = vec![7u8; MIN_MSIZE as usize];
+        assert_eq!(fs.write(1, 0, &data)? as usize, iounit);
+        fs.write(1, iounit as u64, &data[iounit..])?;
+
+        assert_eq!(fs.read(1, 0, MIN_MSIZE)?.len(), iounit);
+        assert_eq!(fs.read(1, iounit as u64, MIN_MSIZE)?, &data[iounit..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_cannot_grow_past_the_largest_size() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.open(1, OpenFlags(OpenFlags::O_RDWR))?;
+        fs.write(1, 0, b"data")?;
+
+        let too_large = |result: Result<()>| matches!(result, Err(Error::FileTooLarge));
+        assert!(too_large(fs.write(1, u64::MAX - 1, b"data").map(drop)));
+        assert!(too_large(fs.write(1, MAX_CONTENT_SIZE, b"data").map(drop)));
+        let size = MAX_CONTENT_SIZE + 1;
+        let truncate = Setattr { valid: SETATTR_SIZE, size, ..Setattr::default() };
+        assert!(too_large(fs.setattr(1, &truncate)));
+        assert!(too_large(fs.wstat(1, &Stat { length: size, mode: 0o600, ..dont_touch() })));
+
+        // Nothing else the requests asked for was changed
+        let stat = fs.stat(1)?;
+        assert_eq!((stat.length, stat.mode), (4, 0o644));
+        assert_eq!(fs.read(1, 0, 100)?, b"data");
+
+        // A file may still grow up to the limit itself
+        let truncate = Setattr { size: MAX_CONTENT_SIZE, ..truncate };
+        fs.setattr(1, &truncate)?;
+        assert_eq!(fs.stat(1)?.length, MAX_CONTENT_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_io_needs_a_matching_open_mode() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        assert!(fs.read(1, 0, 100).is_err());
+        assert!(fs.write(1, 0, b"data").is_err());
+
+        fs.open(1, OpenFlags(OpenFlags::O_WRONLY))?;
+        fs.write(1, 0, b"data")?;
+        assert!(fs.read(1, 0, 100).is_err());
+
+        fs.walk(0, 2, &names(&["a", "x"]))?;
+        fs.open(2, OpenFlags(OpenFlags::O_RDONLY))?;
+        assert!(fs.write(2, 0, b"data").is_err());
+        assert_eq!(fs.read(2, 0, 100)?, b"data");
+
+        fs.walk(0, 3, &names(&["a"]))?;
+        assert!(fs.open(3, OpenFlags(OpenFlags::O_RDWR)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_truncates_and_removes_on_close() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.open(1, OpenFlags(OpenFlags::O_WRONLY))?;
+        fs.write(1, 0, b"data")?;
+
+        let flags = OpenFlags::O_RDWR | OpenFlags::O_TRUNC | OpenFlags::O_RCLOSE;
+        fs.walk(0, 2, &names(&["a", "x"]))?;
+        fs.open(2, OpenFlags(flags))?;
+        assert!(fs.read(2, 0, 100)?.is_empty());
+        assert_eq!(fs.stat(2)?.length, 0);
+
+        fs.clunk(2)?;
+        assert_eq!(fs.walk(0, 3, &names(&["a", "x"]))?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclusive_and_append_only_files() -> Result<()> {
+        let fs = setup_walk_tree()?;
+        let mut first = fs.connection();
+        let mut second = fs.connection();
+        let rdwr = OpenFlags(OpenFlags::O_RDWR);
+
+        first.attach(0, None, "user", "")?;
+        first.create(0, "log", DMEXCL | DMAPPEND | 0o600, rdwr, "")?;
+        second.attach(0, None, "user", "")?;
+        second.walk(0, 1, &names(&["log"]))?;
+        assert!(second.open(1, rdwr).is_err());
+        assert!(second.lopen(1, 2).is_err());
+
+        first.write(0, 0, b"one")?;
+        first.write(0, 0, b"two")?;
+        assert_eq!(first.read(0, 0, 100)?, b"onetwo");
+
+        // Only the holder may truncate the file, whatever the dialect
+        let truncate = Setattr { valid: SETATTR_SIZE, size: 3, ..Setattr::default() };
+        assert!(matches!(second.setattr(1, &truncate), Err(Error::InUse)));
+        let stat = Stat { length: 3, ..dont_touch() };
+        assert!(matches!(second.wstat(1, &stat), Err(Error::InUse)));
+        let log = second.fid_inode(1)?;
+        let user = Credentials { uid: DEFAULT_UID, gid: DEFAULT_GID, uname: "user".into() };
+        assert!(matches!(second.open_entry(&user, log, rdwr), Err(Error::InUse)));
+        first.setattr(0, &truncate)?;
+        assert_eq!(first.read(0, 0, 100)?, b"one");
+
+        first.clunk(0)?;
+        let fh = second.open_entry(&user, log, rdwr)?;
+        assert_ne!(fh & FH_EXCLUSIVE, 0);
+        assert!(second.open(1, rdwr).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_checks_permissions() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let attr = &mut bindings.get_mut(&4).unwrap().1.attr;
+            (attr.uid, attr.gid, attr.perm) = (4242, 4242, 0o604);
+        }
+        let rdonly = OpenFlags(OpenFlags::O_RDONLY);
+        let wronly = OpenFlags(OpenFlags::O_WRONLY);
+
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        assert!(fs.open(1, wronly).is_err());
+        let truncate = OpenFlags(OpenFlags::O_RDONLY | OpenFlags::O_TRUNC);
+        assert!(fs.open(1, truncate).is_err());
+        assert!(fs.lopen(1, LINUX_O_TRUNC).is_err());
+        let user = Credentials { uid: DEFAULT_UID, gid: DEFAULT_GID, uname: "user".into() };
+        assert!(fs.open_entry(&user, 4, wronly).is_err());
+        assert_eq!(fs.open_entry(&user, 4, rdonly)?, 0);
+        fs.open(1, rdonly)?;
+
+        fs.attach(2, None, "4242", "")?;
+        fs.walk(2, 3, &names(&["a", "x"]))?;
+        fs.open(3, wronly)?;
+
+        // Creating and removing need write permission on the directory
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            bindings.get_mut(&2).unwrap().1.attr.perm = 0o555;
         }
+        fs.walk(0, 4, &names(&["a"]))?;
+        assert!(fs.create(4, "new", 0o644, wronly, "").is_err());
+        fs.walk(0, 5, &names(&["a", "x"]))?;
+        assert!(fs.remove(5).is_err());
         Ok(())
     }
 
     #[test]
-    fn test_file_attributes() -> Result<()> {
-        let fs = setup_test_fs()?;
-        let content = b"Hello, World!".to_vec();
-        let (name, entry) = create_test_file_entry(2, "test.txt", Some(content.clone()));
+    fn test_qid_version_follows_changes() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        let qids = fs.walk(0, 1, &names(&["a", "x"]))?;
+        assert_eq!(qids[1].version, 0);
+        fs.open(1, OpenFlags(OpenFlags::O_RDWR))?;
 
-        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
-        bindings.insert(2, (name, entry.clone()));
+        fs.write(1, 0, b"data")?;
+        assert_eq!(fs.stat(1)?.qid.version, 1);
 
-        assert_eq!(entry.attr.size, 13); // "Hello, World!".len()
-        assert_eq!(entry.attr.kind, FileType::RegularFile);
-        assert_eq!(entry.content.unwrap(), content);
+        let mut stat = fs.stat(1)?;
+        stat.mode = DMAPPEND | 0o600;
+        fs.wstat(1, &stat)?;
+        let stat = fs.stat(1)?;
+        assert_eq!(stat.qid.version, 2);
+        assert_eq!(stat.qid.file_type, QTAPPEND);
+        assert_eq!(stat.mode, DMAPPEND | 0o600);
+
+        let setattr = Setattr {
+            valid: SETATTR_SIZE,
+            ..Setattr::default()
+        };
+        fs.setattr(1, &setattr)?;
+        assert_eq!(fs.getattr(1, GETATTR_BASIC)?.data_version, 3);
+
+        // Adding an entry changes the directory
+        fs.walk(0, 2, &names(&["b"]))?;
+        fs.create(2, "y", DMEXCL | 0o600, OpenFlags(OpenFlags::O_RDWR), "")?;
+        assert_eq!(fs.stat(2)?.qid.file_type, QTEXCL);
+        assert_eq!(fs.walk(0, 3, &names(&["b"]))?[0].version, 1);
         Ok(())
     }
 
     #[test]
-    fn test_root_directory_attributes() -> Result<()> {
-        let fs = setup_test_fs()?;
-        let bindings = fs.namespace_manager.bindings.lock().unwrap();
+    fn test_qid_version_follows_backing_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("x");
+        fs::write(&path, b"old")?;
 
-        // Root directory should have inode 1
-        let root_exists = bindings.iter().any(|(ino, _)| *ino == 1);
-        assert!(root_exists);
+        let mut fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let entry = &mut bindings.get_mut(&4).unwrap().1;
+            entry.backing = Some(Backing::new(path.clone(), &fs::metadata(&path)?));
+        }
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.open(1, OpenFlags(OpenFlags::O_RDONLY))?;
+        assert_eq!(fs.stat(1)?.qid.version, 0);
+
+        fs::write(&path, b"changed")?;
+        let stat = fs.stat(1)?;
+        assert_eq!(stat.qid.version, 1);
+        assert_eq!(stat.length, 7);
+        assert_eq!(fs.read(1, 0, 100)?, b"changed");
+        assert_eq!(fs.stat(1)?.qid.version, 1);
         Ok(())
     }
 
     #[test]
-    fn test_file_lookup() -> Result<()> {
-        let fs = setup_test_fs()?;
-        let (name, entry) = create_test_file_entry(2, "test.txt", Some(b"content".to_vec()));
+    fn test_create_inherits_permissions() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        let rdonly = OpenFlags(OpenFlags::O_RDONLY);
+        fs.attach(0, None, "0", "")?;
+        fs.walk(0, 1, &names(&["a"]))?;
+        fs.walk(0, 2, &names(&["a"]))?;
 
-        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
-        bindings.insert(2, (name, entry));
+        let (qid, _) = fs.create(1, "dir", DMDIR | 0o777, rdonly, "")?;
+        assert_eq!(qid.file_type, QTDIR);
+        let stat = fs.stat(1)?;
+        assert_eq!(stat.mode, DMDIR | 0o644);
+        assert_eq!((stat.n_uid, stat.n_gid), (0, DEFAULT_GID));
 
-        // File should be findable by inode
-        assert!(bindings.contains_key(&2));
+        fs.create(2, "file", 0o777, rdonly, "")?;
+        assert_eq!(fs.stat(2)?.mode, 0o755);
 
-        // Content check
-        if let Some((_, entry)) = bindings.get(&2) {
-            assert_eq!(entry.content.as_ref().unwrap(), b"content");
-        } else {
-            panic!("File not found");
+        fs.walk(0, 3, &names(&["a"]))?;
+        let rdwr = OpenFlags(OpenFlags::O_RDWR);
+        assert!(fs.create(3, "other", DMDIR | 0o777, rdwr, "").is_err());
+        Ok(())
+    }
+
+    // A stat that changes nothing when written
+    fn dont_touch() -> Stat {
+        Stat {
+            size: 0,
+            typ: u16::MAX,
+            dev: u32::MAX,
+            qid: Qid {
+                version: u32::MAX,
+                path: u64::MAX,
+                file_type: u8::MAX,
+            },
+            mode: u32::MAX,
+            atime: u32::MAX,
+            mtime: u32::MAX,
+            length: u64::MAX,
+            name: String::new(),
+            uid: String::new(),
+            gid: String::new(),
+            muid: String::new(),
+            extension: String::new(),
+            n_uid: NONUNAME,
+            n_gid: NONUNAME,
+            n_muid: NONUNAME,
         }
+    }
+
+    #[test]
+    fn test_wstat_changes_only_requested_fields() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.open(1, OpenFlags(OpenFlags::O_RDWR))?;
+        fs.write(1, 0, b"hello world")?;
+        let before = fs.stat(1)?;
+
+        fs.wstat(1, &dont_touch())?;
+        assert_eq!(fs.stat(1)?, before);
+
+        let stat = Stat {
+            name: "y".to_string(),
+            length: 5,
+            mtime: 1000,
+            ..dont_touch()
+        };
+        fs.wstat(1, &stat)?;
+        let after = fs.stat(1)?;
+        assert_eq!((after.length, after.mtime), (5, 1000));
+        assert_eq!(after.name, "y");
+        assert_eq!(after.mode, before.mode);
+        assert_eq!(fs.read(1, 0, 100)?, b"hello");
+        assert_eq!(fs.walk(0, 2, &names(&["a", "y"]))?.len(), 2);
         Ok(())
     }
 
     #[test]
-    fn test_directory_listing() -> Result<()> {
-        let fs = setup_test_fs()?;
-        let (name1, entry1) = create_test_file_entry(2, "test1.txt", Some(b"content1".to_vec()));
-        let (name2, entry2) = create_test_file_entry(3, "test2.txt", Some(b"content2".to_vec()));
+    fn test_wstat_is_all_or_nothing() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.walk(0, 2, &names(&["a"]))?;
+        fs.create(2, "z", 0o644, OpenFlags(OpenFlags::O_RDWR), "")?;
 
-        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
-        bindings.insert(2, (name1, entry1));
-        bindings.insert(3, (name2, entry2));
+        // The name is taken, so the mode is not changed either
+        let stat = Stat {
+            name: "z".to_string(),
+            mode: 0o600,
+            ..dont_touch()
+        };
+        assert!(fs.wstat(1, &stat).is_err());
+        assert_eq!(fs.stat(1)?.mode, 0o644);
 
-        // Should have root dir (1) plus our two files
-        assert_eq!(bindings.len(), 3);
+        let stat = Stat {
+            mode: DMDIR | 0o755,
+            ..dont_touch()
+        };
+        assert!(fs.wstat(1, &stat).is_err());
+        let stat = Stat {
+            uid: "someone-else".to_string(),
+            ..dont_touch()
+        };
+        assert!(fs.wstat(1, &stat).is_err());
 
-        // Check file entries exist
-        assert!(bindings.contains_key(&2));
-        assert!(bindings.contains_key(&3));
+        // Fields the server keeps may only be written with their value
+        let current = fs.stat(1)?;
+        let fixed = [
+            Stat { typ: 1, ..dont_touch() },
+            Stat { dev: 1, ..dont_touch() },
+            Stat { qid: Qid { path: 42, ..current.qid.clone() }, ..dont_touch() },
+            Stat { atime: current.atime.wrapping_add(1), ..dont_touch() },
+            Stat { muid: "someone-else".to_string(), ..dont_touch() },
+        ];
+        for stat in fixed {
+            let stat = Stat { length: 0, ..stat };
+            assert!(matches!(fs.wstat(1, &stat), Err(Error::InvalidArgument)));
+        }
+        let same = Stat { typ: 0, dev: 0, atime: current.atime, ..dont_touch() };
+        fs.wstat(1, &Stat { qid: current.qid.clone(), ..same })?;
+        assert_eq!(fs.stat(1)?, current);
 
-        // Verify file names
-        let files: Vec<_> = bindings
-            .iter()
-            .filter(|(ino, _)| **ino != 1) // Exclude root directory
-            .map(|(_, (name, _))| name.to_str().unwrap())
-            .collect();
+        // Only root may give the file to a group the user is not in
+        let stat = Stat {
+            gid: "4242".to_string(),
+            ..dont_touch()
+        };
+        assert!(fs.wstat(1, &stat).is_err());
+        fs.attach(3, None, "0", "")?;
+        fs.walk(3, 4, &names(&["a", "x"]))?;
+        fs.wstat(4, &stat)?;
+        assert_eq!(fs.stat(1)?.n_gid, 4242);
+        Ok(())
+    }
 
-        assert!(files.contains(&"test1.txt"));
-        assert!(files.contains(&"test2.txt"));
+    #[test]
+    fn test_wstat_renames_backing_file() -> Result<()> {
+        let dir = tempdir()?;
+        let mut fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let root = &mut bindings.get_mut(&ROOT_INODE).unwrap().1;
+            let metadata = fs::metadata(dir.path())?;
+            root.backing = Some(Backing::new(dir.path().to_path_buf(), &metadata));
+        }
+        let rdonly = OpenFlags(OpenFlags::O_RDONLY);
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&[]))?;
+        fs.create(1, "old", DMDIR | 0o755, rdonly, "")?;
+        fs.walk(0, 2, &names(&["old"]))?;
+        fs.create(2, "file", 0o644, rdonly, "")?;
+
+        let stat = Stat {
+            name: "new".to_string(),
+            mode: DMDIR | 0o700,
+            ..dont_touch()
+        };
+        fs.wstat(1, &stat)?;
+        assert!(!dir.path().join("old").exists());
+        let metadata = fs::metadata(dir.path().join("new"))?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+
+        // Entries below the directory follow it on disk
+        let stat = Stat {
+            length: 3,
+            ..dont_touch()
+        };
+        fs.wstat(2, &stat)?;
+        assert_eq!(fs::metadata(dir.path().join("new/file"))?.len(), 3);
+
+        // A file on disk outside the namespace is not replaced, and the
+        // truncation that came with the rename is not made either
+        fs::write(dir.path().join("new/taken"), b"keep")?;
+        let stat = Stat {
+            name: "taken".to_string(),
+            length: 0,
+            ..dont_touch()
+        };
+        assert!(matches!(fs.wstat(2, &stat), Err(Error::Exists)));
+        assert_eq!(fs::read(dir.path().join("new/taken"))?, b"keep");
+        assert_eq!(fs::metadata(dir.path().join("new/file"))?.len(), 3);
         Ok(())
     }
 
     #[test]
-    fn test_file_content() -> Result<()> {
-        let fs = setup_test_fs()?;
-        let content = b"Hello, World!".to_vec();
-        let (name, entry) = create_test_file_entry(2, "test.txt", Some(content.clone()));
+    fn test_create_in_backed_directory() -> Result<()> {
+        let dir = tempdir()?;
+        let mut fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let root = &mut bindings.get_mut(&ROOT_INODE).unwrap().1;
+            let metadata = fs::metadata(dir.path())?;
+            root.backing = Some(Backing::new(dir.path().to_path_buf(), &metadata));
+        }
+        let rdonly = OpenFlags(OpenFlags::O_RDONLY);
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&[]))?;
+        fs.walk(0, 2, &names(&[]))?;
 
-        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
-        bindings.insert(2, (name, entry));
+        fs.create(1, "new", DMDIR | 0o750, rdonly, "")?;
+        fs.create(2, "link", DMSYMLINK | 0o777, rdonly, "new")?;
+        let metadata = fs::metadata(dir.path().join("new"))?;
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+        assert_eq!(fs::read_link(dir.path().join("link"))?, Path::new("new"));
 
-        if let Some((_, entry)) = bindings.get(&2) {
-            assert_eq!(entry.content.as_ref().unwrap(), &content);
-            assert_eq!(entry.attr.size, content.len() as u64);
-        } else {
-            panic!("File not found");
+        // The new directory is backed too
+        fs.walk(0, 3, &names(&["new"]))?;
+        fs.create(3, "file", 0o640, rdonly, "")?;
+        assert!(dir.path().join("new/file").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_special_files() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.version("9P2000.u", 8192)?;
+        let rdwr = OpenFlags(OpenFlags::O_RDWR);
+
+        fs.attach(0, None, "user", "")?;
+        let (qid, _) = fs.create(0, "link", DMSYMLINK | 0o777, rdwr, "/a/x")?;
+        assert_eq!(qid.file_type, QTSYMLINK);
+        let stat = fs.stat(0)?;
+        assert_ne!(stat.mode & DMSYMLINK, 0);
+        assert_eq!(stat.extension, "/a/x");
+
+        fs.attach(1, None, "user", "")?;
+        fs.create(1, "null", DMDEVICE | 0o666, rdwr, "c 1 3")?;
+        let stat = fs.stat(1)?;
+        assert_ne!(stat.mode & DMDEVICE, 0);
+        assert_eq!(stat.extension, "c 1 3");
+        assert_eq!(stat.n_uid, DEFAULT_UID);
+
+        fs.attach(2, None, "user", "")?;
+        assert!(fs.create(2, "bad", DMDEVICE | 0o666, rdwr, "x 1").is_err());
+        Ok(())
+    }
+
+    // Parses the entries of a Treaddir reply into (offset, type, name)
+    fn dirents(mut data: &[u8]) -> Vec<(u64, u8, String)> {
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            let offset = u64::from_le_bytes(data[13..21].try_into().unwrap());
+            let typ = data[21];
+            let len = u16::from_le_bytes(data[22..24].try_into().unwrap()) as usize;
+            let name = String::from_utf8(data[24..24 + len].to_vec()).unwrap();
+            entries.push((offset, typ, name));
+            data = &data[24 + len..];
         }
+        entries
+    }
+
+    #[test]
+    fn test_linux_create_and_readdir() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.version("9P2000.L", 8192)?;
+        fs.attach(0, None, "user", "")?;
+
+        let qid = fs.mkdir(0, "dir", 0o750, DEFAULT_GID)?;
+        assert_eq!(qid.file_type, QTDIR);
+        assert!(fs.mkdir(0, "dir", 0o750, DEFAULT_GID).is_err());
+
+        fs.walk(0, 1, &names(&["dir"]))?;
+        fs.lcreate(1, "file", 0o1002, 0o640, DEFAULT_GID)?;
+        fs.write(1, 0, b"data")?;
+        let attr = fs.getattr(1, GETATTR_BASIC)?;
+        assert_eq!(attr.mode, S_IFREG | 0o640);
+        assert_eq!(
+            (attr.uid, attr.gid, attr.size),
+            (DEFAULT_UID, DEFAULT_GID, 4)
+        );
+
+        fs.symlink(0, "link", "dir/file", DEFAULT_GID)?;
+        fs.walk(0, 2, &names(&["link"]))?;
+        assert_eq!(fs.readlink(2)?, "dir/file");
+        assert!(fs.readlink(0).is_err());
+
+        fs.mknod(0, "null", S_IFCHR | 0o666, 1, 3, DEFAULT_GID)?;
+        fs.walk(0, 3, &names(&["null"]))?;
+        assert_eq!(fs.getattr(3, GETATTR_BASIC)?.rdev, encode_dev(1, 3) as u64);
+
+        fs.lopen(0, 0)?;
+        let entries = dirents(&fs.readdir(0, 0, 8192)?);
+        let listed: Vec<&str> = entries.iter().map(|(_, _, name)| name.as_str()).collect();
+        assert_eq!(listed, vec![".", "..", "a", "b", "dir", "link", "null"]);
+        assert_eq!(entries[4].1, (S_IFDIR >> 12) as u8);
+
+        // Reading continues after the offset of the last entry returned
+        let rest = dirents(&fs.readdir(0, entries[2].0, 8192)?);
+        assert_eq!(rest[0].2, "b");
+        assert!(fs.readdir(0, entries.len() as u64, 8192)?.is_empty());
         Ok(())
     }
 
     #[test]
-    fn test_empty_file() -> Result<()> {
-        let fs = setup_test_fs()?;
-        let (name, entry) = create_test_file_entry(2, "empty.txt", None);
+    fn test_linux_setattr() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        // Only root may give a file away
+        fs.attach(0, None, "0", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        fs.lopen(1, 2)?;
+        fs.write(1, 0, b"hello world")?;
 
-        let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
-        bindings.insert(2, (name, entry));
+        let setattr = Setattr {
+            valid: SETATTR_MODE | SETATTR_SIZE | SETATTR_UID | SETATTR_MTIME | SETATTR_MTIME_SET,
+            mode: 0o600,
+            uid: 42,
+            size: 5,
+            mtime_sec: 1000,
+            mtime_nsec: 5,
+            ..Setattr::default()
+        };
+        fs.setattr(1, &setattr)?;
 
-        if let Some((_, entry)) = bindings.get(&2) {
-            assert!(entry.content.is_none());
-            assert_eq!(entry.attr.size, 0);
-        } else {
-            panic!("File not found");
+        let attr = fs.getattr(1, GETATTR_BASIC)?;
+        assert_eq!(attr.mode & 0o7777, 0o600);
+        assert_eq!((attr.uid, attr.size), (42, 5));
+        assert_eq!((attr.mtime_sec, attr.mtime_nsec), (1000, 5));
+        assert_eq!(fs.read(1, 0, 100)?, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn test_linux_setattr_rejects_times_out_of_range() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        let before = fs.getattr(1, GETATTR_BASIC)?;
+
+        let far = Setattr {
+            valid: SETATTR_MTIME | SETATTR_MTIME_SET | SETATTR_SIZE,
+            mtime_sec: u64::MAX,
+            ..Setattr::default()
+        };
+        assert!(matches!(fs.setattr(1, &far), Err(Error::InvalidArgument)));
+        let bad_nsec = Setattr {
+            valid: SETATTR_ATIME | SETATTR_ATIME_SET,
+            atime_nsec: u64::MAX,
+            ..Setattr::default()
+        };
+        assert!(matches!(fs.setattr(1, &bad_nsec), Err(Error::InvalidArgument)));
+
+        // Nothing was changed, and the namespace is still usable
+        let after = fs.getattr(1, GETATTR_BASIC)?;
+        assert_eq!((after.size, after.mtime_sec), (before.size, before.mtime_sec));
+        Ok(())
+    }
+
+    #[test]
+    fn test_linux_changes_check_permissions() -> Result<()> {
+        let mut owner = setup_walk_tree()?;
+        let mut other = owner.connection();
+        owner.attach(0, None, "user", "")?;
+        other.attach(0, None, "1234", "")?;
+        other.walk(0, 1, &names(&["a"]))?;
+        other.walk(0, 2, &names(&["a", "x"]))?;
+
+        // The root directory and a/x are only writable by their owner
+        let denied = |result: Result<()>| matches!(result, Err(Error::PermissionDenied));
+        let not_owner = |result: Result<()>| matches!(result, Err(Error::NotOwner));
+        assert!(denied(other.mkdir(0, "d", 0o755, DEFAULT_GID).map(drop)));
+        assert!(denied(other.symlink(0, "l", "a", DEFAULT_GID).map(drop)));
+        assert!(denied(
+            other
+                .mknod(0, "n", S_IFIFO | 0o644, 0, 0, DEFAULT_GID)
+                .map(drop)
+        ));
+        assert!(denied(other.link(0, 2, "hard")));
+        assert!(denied(other.renameat(0, "b", 0, "c")));
+        assert!(denied(other.unlinkat(1, "x", 0)));
+
+        let chmod = Setattr {
+            valid: SETATTR_MODE,
+            mode: 0o666,
+            ..Setattr::default()
+        };
+        let chown = Setattr {
+            valid: SETATTR_UID,
+            uid: 1234,
+            ..Setattr::default()
+        };
+        let truncate = Setattr {
+            valid: SETATTR_SIZE,
+            ..Setattr::default()
+        };
+        let utimes = Setattr {
+            valid: SETATTR_MTIME | SETATTR_MTIME_SET,
+            ..Setattr::default()
+        };
+        assert!(not_owner(other.setattr(2, &chmod)));
+        assert!(not_owner(other.setattr(2, &chown)));
+        assert!(denied(other.setattr(2, &truncate)));
+        assert!(not_owner(other.setattr(2, &utimes)));
+        assert!(denied(other.xattrcreate(2, "user.x", 1, 0)));
+
+        // The owner may change the mode and group, but only to their groups
+        owner.walk(0, 1, &names(&["a", "x"]))?;
+        owner.setattr(1, &chmod)?;
+        assert!(not_owner(owner.setattr(1, &chown)));
+        let chgrp = Setattr {
+            valid: SETATTR_GID,
+            gid: 4242,
+            ..Setattr::default()
+        };
+        assert!(not_owner(owner.setattr(1, &chgrp)));
+        assert!(not_owner(owner.mkdir(0, "d", 0o755, 4242).map(drop)));
+        owner.mkdir(0, "d", 0o755, DEFAULT_GID)?;
+
+        // Once a/x is writable by everyone, anyone may truncate it
+        other.setattr(2, &truncate)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_linux_renameat_and_unlinkat() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a"]))?;
+        fs.walk(0, 2, &names(&["b"]))?;
+
+        // Moving a/x over b/x replaces it
+        fs.renameat(1, "x", 2, "x")?;
+        assert!(fs.walk(0, 3, &names(&["a", "x"]))?.len() < 2);
+        assert_eq!(fs.walk(0, 3, &names(&["b", "x"]))?[1].path, 4);
+
+        fs.renameat(2, "x", 0, "moved")?;
+        assert_eq!(fs.walk(0, 4, &names(&["moved"]))?[0].path, 4);
+        assert!(fs.renameat(0, "a", 1, "inside").is_err());
+
+        assert!(fs.unlinkat(0, "a", 0).is_err());
+        assert!(fs.unlinkat(0, "moved", AT_REMOVEDIR).is_err());
+        fs.unlinkat(0, "moved", 0)?;
+        fs.unlinkat(0, "a", AT_REMOVEDIR)?;
+        assert!(fs.unlinkat(0, "a", AT_REMOVEDIR).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_linux_locks() -> Result<()> {
+        let fs = setup_walk_tree()?;
+        let mut first = fs.connection();
+        let mut second = fs.connection();
+        first.attach(0, None, "user", "")?;
+        first.walk(0, 1, &names(&["a", "x"]))?;
+        second.attach(0, None, "user", "")?;
+        second.walk(0, 1, &names(&["a", "x"]))?;
+
+        let write_lock = |proc_id, start, length| Flock {
+            typ: LOCK_TYPE_WRLCK,
+            start,
+            length,
+            proc_id,
+            client_id: "client".to_string(),
+            ..Flock::default()
+        };
+        assert_eq!(first.lock(1, &write_lock(1, 0, 10))?, LOCK_SUCCESS);
+        assert_eq!(second.lock(1, &write_lock(2, 5, 10))?, LOCK_BLOCKED);
+        assert_eq!(second.lock(1, &write_lock(2, 10, 0))?, LOCK_SUCCESS);
+
+        let conflict = second.getlock(1, &write_lock(2, 0, 1))?;
+        assert_eq!((conflict.typ, conflict.proc_id), (LOCK_TYPE_WRLCK, 1));
+
+        let unlock = Flock {
+            typ: LOCK_TYPE_UNLCK,
+            ..write_lock(1, 0, 0)
+        };
+        first.lock(1, &unlock)?;
+        assert_eq!(second.getlock(1, &write_lock(2, 0, 1))?.typ, LOCK_TYPE_UNLCK);
+        Ok(())
+    }
+
+    #[test]
+    fn test_linux_partial_unlock_keeps_the_rest() -> Result<()> {
+        let fs = setup_walk_tree()?;
+        let mut first = fs.connection();
+        let mut second = fs.connection();
+        first.attach(0, None, "user", "")?;
+        first.walk(0, 1, &names(&["a", "x"]))?;
+        second.attach(0, None, "user", "")?;
+        second.walk(0, 1, &names(&["a", "x"]))?;
+
+        let lock = |typ, start, length| Flock {
+            typ,
+            start,
+            length,
+            proc_id: 1,
+            client_id: "client".to_string(),
+            ..Flock::default()
+        };
+        assert_eq!(first.lock(1, &lock(LOCK_TYPE_WRLCK, 0, 0))?, LOCK_SUCCESS);
+        first.lock(1, &lock(LOCK_TYPE_UNLCK, 10, 10))?;
+
+        // The same process id on another connection is another owner
+        let held_at = |offset| second.getlock(1, &lock(LOCK_TYPE_WRLCK, offset, 1));
+        assert_eq!(held_at(9)?.typ, LOCK_TYPE_WRLCK);
+        assert_eq!(held_at(10)?.typ, LOCK_TYPE_UNLCK);
+        assert_eq!(held_at(19)?.typ, LOCK_TYPE_UNLCK);
+        let after = held_at(20)?;
+        assert_eq!((after.typ, after.start, after.length), (LOCK_TYPE_WRLCK, 20, 0));
+        assert_eq!(held_at(u64::MAX - 1)?.typ, LOCK_TYPE_WRLCK);
+        Ok(())
+    }
+
+    #[test]
+    fn test_linux_locks_are_released_with_their_fid() -> Result<()> {
+        let fs = setup_walk_tree()?;
+        let mut first = fs.connection();
+        let mut second = fs.connection();
+        first.attach(0, None, "user", "")?;
+        first.walk(0, 1, &names(&["a", "x"]))?;
+        first.walk(0, 2, &names(&["a", "x"]))?;
+        second.attach(0, None, "user", "")?;
+        second.walk(0, 1, &names(&["a", "x"]))?;
+
+        let write_lock = |proc_id, start| Flock {
+            typ: LOCK_TYPE_WRLCK,
+            start,
+            length: 1,
+            proc_id,
+            client_id: "client".to_string(),
+            ..Flock::default()
+        };
+        assert_eq!(first.lock(1, &write_lock(1, 0))?, LOCK_SUCCESS);
+        assert_eq!(first.lock(2, &write_lock(1, 1))?, LOCK_SUCCESS);
+        assert_eq!(second.lock(1, &write_lock(2, 0))?, LOCK_BLOCKED);
+
+        // Clunking a fid drops only the locks taken through it
+        first.clunk(1)?;
+        assert_eq!(second.lock(1, &write_lock(2, 0))?, LOCK_SUCCESS);
+        assert_eq!(second.lock(1, &write_lock(2, 1))?, LOCK_BLOCKED);
+
+        // A client that disconnects leaves none of its locks behind
+        first.clunk_all();
+        assert_eq!(second.lock(1, &write_lock(2, 1))?, LOCK_SUCCESS);
+        assert!(first.locks.lock().unwrap().values().flatten().all(|held| held.lock.proc_id == 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_linux_xattr_list_is_empty() -> Result<()> {
+        let mut fs = setup_walk_tree()?;
+        fs.attach(0, None, "user", "")?;
+
+        assert_eq!(fs.xattrwalk(0, 1, "")?, 0);
+        assert!(fs.read(1, 0, 100)?.is_empty());
+        assert!(fs.xattrwalk(0, 2, "user.missing").is_err());
+        assert!(fs.xattrcreate(0, "user.x", 1, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattrs_pass_through_to_backing_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("x");
+        fs::write(&path, b"data")?;
+        let mut fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let entry = &mut bindings.get_mut(&4).unwrap().1;
+            entry.backing = Some(Backing::new(path.clone(), &fs::metadata(&path)?));
+        }
+
+        let name = OsStr::new("user.checksum");
+        fs.set_entry_xattr(4, name, b"abc", 0)?;
+        assert_eq!(get_xattr(&path, name)?, b"abc");
+        assert_eq!(fs.entry_xattr(4, name)?, b"abc");
+        assert_eq!(fs.entry_xattrs(&root(), 4)?, b"user.checksum\0");
+        let missing = fs.entry_xattr(4, OsStr::new("user.none"));
+        assert_eq!(missing.unwrap_err().errno(), libc::ENODATA);
+
+        fs.attach(0, None, "user", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+        assert_eq!(fs.xattrwalk(1, 2, "user.checksum")?, 3);
+        assert_eq!(fs.read(2, 0, 100)?, b"abc");
+
+        fs.walk(1, 3, &[])?;
+        fs.xattrcreate(3, "user.label", 5, 0)?;
+        assert!(matches!(fs.write(3, 3, b"too long"), Err(Error::InvalidArgument)));
+        fs.write(3, 0, b"lab")?;
+        fs.write(3, 3, b"el")?;
+        fs.clunk(3)?;
+        assert_eq!(get_xattr(&path, OsStr::new("user.label"))?, b"label");
+
+        // An empty value removes the attribute
+        fs.walk(1, 4, &[])?;
+        fs.xattrcreate(4, "user.label", 0, 0)?;
+        fs.clunk(4)?;
+        assert!(fs.entry_xattr(4, OsStr::new("user.label")).is_err());
+        fs.remove_entry_xattr(4, name)?;
+        assert!(fs.entry_xattrs(&root(), 4)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattrs_follow_namespace_rules() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("x");
+        fs::write(&path, b"data")?;
+        set_xattr(&path, OsStr::new("user.checksum"), b"abc", 0)?;
+        let mut fs = setup_walk_tree()?;
+        {
+            let mut bindings = fs.namespace_manager.bindings.lock().unwrap();
+            let entry = &mut bindings.get_mut(&4).unwrap().1;
+            entry.backing = Some(Backing::new(path.clone(), &fs::metadata(&path)?));
         }
+        fs.attach(0, None, "1234", "")?;
+        fs.walk(0, 1, &names(&["a", "x"]))?;
+
+        // Others may read the user attributes of a file they can read, but
+        // not change them
+        assert_eq!(fs.xattrwalk(1, 2, "user.checksum")?, 3);
+        assert_eq!(fs.read(2, 0, 100)?, b"abc");
+        assert_eq!(fs.xattrwalk(1, 3, "")?, b"user.checksum\0".len() as u64);
+        let denied = |result: Result<()>| matches!(result, Err(Error::PermissionDenied));
+        assert!(denied(fs.xattrcreate(1, "user.label", 5, 0)));
+
+        // Trusted and security attributes are root's
+        let not_owner = |result: Result<()>| matches!(result, Err(Error::NotOwner));
+        assert!(not_owner(fs.xattrcreate(1, "trusted.label", 5, 0)));
+        assert!(not_owner(fs.xattrcreate(1, "security.capability", 5, 0)));
+        let hidden = fs.xattrwalk(1, 4, "trusted.label");
+        assert!(matches!(hidden, Err(Error::NoAttribute)));
+        let cred = fs.fids.lock().unwrap()[&1].credentials();
+        fs.check_xattr(&cred, 4, OsStr::new("security.selinux"), ACCESS_READ)?;
+        assert!(fs.check_xattr(&root(), 4, OsStr::new("trusted.label"), ACCESS_WRITE).is_ok());
+
+        // Other namespaces are not supported, and a name needs more than
+        // its namespace
+        let unsupported = fs.xattrwalk(1, 5, "system.posix_acl_access");
+        assert!(matches!(unsupported, Err(Error::NotSupported)));
+        assert!(matches!(fs.xattrcreate(1, "user.", 1, 0), Err(Error::InvalidArgument)));
+
+        // Without read permission user attributes cannot be read either
+        fs.update_attr(4, |attr| attr.perm = 0o600)?;
+        assert!(matches!(fs.xattrwalk(1, 6, "user.checksum"), Err(Error::PermissionDenied)));
+        assert_eq!(get_xattr(&path, OsStr::new("user.checksum"))?, b"abc");
         Ok(())
     }
 }
@@ -0,0 +1,1105 @@
+//! 9P network server.
+//!
+//! This module exposes a `NineP` filesystem to stock 9P clients such as the
+//! Linux v9fs driver or plan9port's `9p` tool, either over TCP or over a Unix
+//! domain socket. Every connection reads framed T-messages, dispatches them to
+//! the matching `NineP` method and writes the encoded R-message back. Requests
+//! run concurrently and may be cancelled with Tflush.
+
+use super::codec::{Dialect, Rmessage, Tmessage, HEADER_SIZE, IOHDRSZ, NOFID, NONUNAME};
+use super::constants::MAX_MSIZE;
+use super::error::Error;
+use super::proto::{NineP, OpenFlags};
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
+
+/// A 9P server exporting a `NineP` filesystem.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use froggr::modules::server::Server;
+/// use froggr::NineP;
+/// use std::path::PathBuf;
+///
+/// # async fn run() -> anyhow::Result<()> {
+/// let fs = NineP::new(PathBuf::from("/tmp/test"))?;
+/// Server::new(fs).serve_tcp("127.0.0.1:564").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Server {
+    /// The filesystem served to every connection.
+    fs: NineP,
+}
+
+impl Server {
+    /// Creates a new server for the given filesystem.
+    ///
+    /// # Arguments
+    /// * `fs` - The 9P filesystem to export.
+    pub fn new(fs: NineP) -> Self {
+        Self { fs }
+    }
+
+    /// Binds a TCP address and serves clients until an error occurs.
+    ///
+    /// # Arguments
+    /// * `addr` - The address to listen on, e.g. `127.0.0.1:564`.
+    pub async fn serve_tcp(&self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("9P server listening on {}", listener.local_addr()?);
+        self.serve(listener).await
+    }
+
+    /// Accepts clients from an already bound TCP listener.
+    ///
+    /// Each client is handled on its own task, so a slow client does not
+    /// block the others.
+    ///
+    /// # Arguments
+    /// * `listener` - The listener to accept connections from.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            info!("Accepted 9P connection from {}", peer);
+            let fs = self.fs.connection();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle_connection(stream, fs).await {
+                    error!("9P connection from {} failed: {}", peer, e);
+                }
+                info!("9P connection from {} closed", peer);
+            });
+        }
+    }
+
+    /// Binds a Unix domain socket and serves clients until an error occurs.
+    ///
+    /// # Arguments
+    /// * `path` - The filesystem path of the socket to create.
+    pub async fn serve_unix(&self, path: &Path) -> Result<()> {
+        let listener = UnixListener::bind(path)?;
+        info!("9P server listening on {}", path.display());
+        self.serve_unix_listener(listener).await
+    }
+
+    /// Accepts clients from an already bound Unix domain socket.
+    ///
+    /// # Arguments
+    /// * `listener` - The listener to accept connections from.
+    pub async fn serve_unix_listener(&self, listener: UnixListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            info!("Accepted 9P connection on Unix socket");
+            let fs = self.fs.connection();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle_connection(stream, fs).await {
+                    error!("9P connection on Unix socket failed: {}", e);
+                }
+                info!("9P connection on Unix socket closed");
+            });
+        }
+    }
+
+    /// Serves a single client connection until it is closed.
+    ///
+    /// Requests are executed concurrently, so a slow request does not hold
+    /// up the ones behind it and can be cancelled with Tflush. When the
+    /// connection ends, requests that have not started are abandoned, those
+    /// running are stopped and waited for, and all fids held by the client
+    /// are clunked, whether it was closed cleanly or failed.
+    ///
+    /// # Arguments
+    /// * `stream` - The connection to read requests from and write replies to.
+    /// * `fs` - The connection's handle on the filesystem, see `NineP::connection`.
+    pub async fn handle_connection<S>(stream: S, mut fs: NineP) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let connection = Connection {
+            writer: Arc::new(AsyncMutex::new(writer)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let result = connection.process_requests(&mut reader, &mut fs).await;
+        connection.cancel_all().await;
+        fs.clunk_all();
+        result
+    }
+
+    /// Executes a single request against the filesystem.
+    ///
+    /// Failures are turned into `Rerror` or `Rlerror` replies describing the error.
+    ///
+    /// # Arguments
+    /// * `fs` - The filesystem to operate on.
+    /// * `request` - The decoded request.
+    ///
+    /// # Returns
+    /// The reply to send to the client.
+    pub fn dispatch(fs: &mut NineP, request: Tmessage) -> Rmessage {
+        let result = Self::execute(fs, request);
+        result.unwrap_or_else(|e| Self::error_reply(fs.dialect(), e))
+    }
+
+    // Executes a request, leaving the reporting of failures to the caller
+    fn execute(fs: &mut NineP, request: Tmessage) -> Result<Rmessage, Error> {
+        match request {
+            Tmessage::Version { msize, version } => fs
+                .version(&version, msize)
+                .map(|(msize, version)| Rmessage::Version { msize, version }),
+            Tmessage::Auth {
+                afid,
+                uname,
+                aname,
+                n_uname,
+            } => fs
+                .auth(&user(uname, n_uname), &aname, afid)
+                .map(|aqid| Rmessage::Auth { aqid }),
+            Tmessage::Attach {
+                fid,
+                afid,
+                uname,
+                aname,
+                n_uname,
+            } => {
+                let afid = if afid == NOFID { None } else { Some(afid) };
+                fs.attach(fid, afid, &user(uname, n_uname), &aname)
+                    .map(|qid| Rmessage::Attach { qid })
+            }
+            // Cancellation needs the connection's pending requests and is
+            // done before dispatch, see `handle_connection`
+            Tmessage::Flush { .. } => Ok(Rmessage::Flush),
+            Tmessage::Walk {
+                fid,
+                newfid,
+                wnames,
+            } => fs
+                .walk(fid, newfid, &wnames)
+                .map(|wqids| Rmessage::Walk { wqids }),
+            Tmessage::Open { fid, mode } => {
+                fs.open(fid, OpenFlags(mode as u32))
+                    .map(|(qid, msize)| Rmessage::Open {
+                        qid,
+                        iounit: msize.saturating_sub(IOHDRSZ),
+                    })
+            }
+            Tmessage::Create {
+                fid,
+                name,
+                perm,
+                mode,
+                extension,
+            } => fs
+                .create(fid, &name, perm, OpenFlags(mode as u32), &extension)
+                .map(|(qid, msize)| Rmessage::Create {
+                    qid,
+                    iounit: msize.saturating_sub(IOHDRSZ),
+                }),
+            Tmessage::Read { fid, offset, count } => fs
+                .read(fid, offset, count)
+                .map(|data| Rmessage::Read { data }),
+            Tmessage::Write { fid, offset, data } => fs
+                .write(fid, offset, &data)
+                .map(|count| Rmessage::Write { count }),
+            Tmessage::Clunk { fid } => fs.clunk(fid).map(|_| Rmessage::Clunk),
+            Tmessage::Remove { fid } => fs.remove(fid).map(|_| Rmessage::Remove),
+            Tmessage::Stat { fid } => fs.stat(fid).map(|stat| Rmessage::Stat { stat }),
+            Tmessage::Wstat { fid, stat } => fs.wstat(fid, &stat).map(|_| Rmessage::Wstat),
+            Tmessage::Statfs { fid } => fs.statfs(fid).map(|statfs| Rmessage::Statfs { statfs }),
+            Tmessage::Lopen { fid, flags } => {
+                fs.lopen(fid, flags).map(|(qid, msize)| Rmessage::Lopen {
+                    qid,
+                    iounit: msize.saturating_sub(IOHDRSZ),
+                })
+            }
+            Tmessage::Lcreate {
+                fid,
+                name,
+                flags,
+                mode,
+                gid,
+            } => fs
+                .lcreate(fid, &name, flags, mode, gid)
+                .map(|(qid, msize)| Rmessage::Lcreate {
+                    qid,
+                    iounit: msize.saturating_sub(IOHDRSZ),
+                }),
+            Tmessage::Symlink {
+                fid,
+                name,
+                symtgt,
+                gid,
+            } => fs
+                .symlink(fid, &name, &symtgt, gid)
+                .map(|qid| Rmessage::Symlink { qid }),
+            Tmessage::Mknod {
+                dfid,
+                name,
+                mode,
+                major,
+                minor,
+                gid,
+            } => fs
+                .mknod(dfid, &name, mode, major, minor, gid)
+                .map(|qid| Rmessage::Mknod { qid }),
+            Tmessage::Readlink { fid } => fs
+                .readlink(fid)
+                .map(|target| Rmessage::Readlink { target }),
+            Tmessage::Getattr { fid, request_mask } => fs
+                .getattr(fid, request_mask)
+                .map(|attr| Rmessage::Getattr { attr }),
+            Tmessage::Setattr { fid, attr } => fs.setattr(fid, &attr).map(|_| Rmessage::Setattr),
+            Tmessage::Xattrwalk { fid, newfid, name } => fs
+                .xattrwalk(fid, newfid, &name)
+                .map(|size| Rmessage::Xattrwalk { size }),
+            Tmessage::Xattrcreate {
+                fid,
+                name,
+                attr_size,
+                flags,
+            } => fs
+                .xattrcreate(fid, &name, attr_size, flags)
+                .map(|_| Rmessage::Xattrcreate),
+            Tmessage::Readdir { fid, offset, count } => fs
+                .readdir(fid, offset, count)
+                .map(|data| Rmessage::Readdir { data }),
+            Tmessage::Fsync { fid, .. } => fs.fsync(fid).map(|_| Rmessage::Fsync),
+            Tmessage::Lock { fid, lock } => {
+                fs.lock(fid, &lock).map(|status| Rmessage::Lock { status })
+            }
+            Tmessage::Getlock { fid, lock } => {
+                fs.getlock(fid, &lock).map(|lock| Rmessage::Getlock { lock })
+            }
+            Tmessage::Link { dfid, fid, name } => fs.link(dfid, fid, &name).map(|_| Rmessage::Link),
+            Tmessage::Mkdir {
+                dfid,
+                name,
+                mode,
+                gid,
+            } => fs
+                .mkdir(dfid, &name, mode, gid)
+                .map(|qid| Rmessage::Mkdir { qid }),
+            Tmessage::Renameat {
+                olddirfid,
+                oldname,
+                newdirfid,
+                newname,
+            } => fs
+                .renameat(olddirfid, &oldname, newdirfid, &newname)
+                .map(|_| Rmessage::Renameat),
+            Tmessage::Unlinkat { dirfd, name, flags } => fs
+                .unlinkat(dirfd, &name, flags)
+                .map(|_| Rmessage::Unlinkat),
+        }
+    }
+
+    // Builds the error reply of the connection's dialect
+    fn error_reply(dialect: Dialect, error: Error) -> Rmessage {
+        // 9P2000.L reports only the errno
+        if dialect == Dialect::Linux {
+            Rmessage::Lerror {
+                ecode: error.errno() as u32,
+            }
+        } else {
+            Rmessage::Error {
+                ename: error.to_string(),
+                errno: error.errno() as u32,
+            }
+        }
+    }
+}
+
+/// The write half of a connection and the requests still awaiting a reply.
+///
+/// A request runs on a blocking thread. Tflush cancels a request that is
+/// still queued, which then never runs, and stops one that is running where
+/// it next checks for it, see `NineP::cancelled_by`. A request stopped
+/// before it took effect is not answered. One that completed first, or a
+/// write cut short, is answered before the Rflush. Either way the client
+/// knows from the replies it got by the Rflush whether the request took
+/// effect.
+struct Connection<W> {
+    writer: Arc<AsyncMutex<W>>,
+    pending: Arc<Mutex<HashMap<u16, InFlight>>>,
+}
+
+// A request that has been started and not yet answered
+struct InFlight {
+    stage: Arc<Mutex<Stage>>,
+    // Asks the running request to stop
+    cancel: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+// How far a request got, as seen by its task and by Tflush
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    // Waiting for a blocking thread
+    Queued,
+    // Executing or executed, and to be answered unless it was stopped
+    Running,
+    // Flushed before it ran, or abandoned, and not to be answered
+    Flushed,
+}
+
+impl<W> Clone for Connection<W> {
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<W> Connection<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    async fn process_requests<R>(&self, reader: &mut R, fs: &mut NineP) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        while let Some(frame) = read_frame(reader).await? {
+            if frame.len() > fs.msize() as usize {
+                // The header is intact, so the client can be told which
+                // request was refused
+                let tag = u16::from_le_bytes([frame[5], frame[6]]);
+                let reply = Server::error_reply(fs.dialect(), Error::MessageTooLarge);
+                self.send(tag, reply, fs.dialect()).await?;
+                continue;
+            }
+            let (tag, request) = match Tmessage::decode(&frame, fs.dialect()) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    // Without a valid header there is no tag to reply to
+                    return Err(anyhow!("Malformed 9P message: {}", e));
+                }
+            };
+            debug!("<- tag {} {:?}", tag, request);
+
+            let started = match request {
+                Tmessage::Flush { oldtag } => self.flush(tag, oldtag, fs.dialect()),
+                Tmessage::Version { .. } => {
+                    // A new version aborts all outstanding requests
+                    self.cancel_all().await;
+                    let reply = Server::dispatch(fs, request);
+                    // Rversion is laid out the same in every dialect
+                    self.send(tag, reply, fs.dialect()).await?;
+                    true
+                }
+                request => self.start(tag, request, fs),
+            };
+            if !started {
+                warn!("Tag {} is already in use", tag);
+                let reply = Server::error_reply(fs.dialect(), Error::InUse);
+                self.send(tag, reply, fs.dialect()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Executes a request on a blocking thread and replies unless it gets
+    // flushed. Returns false, without running the request, if its tag is
+    // still pending.
+    fn start(&self, tag: u16, request: Tmessage, fs: &NineP) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.contains_key(&tag) {
+            return false;
+        }
+
+        let stage = Arc::new(Mutex::new(Stage::Queued));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let claim = stage.clone();
+        let mut fs = fs.cancelled_by(cancel.clone());
+        let dialect = fs.dialect();
+        let dispatch = tokio::task::spawn_blocking(move || {
+            let mut stage = claim.lock().unwrap();
+            if *stage == Stage::Flushed {
+                return None;
+            }
+            *stage = Stage::Running;
+            drop(stage);
+            match Server::execute(&mut fs, request) {
+                Err(Error::Interrupted) => None,
+                result => Some(result.unwrap_or_else(|e| Server::error_reply(fs.dialect(), e))),
+            }
+        });
+
+        let connection = self.clone();
+        let claim = stage.clone();
+        // The task cannot finish before it is registered, as it needs the
+        // pending lock held here to release its tag
+        let task = tokio::spawn(async move {
+            let reply = match dispatch.await {
+                Ok(reply) => reply,
+                Err(e) => Some(Server::error_reply(dialect, Error::Io(e.into()))),
+            };
+            connection.answer(tag, &claim, reply, dialect).await;
+        });
+        pending.insert(tag, InFlight { stage, cancel, task });
+        true
+    }
+
+    // Stops the request with tag `oldtag`, see `InFlight::cancel`, and
+    // acknowledges the Tflush once the request has stopped or been
+    // answered. Other requests are read and started meanwhile. Returns
+    // false if the tag of the Tflush is still pending.
+    fn flush(&self, tag: u16, oldtag: u16, dialect: Dialect) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.contains_key(&tag) {
+            return false;
+        }
+        let request = pending.remove(&oldtag);
+
+        // The Tflush is pending itself, so that the connection does not
+        // end before it is answered
+        let stage = Arc::new(Mutex::new(Stage::Running));
+        let claim = stage.clone();
+        let connection = self.clone();
+        let task = tokio::spawn(async move {
+            if let Some(request) = request {
+                debug!("Flushing tag {}", oldtag);
+                request.cancel(false).await;
+            }
+            connection.answer(tag, &claim, Some(Rmessage::Flush), dialect).await;
+        });
+        let cancel = Arc::new(AtomicBool::new(false));
+        pending.insert(tag, InFlight { stage, cancel, task });
+        true
+    }
+
+    // Releases the tag of a request and writes its reply, if it has one and
+    // was not abandoned. The tag is released first, so that the client may
+    // reuse it as soon as it has the reply.
+    async fn answer(
+        &self,
+        tag: u16,
+        claim: &Arc<Mutex<Stage>>,
+        reply: Option<Rmessage>,
+        dialect: Dialect,
+    ) {
+        let mut writer = self.writer.lock().await;
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.get(&tag).is_some_and(|r| Arc::ptr_eq(&r.stage, claim)) {
+                pending.remove(&tag);
+            }
+        }
+        let Some(reply) = reply else {
+            debug!("Tag {} was stopped and is not answered", tag);
+            return;
+        };
+        if *claim.lock().unwrap() == Stage::Flushed {
+            debug!("Dropping reply to abandoned tag {}", tag);
+            return;
+        }
+        debug!("-> tag {} {:?}", tag, reply);
+        if let Err(e) = writer.write_all(&encode_reply(tag, reply, dialect)).await {
+            error!("Failed to send reply to tag {}: {}", tag, e);
+        }
+    }
+
+    // Abandons every request that has not been answered yet, waiting for
+    // those already running to finish
+    async fn cancel_all(&self) {
+        let requests: Vec<InFlight> = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.drain().map(|(_, request)| request).collect()
+        };
+        for request in requests {
+            request.cancel(true).await;
+        }
+    }
+
+    async fn send(&self, tag: u16, reply: Rmessage, dialect: Dialect) -> Result<()> {
+        debug!("-> tag {} {:?}", tag, reply);
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&encode_reply(tag, reply, dialect)).await?;
+        Ok(())
+    }
+}
+
+impl InFlight {
+    // Keeps the request from running if it has not started, and otherwise
+    // asks it to stop and waits for it to finish. A running request that
+    // completes anyway is still answered unless `abandon` is set.
+    async fn cancel(self, abandon: bool) {
+        let queued = {
+            let mut stage = self.stage.lock().unwrap();
+            let queued = *stage == Stage::Queued;
+            if queued || abandon {
+                *stage = Stage::Flushed;
+            }
+            queued
+        };
+        self.cancel.store(true, Ordering::Relaxed);
+        if queued {
+            self.task.abort();
+        } else if let Err(e) = self.task.await {
+            error!("Request task failed: {}", e);
+        }
+    }
+}
+
+// Encodes a reply, or an error in its place if the reply does not fit the
+// 9P format
+fn encode_reply(tag: u16, reply: Rmessage, dialect: Dialect) -> Vec<u8> {
+    reply.encode(tag, dialect).unwrap_or_else(|e| {
+        error!("Failed to encode reply to tag {}: {}", tag, e);
+        Server::error_reply(dialect, Error::MessageTooLarge)
+            .encode(tag, dialect)
+            .expect("error replies always fit")
+    })
+}
+
+// 9P2000.u clients may identify the user by number only
+fn user(uname: String, n_uname: u32) -> String {
+    if uname.is_empty() && n_uname != NONUNAME {
+        n_uname.to_string()
+    } else {
+        uname
+    }
+}
+
+/// Reads one complete 9P message from a stream.
+///
+/// # Returns
+/// * `Ok(Some(frame))` with the message bytes, including the size header
+/// * `Ok(None)` if the peer closed the connection between messages
+/// * `Err` if the stream failed or the size header is invalid
+async fn read_frame<S>(stream: &mut S) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut size_buf = [0u8; 4];
+    match stream.read_exact(&mut size_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let size = u32::from_le_bytes(size_buf);
+    if (size as usize) < HEADER_SIZE || size > MAX_MSIZE {
+        return Err(anyhow!("Invalid 9P message size: {}", size));
+    }
+
+    let mut frame = vec![0u8; size as usize];
+    frame[..4].copy_from_slice(&size_buf);
+    stream.read_exact(&mut frame[4..]).await?;
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::codec::NOTAG;
+    use crate::modules::constants::DEFAULT_UID;
+    use crate::modules::proto::Backing;
+    use tempfile::tempdir;
+    use tokio::net::{TcpStream, UnixStream};
+
+    async fn roundtrip<S>(stream: &mut S, tag: u16, request: Tmessage) -> Rmessage
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        roundtrip_as(stream, Dialect::Plan9, tag, request).await
+    }
+
+    async fn roundtrip_as<S>(
+        stream: &mut S,
+        dialect: Dialect,
+        tag: u16,
+        request: Tmessage,
+    ) -> Rmessage
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream
+            .write_all(&request.encode(tag, dialect).unwrap())
+            .await
+            .unwrap();
+        let frame = read_frame(stream).await.unwrap().unwrap();
+        let (reply_tag, reply) = Rmessage::decode(&frame, dialect).unwrap();
+        assert_eq!(reply_tag, tag);
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp_version_and_attach() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let fs = NineP::new(temp_dir.path().to_path_buf())?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(fs);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let reply = roundtrip(
+            &mut stream,
+            NOTAG,
+            Tmessage::Version {
+                msize: 8192,
+                version: "9P2000".to_string(),
+            },
+        )
+        .await;
+        assert_eq!(
+            reply,
+            Rmessage::Version {
+                msize: 8192,
+                version: "9P2000".to_string()
+            }
+        );
+
+        let reply = roundtrip(
+            &mut stream,
+            1,
+            Tmessage::Attach {
+                fid: 0,
+                afid: NOFID,
+                uname: "glenda".to_string(),
+                aname: String::new(),
+                n_uname: NONUNAME,
+            },
+        )
+        .await;
+        assert!(matches!(reply, Rmessage::Attach { qid } if qid.path == 1));
+
+        // Errors are reported as Rerror without closing the connection
+        let reply = roundtrip(&mut stream, 2, Tmessage::Clunk { fid: 42 }).await;
+        assert!(matches!(reply, Rmessage::Error { .. }));
+        let reply = roundtrip(&mut stream, 3, Tmessage::Clunk { fid: 0 }).await;
+        assert_eq!(reply, Rmessage::Clunk);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_unix_socket() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let fs = NineP::new(temp_dir.path().join("root"))?;
+        let socket_path = temp_dir.path().join("test.9p");
+        let listener = UnixListener::bind(&socket_path)?;
+        let server = Server::new(fs);
+        tokio::spawn(async move { server.serve_unix_listener(listener).await });
+
+        let mut stream = UnixStream::connect(&socket_path).await?;
+        let reply = roundtrip(
+            &mut stream,
+            NOTAG,
+            Tmessage::Version {
+                msize: 8192,
+                version: "9P2000".to_string(),
+            },
+        )
+        .await;
+        assert!(matches!(reply, Rmessage::Version { msize: 8192, .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversize_message_is_rejected() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let fs = NineP::new(temp_dir.path().to_path_buf())?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(fs);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let reply = roundtrip(
+            &mut stream,
+            NOTAG,
+            Tmessage::Version {
+                msize: 8192,
+                version: "9P2000".to_string(),
+            },
+        )
+        .await;
+        assert!(matches!(reply, Rmessage::Version { msize: 8192, .. }));
+
+        let write = Tmessage::Write {
+            fid: 0,
+            offset: 0,
+            data: vec![0; 8192],
+        };
+        let reply = roundtrip(&mut stream, 1, write).await;
+        assert!(matches!(reply, Rmessage::Error { ename, .. } if ename == "message too large"));
+
+        // The connection stays usable after the rejected message
+        let reply = roundtrip(&mut stream, 2, Tmessage::Clunk { fid: 0 }).await;
+        assert!(matches!(reply, Rmessage::Error { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_cancels_pending_request() -> Result<()> {
+        // With a single blocking thread, a request queued behind a stuck one
+        // has certainly not started when it is flushed
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .max_blocking_threads(1)
+            .build()?;
+        runtime.block_on(async {
+            let temp_dir = tempdir()?;
+            let fs = NineP::new(temp_dir.path().to_path_buf())?;
+            let bindings = fs.namespace_manager.bindings.clone();
+            let (mut stream, server_end) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(Server::handle_connection(server_end, fs.connection()));
+
+            let version = Tmessage::Version {
+                msize: 8192,
+                version: "9P2000".to_string(),
+            };
+            roundtrip(&mut stream, NOTAG, version).await;
+            let attach = Tmessage::Attach {
+                fid: 0,
+                afid: NOFID,
+                uname: "glenda".to_string(),
+                aname: String::new(),
+                n_uname: NONUNAME,
+            };
+            roundtrip(&mut stream, 1, attach).await;
+            let clone = Tmessage::Walk {
+                fid: 0,
+                newfid: 1,
+                wnames: Vec::new(),
+            };
+            roundtrip(&mut stream, 2, clone).await;
+
+            // Hold the namespace so that the Tstat below cannot complete
+            let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            let holder = std::thread::spawn(move || {
+                let _bindings = bindings.lock().unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            });
+            locked_rx.recv()?;
+
+            let stat = Tmessage::Stat { fid: 0 };
+            stream.write_all(&stat.encode(3, Dialect::Plan9)?).await?;
+            let create = Tmessage::Create {
+                fid: 1,
+                name: "flushed".to_string(),
+                perm: 0o644,
+                mode: 0,
+                extension: String::new(),
+            };
+            stream.write_all(&create.encode(4, Dialect::Plan9)?).await?;
+            let reply = roundtrip(&mut stream, 5, Tmessage::Flush { oldtag: 4 }).await;
+            assert_eq!(reply, Rmessage::Flush);
+
+            // The running Tstat is answered, the flushed Tcreate never is
+            release_tx.send(())?;
+            holder.join().unwrap();
+            let frame = read_frame(&mut stream).await?.unwrap();
+            let (tag, reply) = Rmessage::decode(&frame, Dialect::Plan9)?;
+            assert_eq!(tag, 3);
+            assert!(matches!(reply, Rmessage::Stat { .. }));
+
+            // The Tcreate left no trace: no file, and fid 1 is not open
+            let walk = Tmessage::Walk {
+                fid: 0,
+                newfid: 2,
+                wnames: vec!["flushed".to_string()],
+            };
+            let reply = roundtrip(&mut stream, 6, walk).await;
+            assert!(matches!(reply, Rmessage::Error { .. }));
+            let read = Tmessage::Read {
+                fid: 1,
+                offset: 0,
+                count: 100,
+            };
+            let reply = roundtrip(&mut stream, 7, read).await;
+            assert!(matches!(reply, Rmessage::Error { .. }));
+
+            // Flushing a request that was already answered is acknowledged too
+            let reply = roundtrip(&mut stream, 8, Tmessage::Flush { oldtag: 6 }).await;
+            assert_eq!(reply, Rmessage::Flush);
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_flush_stops_running_request() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("file");
+        std::fs::write(&path, b"data")?;
+        let mut fs = NineP::new(temp_dir.path().to_path_buf())?;
+        let bindings = fs.namespace_manager.bindings.clone();
+
+        // The connection is set up by hand, so that the test can see when
+        // the request is running
+        let (mut stream, server_end) = tokio::io::duplex(64 * 1024);
+        let (mut reader, writer) = tokio::io::split(server_end);
+        let connection = Connection {
+            writer: Arc::new(AsyncMutex::new(writer)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let pending = connection.pending.clone();
+        tokio::spawn(async move { connection.process_requests(&mut reader, &mut fs).await });
+
+        let version = Tmessage::Version {
+            msize: 8192,
+            version: "9P2000".to_string(),
+        };
+        roundtrip(&mut stream, NOTAG, version).await;
+        let attach = Tmessage::Attach {
+            fid: 0,
+            afid: NOFID,
+            uname: "glenda".to_string(),
+            aname: String::new(),
+            n_uname: NONUNAME,
+        };
+        roundtrip(&mut stream, 1, attach).await;
+        let create = Tmessage::Create {
+            fid: 0,
+            name: "file".to_string(),
+            perm: 0o666,
+            mode: OpenFlags::O_RDWR as u8,
+            extension: String::new(),
+        };
+        let reply = roundtrip(&mut stream, 2, create).await;
+        let Rmessage::Create { qid, .. } = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        {
+            let mut bindings = bindings.lock().unwrap();
+            let entry = &mut bindings.get_mut(&qid.path).unwrap().1;
+            entry.backing = Some(Backing::new(path.clone(), &std::fs::metadata(&path)?));
+        }
+
+        // Hold the namespace so that the Twrite below cannot complete
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = std::thread::spawn(move || {
+            let _bindings = bindings.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        locked_rx.recv()?;
+
+        let write = Tmessage::Write {
+            fid: 0,
+            offset: 0,
+            data: b"flushed".to_vec(),
+        };
+        stream.write_all(&write.encode(3, Dialect::Plan9)?).await?;
+        let running = || pending.lock().unwrap().get(&3).map(|r| *r.stage.lock().unwrap());
+        while running() != Some(Stage::Running) {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let flush = Tmessage::Flush { oldtag: 3 };
+        stream.write_all(&flush.encode(4, Dialect::Plan9)?).await?;
+
+        // Requests are still read while the flush waits for the Twrite
+        let reply = roundtrip(&mut stream, 5, Tmessage::Flush { oldtag: 99 }).await;
+        assert_eq!(reply, Rmessage::Flush);
+
+        // Once the Twrite can go on it stops, unanswered, and only then is
+        // the flush acknowledged
+        release_tx.send(())?;
+        holder.join().unwrap();
+        let frame = read_frame(&mut stream).await?.unwrap();
+        assert_eq!(Rmessage::decode(&frame, Dialect::Plan9)?, (4, Rmessage::Flush));
+        assert_eq!(std::fs::read(&path)?, b"data");
+
+        // The tag of the flushed request may be used again
+        let read = Tmessage::Read {
+            fid: 0,
+            offset: 0,
+            count: 100,
+        };
+        let reply = roundtrip(&mut stream, 3, read).await;
+        assert_eq!(reply, Rmessage::Read { data: b"data".to_vec() });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fids_are_scoped_to_a_connection() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let fs = NineP::new(temp_dir.path().to_path_buf())?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(fs);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let attach = Tmessage::Attach {
+            fid: 0,
+            afid: NOFID,
+            uname: "glenda".to_string(),
+            aname: String::new(),
+            n_uname: NONUNAME,
+        };
+        let mut first = TcpStream::connect(addr).await?;
+        let mut second = TcpStream::connect(addr).await?;
+
+        // Both clients may use fid 0 without seeing each other
+        let reply = roundtrip(&mut first, 1, attach.clone()).await;
+        assert!(matches!(reply, Rmessage::Attach { .. }));
+        let reply = roundtrip(&mut second, 1, attach.clone()).await;
+        assert!(matches!(reply, Rmessage::Attach { .. }));
+        let reply = roundtrip(&mut first, 2, attach.clone()).await;
+        assert!(matches!(reply, Rmessage::Error { .. }));
+
+        // Closing the first connection releases its fids only
+        let reply = roundtrip(&mut first, 3, Tmessage::Clunk { fid: 0 }).await;
+        assert_eq!(reply, Rmessage::Clunk);
+        drop(first);
+        let reply = roundtrip(&mut second, 2, Tmessage::Clunk { fid: 0 }).await;
+        assert_eq!(reply, Rmessage::Clunk);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnecting_releases_locks() -> Result<()> {
+        use crate::modules::codec::{LOCK_BLOCKED, LOCK_SUCCESS, LOCK_TYPE_WRLCK};
+        use crate::modules::proto::Flock;
+
+        let temp_dir = tempdir()?;
+        let fs = NineP::new(temp_dir.path().to_path_buf())?;
+        let linux = Dialect::Linux;
+        let connect = || async {
+            let (mut stream, server_end) = tokio::io::duplex(64 * 1024);
+            let served = tokio::spawn(Server::handle_connection(server_end, fs.connection()));
+            let version = Tmessage::Version {
+                msize: 8192,
+                version: "9P2000.L".to_string(),
+            };
+            roundtrip_as(&mut stream, linux, NOTAG, version).await;
+            let attach = Tmessage::Attach {
+                fid: 0,
+                afid: NOFID,
+                uname: String::new(),
+                aname: String::new(),
+                n_uname: 0,
+            };
+            roundtrip_as(&mut stream, linux, 1, attach).await;
+            (stream, served)
+        };
+        let lock = |proc_id| Tmessage::Lock {
+            fid: 0,
+            lock: Flock {
+                typ: LOCK_TYPE_WRLCK,
+                proc_id,
+                client_id: "client".to_string(),
+                ..Flock::default()
+            },
+        };
+
+        let (mut first, first_served) = connect().await;
+        let (mut second, _) = connect().await;
+        let reply = roundtrip_as(&mut first, linux, 2, lock(1)).await;
+        assert_eq!(reply, Rmessage::Lock { status: LOCK_SUCCESS });
+        let reply = roundtrip_as(&mut second, linux, 2, lock(2)).await;
+        assert_eq!(reply, Rmessage::Lock { status: LOCK_BLOCKED });
+
+        // The first client goes away without unlocking
+        drop(first);
+        first_served.await??;
+        let reply = roundtrip_as(&mut second, linux, 3, lock(2)).await;
+        assert_eq!(reply, Rmessage::Lock { status: LOCK_SUCCESS });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unix_dialect() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let fs = NineP::new(temp_dir.path().to_path_buf())?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(fs);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let unix = Dialect::Unix;
+        let version = Tmessage::Version {
+            msize: 8192,
+            version: "9P2000.u".to_string(),
+        };
+        let reply = roundtrip_as(&mut stream, unix, NOTAG, version).await;
+        assert!(matches!(reply, Rmessage::Version { version, .. } if version == "9P2000.u"));
+
+        let attach = Tmessage::Attach {
+            fid: 0,
+            afid: NOFID,
+            uname: String::new(),
+            aname: String::new(),
+            n_uname: 1000,
+        };
+        let reply = roundtrip_as(&mut stream, unix, 1, attach).await;
+        assert!(matches!(reply, Rmessage::Attach { .. }));
+
+        let stat = roundtrip_as(&mut stream, unix, 2, Tmessage::Stat { fid: 0 }).await;
+        assert!(matches!(stat, Rmessage::Stat { stat } if stat.n_uid == DEFAULT_UID));
+
+        // Errors carry an errno
+        let reply = roundtrip_as(&mut stream, unix, 3, Tmessage::Clunk { fid: 42 }).await;
+        assert!(matches!(reply, Rmessage::Error { errno, .. } if errno == libc::EBADF as u32));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_linux_dialect() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let fs = NineP::new(temp_dir.path().to_path_buf())?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(fs);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        let linux = Dialect::Linux;
+        let version = Tmessage::Version {
+            msize: 8192,
+            version: "9P2000.L".to_string(),
+        };
+        let reply = roundtrip_as(&mut stream, linux, NOTAG, version).await;
+        assert!(matches!(reply, Rmessage::Version { version, .. } if version == "9P2000.L"));
+
+        let attach = Tmessage::Attach {
+            fid: 0,
+            afid: NOFID,
+            uname: String::new(),
+            aname: String::new(),
+            n_uname: 0,
+        };
+        roundtrip_as(&mut stream, linux, 1, attach).await;
+
+        let mkdir = Tmessage::Mkdir {
+            dfid: 0,
+            name: "dir".to_string(),
+            mode: 0o755,
+            gid: 0,
+        };
+        let reply = roundtrip_as(&mut stream, linux, 2, mkdir.clone()).await;
+        assert!(matches!(reply, Rmessage::Mkdir { .. }));
+
+        // Errors are reported as Rlerror
+        let reply = roundtrip_as(&mut stream, linux, 3, mkdir).await;
+        assert_eq!(
+            reply,
+            Rmessage::Lerror {
+                ecode: libc::EEXIST as u32
+            }
+        );
+
+        let getattr = Tmessage::Getattr {
+            fid: 0,
+            request_mask: crate::modules::codec::GETATTR_BASIC,
+        };
+        let reply = roundtrip_as(&mut stream, linux, 4, getattr).await;
+        assert!(matches!(reply, Rmessage::Getattr { attr } if attr.qid.path == 1));
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@
 //! and handles communication between the client and filesystem operations.
 //! It provides a thread-safe way to perform mount, bind, and unmount operations.
 
+use crate::modules::server::Server;
 use crate::FilesystemManager;
 use anyhow::Result;
 use log::{error, info, warn};
@@ -21,10 +22,13 @@ use nix::unistd::{fork, ForkResult};
 use nix::sys::signal::{self, Signal};
 use nix::unistd::Pid;
 use tokio::signal::ctrl_c;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use crate::BindMode;
+use crate::modules::namespace::SymlinkPolicy;
 use nix::libc::{posix_spawn, posix_spawnattr_t, posix_spawn_file_actions_t};
 use std::ffi::CString;
+use std::os::unix::net::{UnixListener, UnixStream};
+use tokio::sync::oneshot;
 
 /// Information about a running filesystem session.
 #[derive(Debug, Serialize, Deserialize)]
@@ -81,7 +85,7 @@ impl SessionManager {
             if session.root == root {
                 info!("Found existing session {} for root {}", session.id, root.display());
                 // Verify the session is still active
-                if let Ok(_) = signal::kill(Pid::from_raw(session.pid), Signal::SIGCONT) {
+                if signal::kill(Pid::from_raw(session.pid), Signal::SIGCONT).is_ok() {
                     info!("Reusing existing session {}", session.id);
                     return Ok(session.id);
                 } else {
@@ -100,11 +104,11 @@ impl SessionManager {
         
         // Prepare arguments for the new process
         let program = CString::new(std::env::current_exe()?.to_str().unwrap())?;
-        let mut args = vec![
+        let mut args = [
             CString::new(program.to_str().unwrap())?,
             CString::new("internal-session")?,
             CString::new(session_id.as_str())?,
-            CString::new(root.to_str().unwrap())?
+            CString::new(root.to_str().unwrap())?,
         ];
         
         // Create a vector of pointers to the args
@@ -190,7 +194,7 @@ impl SessionManager {
                     match entry_result {
                         Ok(entry) => {
                             info!("Processing entry: {:?}", entry.path());
-                            if entry.path().extension().map_or(false, |ext| ext == "json") {
+                            if entry.path().extension().is_some_and(|ext| ext == "json") {
                                 match fs::read_to_string(entry.path()) {
                                     Ok(content) => {
                                         info!("Read session file content");
@@ -234,6 +238,12 @@ impl SessionManager {
             let info: SessionInfo = serde_json::from_str(&content)?;
             signal::kill(Pid::from_raw(info.pid), Signal::SIGTERM)?;
             fs::remove_file(session_file)?;
+            let socket_path = self.socket_path(session_id);
+            if socket_path.exists() {
+                if let Err(e) = fs::remove_file(&socket_path) {
+                    warn!("Failed to remove session socket: {}", e);
+                }
+            }
             info!("Killed session: {}", session_id);
             Ok(())
         } else {
@@ -270,6 +280,14 @@ impl SessionManager {
         Ok(killed)
     }
 
+    /// Returns the path of the Unix domain socket a session posts its namespace on.
+    ///
+    /// # Arguments
+    /// * `session_id` - ID of the session
+    pub fn socket_path(&self, session_id: &str) -> PathBuf {
+        self.sessions_dir.join(format!("{}.9p", session_id))
+    }
+
     /// Get details for a specific session.
     ///
     /// # Arguments
@@ -297,11 +315,19 @@ impl SessionManager {
     /// * `source` - Source path to bind from
     /// * `target` - Target path to bind to
     /// * `mode` - Binding mode to use
+    /// * `symlinks` - Treatment of symlinks pointing outside the bound directories
     ///
     /// # Returns
     /// * `Ok(())` if the command was sent successfully
     /// * `Err` if the session doesn't exist or the command couldn't be sent
-    pub fn send_bind_command(&self, session_id: &str, source: PathBuf, target: PathBuf, mode: BindMode) -> Result<()> {
+    pub fn send_bind_command(
+        &self,
+        session_id: &str,
+        source: PathBuf,
+        target: PathBuf,
+        mode: BindMode,
+        symlinks: SymlinkPolicy,
+    ) -> Result<()> {
         info!("Sending bind command to session {}", session_id);
         if let Some(session) = self.get_session(session_id)? {
             // Ensure the pipe exists
@@ -315,6 +341,7 @@ impl SessionManager {
                 source,
                 target,
                 mode,
+                symlinks,
             };
             let command_str = serde_json::to_string(&command)?;
             
@@ -346,7 +373,8 @@ impl SessionManager {
         info!("Getting active session for ID: {}", session_id);
         if let Some(session_info) = self.get_session(session_id)? {
             // Create or get the session instance
-            let session = Session::new(session_info.root, session_id.to_string())?;
+            let socket_path = self.socket_path(session_id);
+            let session = Session::new(session_info.root, session_id.to_string(), socket_path)?;
             info!("Retrieved active session");
             Ok(Some(session))
         } else {
@@ -449,17 +477,18 @@ enum SessionMessage {
 /// # Example
 ///
 /// ```no_run
-/// use froggr::Session;
+/// use froggr::session::{Session, SessionManager};
 /// use std::path::PathBuf;
 ///
 /// # fn main() -> anyhow::Result<()> {
-/// let session = Session::new(PathBuf::from("/tmp/test"))?;
+/// let socket_path = SessionManager::new()?.socket_path("example");
+/// let session = Session::new(PathBuf::from("/tmp/test"), "example".to_string(), socket_path)?;
 ///
 /// // Mount a filesystem
 /// session.mount(
-///     &PathBuf::from("/source"),
-///     &PathBuf::from("/target"),
-///     "localhost"
+///     PathBuf::from("/source"),
+///     PathBuf::from("/target"),
+///     "localhost".to_string()
 /// )?;
 ///
 /// // Shutdown cleanly
@@ -473,12 +502,16 @@ pub struct Session {
     pub fs_manager: FilesystemManager,
     /// Channel sender for session messages
     message_tx: Sender<SessionMessage>,
-    /// Handle to the message processing thread
+    /// Handle to the message processing thread, which runs for as long as
+    /// the session does
+    #[allow(dead_code)]
     message_thread: JoinHandle<()>,
     /// Flag indicating if the session is running
     is_running: Arc<AtomicBool>,
     /// Session state
     state: Arc<RwLock<SessionState>>,
+    /// Stops the 9P service posted on the session socket
+    srv_shutdown: Mutex<Option<oneshot::Sender<()>>>,
 }
 
 impl Session {
@@ -487,6 +520,9 @@ impl Session {
     /// # Arguments
     ///
     /// * `root` - The root directory path for the filesystem
+    /// * `session_id` - ID of the session
+    /// * `socket_path` - Where to post the session's 9P service, see
+    ///   [`SessionManager::socket_path`]
     ///
     /// # Returns
     ///
@@ -495,7 +531,7 @@ impl Session {
     /// # Errors
     ///
     /// Returns an error if the filesystem manager cannot be initialized
-    pub fn new(root: PathBuf, session_id: String) -> Result<Arc<Self>> {
+    pub fn new(root: PathBuf, session_id: String, socket_path: PathBuf) -> Result<Arc<Self>> {
         let fs = crate::NineP::new(root.clone())?;
         let fs_manager = FilesystemManager::new(fs);
         let (tx, rx) = channel();
@@ -510,12 +546,21 @@ impl Session {
             Self::run_message_handler(rx, is_running_clone, fs_manager_clone, state_clone);
         });
 
+        let srv_shutdown = match Self::post_srv(&fs_manager, socket_path) {
+            Ok(shutdown) => shutdown,
+            Err(e) => {
+                warn!("Failed to post 9P service for session {}: {}", session_id, e);
+                None
+            }
+        };
+
         let session = Arc::new(Self {
             fs_manager,
             message_tx: tx,
             message_thread,
             is_running,
             state,
+            srv_shutdown: Mutex::new(srv_shutdown),
         });
 
         // Set up command listener
@@ -531,6 +576,75 @@ impl Session {
         Ok(session)
     }
 
+    /// Posts the session's namespace as a 9P service on a Unix domain socket.
+    ///
+    /// Like a `/srv` entry in Plan 9, the socket lets local tools attach to
+    /// the namespace without going through TCP or FUSE. If another process
+    /// already serves the socket it is left alone.
+    ///
+    /// # Arguments
+    /// * `fs_manager` - The filesystem manager whose namespace is served
+    /// * `socket_path` - Where to create the socket
+    ///
+    /// # Returns
+    /// * `Ok(Some(sender))` - Sending on (or dropping) the sender stops the service
+    /// * `Ok(None)` - The socket is already served by someone else
+    /// * `Err` if the socket could not be created
+    fn post_srv(
+        fs_manager: &FilesystemManager,
+        socket_path: PathBuf,
+    ) -> Result<Option<oneshot::Sender<()>>> {
+        if socket_path.exists() {
+            if UnixStream::connect(&socket_path).is_ok() {
+                info!("9P service already posted at {}", socket_path.display());
+                return Ok(None);
+            }
+            // Stale socket left behind by a dead session
+            fs::remove_file(&socket_path)?;
+        }
+
+        let listener = UnixListener::bind(&socket_path)?;
+        listener.set_nonblocking(true)?;
+        info!("Posted 9P service at {}", socket_path.display());
+
+        let server = Server::new(fs_manager.fs.clone());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("Failed to start 9P service runtime: {}", e);
+                    return;
+                }
+            };
+            runtime.block_on(async move {
+                let listener = match tokio::net::UnixListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        error!("Failed to register 9P service socket: {}", e);
+                        return;
+                    }
+                };
+                tokio::select! {
+                    result = server.serve_unix_listener(listener) => {
+                        if let Err(e) = result {
+                            error!("9P service stopped: {}", e);
+                        }
+                    }
+                    _ = shutdown_rx => info!("Stopping 9P service"),
+                }
+            });
+            if let Err(e) = fs::remove_file(&socket_path) {
+                warn!("Failed to remove session socket: {}", e);
+            }
+        });
+
+        Ok(Some(shutdown_tx))
+    }
+
     /// Runs the message handling loop.
     ///
     /// This internal method processes incoming messages and performs the
@@ -773,6 +887,14 @@ impl Session {
         
         // Send shutdown message
         self.message_tx.send(SessionMessage::Shutdown)?;
+
+        // Stop serving the namespace on the session socket
+        if let Some(srv_shutdown) = self.srv_shutdown.lock().take() {
+            let _ = srv_shutdown.send(());
+        }
+
+        // Tear down the FUSE mounts
+        self.fs_manager.unmount_all();
         
         // Clean up session file
         let session_file = format!("/tmp/froggr/sessions/{}", self.state.read().id);
@@ -850,9 +972,9 @@ impl Session {
                                         Err(e) => error!("Mount operation failed: {}", e),
                                     }
                                 }
-                                SessionCommand::Bind { source, target, mode } => {
+                                SessionCommand::Bind { source, target, mode, symlinks } => {
                                     info!("Processing bind command: {:?} -> {:?}", source, target);
-                                    match session.fs_manager.bind(&source, &target, mode) {
+                                    match session.fs_manager.bind_with_symlinks(&source, &target, mode, symlinks) {
                                         Ok(_) => {
                                             info!("Bind operation successful, updating session state");
                                             // Directly update session state here
@@ -995,6 +1117,8 @@ enum SessionCommand {
         source: PathBuf,
         target: PathBuf,
         mode: BindMode,
+        #[serde(default)]
+        symlinks: SymlinkPolicy,
     },
     Mount {
         source: PathBuf,